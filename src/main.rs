@@ -6,23 +6,59 @@ mod tasks {
     pub(crate) mod tts;
     pub(crate) mod stt;
     pub(crate) mod ffmpeg_handler;
+    pub(crate) mod config;
+    pub(crate) mod chat_backend;
+    pub(crate) mod streaming_reply;
+    pub(crate) mod message_splitter;
+    pub(crate) mod reply_attachments;
+    pub(crate) mod database;
+    pub(crate) mod conversation_store;
+    pub(crate) mod context_budget;
+    pub(crate) mod tools;
+    pub(crate) mod persona_store;
+    pub(crate) mod personas;
+    pub(crate) mod logging;
+    pub(crate) mod metrics;
+    pub(crate) mod http_server;
+    pub(crate) mod quota_store;
+    pub(crate) mod quotas;
+    pub(crate) mod quota_commands;
+    pub(crate) mod usage_store;
+    pub(crate) mod usage;
+    pub(crate) mod usage_commands;
+    pub(crate) mod permission_store;
+    pub(crate) mod permissions;
+    pub(crate) mod permission_commands;
+    pub(crate) mod runpod;
+    pub(crate) mod image_generation_store;
+    pub(crate) mod image_buttons;
 }
 
-use std::{env, path::PathBuf, sync::Arc, time::Duration};
+use std::{sync::Arc, time::{Duration, Instant}};
 
 use poise::serenity_prelude as serenity;
 
-use ::serenity::all::FullEvent;
+use ::serenity::all::{FullEvent, Interaction};
 use serenity::{
-    builder::{CreateAllowedMentions, CreateMessage}, http::Typing, model::channel::Message, prelude::*
+    http::Typing, model::Timestamp, prelude::*
 };
 
-use tasks::{handle_errors::return_error_reply, image_generation::imagegen, misc_commands::help, stt::{transcribe_from_attachment, transcribe_from_message, transcribe_from_url}, text_generation::text_reply, tts::{tts_from_message, tts_from_text}};
+use tasks::{chat_backend::{build_chat_backend, ChatBackend}, config::Config, context_budget::TokenCounters, conversation_store::prune_turns, database::Database, handle_errors::{on_error, reply_with_error}, http_server::start_http_server, image_buttons::{handle_image_button, IMAGE_BUTTON_PREFIX}, image_generation::{imagegen_command, load_function_data, ImageParameters}, logging::{init_logging, TracedFramework}, misc_commands::help, permission_commands::permissions, permissions::command_check, personas::persona, quota_commands::quota, quota_store::prune_daily_usage, quotas::{current_day, RateLimiter}, runpod::{RunpodClient, RunpodWebhooks}, stt::{transcribe_from_attachment, transcribe_from_message, transcribe_from_url}, text_generation::text_reply, tools::ToolRegistry, tts::{tts_from_message, tts_from_text}, usage_commands::usage};
 
+use tracing::{error, info, warn, Span};
 use which::which;
 
-struct Data {} // User data, which is stored and accessible in all command invocations
-type Error = Box<dyn std::error::Error + Send + Sync>;
+// User data, which is stored and accessible in all command invocations
+struct Data {
+    config: Config,
+    chat_backend: Box<dyn ChatBackend>,
+    database: Database,
+    token_counters: TokenCounters,
+    tools: ToolRegistry,
+    rate_limiter: RateLimiter,
+    runpod: RunpodClient,
+}
+type Error = tasks::handle_errors::BotError;
 type Context<'a> = poise::Context<'a, Data, Error>;
 
 #[derive(serde::Deserialize)]
@@ -33,7 +69,13 @@ struct FunctionData {
     function_api_key: String,
     function_friendly_name: String,
     prompt_prefix: String,
-    prompt_suffix: String
+    prompt_suffix: String,
+    // Runpod endpoints that usually finish quickly can use /runsync to skip polling the job status
+    #[serde(default)]
+    use_runsync: bool,
+    // The Stable Diffusion defaults and the values users can choose from for runpod_image models
+    #[serde(default)]
+    parameters: ImageParameters
 }
 
 #[derive(serde::Deserialize)]
@@ -43,23 +85,93 @@ struct JsonObject{
 
 #[tokio::main]
 async fn main() {
-    let token = env::var("DISCORD_TOKEN")
-    .expect("Expected a token in the environment");
+    // The config is validated here so any misconfiguration is reported before connecting to Discord
+    let config = match Config::load() {
+        Ok(t) => t,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    // Kept until the bot exits so the log file is flushed
+    let _log_guard = match init_logging(&config.logging, config.discord.debug) {
+        Ok(t) => t,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    let token = config.discord.token.clone();
+
+    let database = match Database::open(&config.database.resolved_path()) {
+        Ok(t) => t,
+        Err(e) => {
+            error!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    let token_counters = match TokenCounters::new(&config.text_generation.model) {
+        Ok(t) => t,
+        Err(e) => {
+            error!("Unable to load the tokenizer: {}", e);
+            std::process::exit(1);
+        }
+    };
+    if config.conversations.retention_days > 0 {
+        let oldest_kept = Timestamp::now().unix_timestamp() - config.conversations.retention_days * 24 * 60 * 60;
+        if let Err(e) = prune_turns(&database, oldest_kept) {
+            warn!("Unable to remove old conversations: {}", e);
+        }
+    }
+    // Only today's usage is needed for quotas
+    if let Err(e) = prune_daily_usage(&database, current_day()) {
+        warn!("Unable to remove old quota usage: {}", e);
+    }
+
+    // Runpod sends finished jobs to the HTTP server when it has a public URL, otherwise the status of each job is checked
+    let runpod_webhooks = (!config.http.public_url.trim().is_empty()).then(|| Arc::new(RunpodWebhooks::new(&config.http.public_url)));
+    let runpod = RunpodClient::new(&config.runpod, runpod_webhooks.clone());
+    if !config.http.listen_address.trim().is_empty() {
+        if let Err(e) = start_http_server(&config.http, runpod_webhooks).await {
+            error!("{}", e);
+            std::process::exit(1);
+        }
+    }
 
     let intents = GatewayIntents::GUILD_MESSAGES
     | GatewayIntents::DIRECT_MESSAGES
     | GatewayIntents::MESSAGE_CONTENT;
 
     let mut command_set = vec![
-        imagegen(),
-        help()
+        imagegen_command(),
+        help(),
+        persona(),
+        quota(),
+        usage(),
+        permissions()
     ];
 
     // Check that FFmpeg is installed and avaliable, this is needed for media conversions
-    let result_test = which("ffmpeg").unwrap_or(PathBuf::default());
-
     // If FFmpeg is avaliable, add the commands that depend on it to the commands list
-    if result_test != PathBuf::default() {
+    let ffmpeg_available = which("ffmpeg").is_ok();
+    if !ffmpeg_available {
+        warn!("FFmpeg could not be found, the TTS and transcription commands will not be available");
+    }
+    // The image models are optional for text replies, if they cannot be loaded the model just cannot generate images
+    let image_functions = match load_function_data() {
+        Ok(t) => t,
+        Err(e) => {
+            warn!("Unable to load assets/functions.json, image generation will not be available in text replies: {}", e);
+            Vec::new()
+        }
+    };
+    let dalle_models = image_functions.iter().any(|t| t.function_type == "openai_dalle");
+    if let Err(e) = config.check_openai_features(ffmpeg_available, dalle_models) {
+        error!("{}", e);
+        std::process::exit(1);
+    }
+    let tools = ToolRegistry::new(ffmpeg_available, image_functions);
+    if ffmpeg_available {
         command_set.push(tts_from_text());
         command_set.push(tts_from_message());
         command_set.push(transcribe_from_attachment());
@@ -82,67 +194,60 @@ async fn main() {
             ],
             ..Default::default()
         },
-        // This code is run before every command
+        // Command errors are shown to the user here, so commands only need to return them
+        on_error: |error| Box::pin(on_error(error)),
+        // The permission rules set with /permissions are checked here before every command
+        command_check: Some(|ctx| Box::pin(command_check(ctx))),
+        // This code is run before every command, prefix commands are only named in the message span from here
         pre_command: |ctx| {
             Box::pin(async move {
-                println!("Executing command {}...", ctx.command().qualified_name);
+                Span::current().record("command", ctx.command().qualified_name.as_str());
+                ctx.set_invocation_data(Instant::now()).await;
+                info!("Running command {}", ctx.command().qualified_name);
             })
         },
         // This code is run after a command if it was successful (returned Ok)
         post_command: |ctx| {
             Box::pin(async move {
-                println!("Executed command {}!", ctx.command().qualified_name);
+                let latency_ms = ctx.invocation_data::<Instant>().await.map(|started| started.elapsed().as_millis() as u64);
+                info!(latency_ms, "Finished command {}", ctx.command().qualified_name);
             })
         },
         /*
             This EventHandler is used by serenity to process any events that happen
             This program currently supports
                 - message - for when any messages are recieved wherever the bot has access to messages (includes DMs)
+                - interaction - for the buttons under generated images, these are handled here so they keep working after a restart
         */
-        event_handler: |ctx, event, _framework, _data| {
+        event_handler: |ctx, event, _framework, data| {
             Box::pin(async move {
-                match event {
-                    FullEvent::Message { new_message } => {
-                        let author_id: String = env::var("USER_ID").unwrap_or("-1".to_owned());
-                        let debug_enabled: String = env::var("DEBUG").unwrap_or("0".to_owned());
-
-                        if debug_enabled != "1".to_owned() ||
-                        author_id == new_message.author.id.to_string() {
-                            let message_prefix: String;
-                            if debug_enabled == "1".to_owned() {
-                                message_prefix = "DEBUG: ".to_string();
-                            } else {
-                                message_prefix = "".to_string();
-                            }
-                            if new_message.author.id != ctx.cache.current_user().id && new_message.mentions_user_id(ctx.cache.current_user().id) {
-                                let http_cache = ctx.clone().http;
-                                let current_user_id: u64 = ctx.cache.current_user().id.into();
-                                let typing = Typing::start(http_cache.clone(), new_message.channel_id.into());
-                                let response_vec = text_reply(new_message.clone(), &ctx, current_user_id).await;
-                                let mut last_sent_reply = Message::default();
-                                let _default_message = Message::default();
-                                for response in response_vec {
-                                    let mut response_message: &Message = new_message;
-                                    // The message ID is set to 1 if it is default, I am making an assumption that this bot will never get a new message with the ID of 1
-                                    // Even if this was the case, this will only affect multi message responses
-                                    if last_sent_reply.id != 1 {
-                                        response_message = &last_sent_reply;
-                                    }
-                                    let message_builder = CreateMessage::new()
-                                        .reference_message(response_message)
-                                        .allowed_mentions(CreateAllowedMentions::new().users(vec![new_message.clone().author.id]))
-                                        .content(format!("{}{}", message_prefix, response));
-                                    last_sent_reply = match new_message.channel_id.send_message(http_cache.clone(), message_builder).await
-                                    {
-                                        Ok(t) => t,
-                                        Err(e) => return_error_reply(new_message.clone(), e.to_string()).await.unwrap(),
-                                    };
-                                }
-                                typing.stop();
+                if let FullEvent::InteractionCreate { interaction: Interaction::Component(component_interaction) } = event {
+                    if component_interaction.data.custom_id.starts_with(IMAGE_BUTTON_PREFIX) {
+                        handle_image_button(ctx, data, component_interaction).await;
+                    }
+                }
+                if let FullEvent::Message { new_message } = event {
+                    let debug_enabled = data.config.discord.debug;
+
+                    if !debug_enabled ||
+                    data.config.discord.debug_user_id == new_message.author.id.get() {
+                        let message_prefix = if debug_enabled {
+                            "DEBUG: ".to_string()
+                        } else {
+                            "".to_string()
+                        };
+                        if new_message.author.id != ctx.cache.current_user().id && new_message.mentions_user_id(ctx.cache.current_user().id) {
+                            let http_cache = ctx.clone().http;
+                            let current_user_id: u64 = ctx.cache.current_user().id.into();
+                            let typing = Typing::start(http_cache.clone(), new_message.channel_id);
+                            let started = Instant::now();
+                            match text_reply(new_message.clone(), ctx, data, current_user_id, message_prefix).await {
+                                Ok(()) => info!(latency_ms = started.elapsed().as_millis() as u64, "Replied to a mention"),
+                                Err(e) => reply_with_error(ctx, new_message, e).await,
                             }
+                            typing.stop();
                         }
                     }
-                    _ => {}
                 }
                 Ok(())
             })
@@ -155,13 +260,24 @@ async fn main() {
         .setup(|ctx, _ready, framework| {
             Box::pin(async move {
                 poise::builtins::register_globally(ctx, &framework.options().commands).await?;
-                Ok(Data {})
+                let chat_backend = build_chat_backend(&config);
+                Ok(Data { config, chat_backend, database, token_counters, tools, rate_limiter: RateLimiter::default(), runpod })
             })
         })
         .build();
 
     let client = serenity::ClientBuilder::new(token, intents)
-        .framework(framework)
+        .framework(TracedFramework(framework))
         .await;
-    client.unwrap().start().await.unwrap();
+    let mut client = match client {
+        Ok(t) => t,
+        Err(e) => {
+            error!("Unable to create the Discord client: {}", e);
+            std::process::exit(1);
+        }
+    };
+    if let Err(e) = client.start().await {
+        error!("The Discord client has stopped: {}", e);
+        std::process::exit(1);
+    }
 }
@@ -0,0 +1,387 @@
+use async_openai::{config::OpenAIConfig, types::{ChatCompletionMessageToolCall, ChatCompletionMessageToolCallChunk, ChatCompletionRequestMessage, ChatCompletionRequestMessageContentPart, ChatCompletionRequestUserMessageContent, ChatCompletionTool, ChatCompletionToolType, CreateChatCompletionRequestArgs, FunctionCall}, Client};
+use std::pin::Pin;
+
+use async_trait::async_trait;
+use base64::prelude::*;
+use futures::{stream, Stream, StreamExt};
+
+use crate::Error;
+
+use super::config::{ChatBackendKind, Config};
+
+/*
+    Everything a backend needs to generate a reply
+    The messages use the OpenAI types as that is what the rest of the text generation builds
+*/
+#[derive(Clone, Debug)]
+pub struct ChatRequest {
+    pub model: String,
+    pub messages: Vec<ChatCompletionRequestMessage>,
+    pub max_tokens: u16,
+    pub temperature: f32,
+    // The tools the model can call, empty if tools are not being used for this request
+    pub tools: Vec<ChatCompletionTool>,
+}
+
+/*
+    Something the model has generated
+    Tool calls are only given once they are complete as OpenAI streams the arguments a piece at a time
+*/
+pub enum ChatEvent {
+    Text(String),
+    ToolCalls(Vec<ChatCompletionMessageToolCall>),
+}
+
+// A streamed reply, each item is the next piece of text or the tool calls made by the model
+pub type ChatStream = Pin<Box<dyn Stream<Item = Result<ChatEvent, Error>> + Send>>;
+
+/*
+    A chat backend is anything that can take the conversation and stream back the next assistant message
+    The backend used is selected with text_generation.backend in the config
+*/
+#[async_trait]
+pub trait ChatBackend: Send + Sync {
+    async fn complete_stream(&self, request: ChatRequest) -> Result<ChatStream, Error>;
+
+    /*
+        Waits for the whole reply, for anything that is not shown to the user as it is written
+        Only the text is returned so this should not be given any tools
+    */
+    async fn complete(&self, request: ChatRequest) -> Result<String, Error> {
+        let mut response_stream = self.complete_stream(request).await?;
+        let mut content = String::new();
+        while let Some(response_event) = response_stream.next().await {
+            if let ChatEvent::Text(t) = response_event? {
+                content.push_str(&t);
+            }
+        }
+        Ok(content)
+    }
+}
+
+/*
+    Creates the backend selected in the config
+*/
+pub fn build_chat_backend(config: &Config) -> Box<dyn ChatBackend> {
+    let text_config = &config.text_generation;
+    match text_config.backend {
+        ChatBackendKind::OpenAi => Box::new(OpenAiBackend::new(config.openai_client_config())),
+        ChatBackendKind::OpenAiCompatible => Box::new(OpenAiBackend::new(
+            OpenAIConfig::new()
+                .with_api_base(text_config.base_url.trim_end_matches('/'))
+                .with_api_key(text_config.api_key.clone())
+        )),
+        // Runpod's serverless vLLM workers expose an OpenAI compatible API under the endpoint
+        ChatBackendKind::Runpod => Box::new(OpenAiBackend::new(
+            OpenAIConfig::new()
+                .with_api_base(format!("{}/{}/openai/v1", config.runpod.base_url.trim_end_matches('/'), text_config.runpod_endpoint_id))
+                .with_api_key(config.runpod.api_key.clone())
+        )),
+        ChatBackendKind::Ollama => Box::new(OllamaBackend::new(text_config.base_url.clone())),
+    }
+}
+
+/*
+    Uses the async-openai library, this works with OpenAI and with any server that copies its API
+    (llama.cpp, vLLM, LM Studio, Ollama's /v1 endpoint and so on) by changing the API base
+*/
+pub struct OpenAiBackend {
+    client: Client<OpenAIConfig>,
+}
+
+impl OpenAiBackend {
+    pub fn new(openai_config: OpenAIConfig) -> Self {
+        OpenAiBackend { client: Client::with_config(openai_config) }
+    }
+}
+
+#[async_trait]
+impl ChatBackend for OpenAiBackend {
+    /*
+        Text is passed on as it arrives, tool calls are built up from their pieces and given once the stream has ended
+    */
+    async fn complete_stream(&self, request: ChatRequest) -> Result<ChatStream, Error> {
+        let mut chat_request_args = CreateChatCompletionRequestArgs::default();
+        chat_request_args
+            .model(request.model)
+            .temperature(request.temperature)
+            .messages(request.messages)
+            .max_tokens(request.max_tokens);
+        // OpenAI rejects an empty tools list so it is left out completely when there are no tools
+        if !request.tools.is_empty() {
+            chat_request_args.tools(request.tools);
+        }
+        let chat_request = chat_request_args.build()?;
+
+        let response_stream = self.client.chat().create_stream(chat_request).await?;
+        let event_stream = stream::unfold(Some((response_stream, Vec::new())), |state| async move {
+            let (mut response_stream, mut tool_calls) = state?;
+            loop {
+                match response_stream.next().await {
+                    Some(Ok(t)) => {
+                        let Some(choice) = t.choices.into_iter().next() else {
+                            continue;
+                        };
+                        for tool_call_chunk in choice.delta.tool_calls.unwrap_or_default() {
+                            merge_tool_call_chunk(&mut tool_calls, tool_call_chunk);
+                        }
+                        if let Some(content) = choice.delta.content {
+                            return Some((Ok(ChatEvent::Text(content)), Some((response_stream, tool_calls))));
+                        }
+                    },
+                    Some(Err(e)) => return Some((Err(e.into()), None)),
+                    None if tool_calls.is_empty() => return None,
+                    None => return Some((Ok(ChatEvent::ToolCalls(tool_calls)), None)),
+                }
+            }
+        });
+
+        Ok(Box::pin(event_stream))
+    }
+}
+
+/*
+    The first piece of a tool call has its ID and name, the arguments are then streamed in over the following pieces
+*/
+fn merge_tool_call_chunk(tool_calls: &mut Vec<ChatCompletionMessageToolCall>, tool_call_chunk: ChatCompletionMessageToolCallChunk) {
+    let index = tool_call_chunk.index.max(0) as usize;
+    while tool_calls.len() <= index {
+        tool_calls.push(ChatCompletionMessageToolCall {
+            id: String::new(),
+            r#type: ChatCompletionToolType::Function,
+            function: FunctionCall { name: String::new(), arguments: String::new() },
+        });
+    }
+
+    let tool_call = &mut tool_calls[index];
+    if let Some(id) = tool_call_chunk.id {
+        tool_call.id = id;
+    }
+    if let Some(function) = tool_call_chunk.function {
+        if let Some(name) = function.name {
+            tool_call.function.name.push_str(&name);
+        }
+        if let Some(arguments) = function.arguments {
+            tool_call.function.arguments.push_str(&arguments);
+        }
+    }
+}
+
+/*
+    Uses Ollama's native /api/chat endpoint
+    Ollama only accepts images as base64 so any image URLs are downloaded first
+*/
+pub struct OllamaBackend {
+    client: reqwest::Client,
+    base_url: String,
+}
+
+#[derive(serde::Serialize)]
+struct OllamaMessage {
+    role: &'static str,
+    content: String,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    images: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    tool_calls: Vec<OllamaToolCall>,
+}
+
+// Ollama gives the arguments as a JSON object rather than a string of JSON like OpenAI
+#[derive(serde::Serialize, serde::Deserialize)]
+struct OllamaFunctionCall {
+    name: String,
+    arguments: serde_json::Value,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+struct OllamaToolCall {
+    function: OllamaFunctionCall,
+}
+
+#[derive(serde::Serialize)]
+struct OllamaOptions {
+    temperature: f32,
+    num_predict: u16,
+}
+
+#[derive(serde::Serialize)]
+struct OllamaChatRequest {
+    model: String,
+    messages: Vec<OllamaMessage>,
+    stream: bool,
+    options: OllamaOptions,
+    // Ollama uses the same format as OpenAI for describing tools
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    tools: Vec<ChatCompletionTool>,
+}
+
+#[derive(serde::Deserialize)]
+struct OllamaResponseMessage {
+    content: String,
+    #[serde(default)]
+    tool_calls: Vec<OllamaToolCall>,
+}
+
+// Ollama reports errors part way through a stream as a line with only an error field
+#[derive(serde::Deserialize)]
+struct OllamaStreamLine {
+    message: Option<OllamaResponseMessage>,
+    error: Option<String>,
+}
+
+impl OllamaBackend {
+    pub fn new(base_url: String) -> Self {
+        let base_url = if base_url.trim().is_empty() {
+            "http://localhost:11434".to_owned()
+        } else {
+            base_url.trim_end_matches('/').to_owned()
+        };
+        OllamaBackend { client: reqwest::Client::new(), base_url }
+    }
+
+    async fn build_request(&self, request: ChatRequest) -> Result<OllamaChatRequest, Error> {
+        let mut messages: Vec<OllamaMessage> = Vec::new();
+        for message in request.messages {
+            messages.push(self.convert_message(message).await?);
+        }
+
+        Ok(OllamaChatRequest {
+            model: request.model,
+            messages,
+            stream: true,
+            options: OllamaOptions {
+                temperature: request.temperature,
+                num_predict: request.max_tokens,
+            },
+            tools: request.tools,
+        })
+    }
+
+    async fn convert_message(&self, message: ChatCompletionRequestMessage) -> Result<OllamaMessage, Error> {
+        let mut tool_calls: Vec<OllamaToolCall> = Vec::new();
+        let (role, content, image_urls) = match message {
+            ChatCompletionRequestMessage::System(t) => ("system", t.content, Vec::new()),
+            ChatCompletionRequestMessage::Assistant(t) => {
+                for tool_call in t.tool_calls.unwrap_or_default() {
+                    let arguments = serde_json::from_str(&tool_call.function.arguments).unwrap_or(serde_json::Value::Object(Default::default()));
+                    tool_calls.push(OllamaToolCall { function: OllamaFunctionCall { name: tool_call.function.name, arguments } });
+                }
+                ("assistant", t.content.unwrap_or_default(), Vec::new())
+            },
+            ChatCompletionRequestMessage::Tool(t) => ("tool", t.content, Vec::new()),
+            ChatCompletionRequestMessage::Function(t) => ("tool", t.content.unwrap_or_default(), Vec::new()),
+            ChatCompletionRequestMessage::User(t) => match t.content {
+                ChatCompletionRequestUserMessageContent::Text(text) => ("user", text, Vec::new()),
+                ChatCompletionRequestUserMessageContent::Array(parts) => {
+                    let mut text_parts: Vec<String> = Vec::new();
+                    let mut image_urls: Vec<String> = Vec::new();
+                    for part in parts {
+                        match part {
+                            ChatCompletionRequestMessageContentPart::Text(text) => text_parts.push(text.text),
+                            ChatCompletionRequestMessageContentPart::Image(image) => image_urls.push(image.image_url.url),
+                        }
+                    }
+                    ("user", text_parts.join("\n"), image_urls)
+                }
+            },
+        };
+
+        let mut images: Vec<String> = Vec::new();
+        for image_url in image_urls {
+            let image_bytes = self.client.get(image_url).send().await?.error_for_status()?.bytes().await?;
+            images.push(BASE64_STANDARD.encode(image_bytes));
+        }
+
+        Ok(OllamaMessage { role, content, images, tool_calls })
+    }
+}
+
+#[async_trait]
+impl ChatBackend for OllamaBackend {
+    /*
+        Ollama streams one JSON object per line, the response is read in chunks and split on new lines
+        as a chunk can end part way through a line
+        Tool calls come complete in a single line, Ollama does not give them IDs so they are numbered here
+    */
+    async fn complete_stream(&self, request: ChatRequest) -> Result<ChatStream, Error> {
+        let ollama_request = self.build_request(request).await?;
+
+        let response = self.client.post(format!("{}/api/chat", self.base_url))
+            .json(&ollama_request)
+            .send()
+            .await?
+            .error_for_status()?;
+
+        // The buffer is kept as bytes so a multi-byte character split across two chunks is not broken
+        let event_stream = stream::unfold(Some((response, Vec::new(), 0)), |state| async move {
+            let (mut response, mut buffer, mut tool_call_count): (reqwest::Response, Vec<u8>, usize) = state?;
+            loop {
+                if let Some(line_end) = buffer.iter().position(|byte| *byte == b'\n') {
+                    let line: Vec<u8> = buffer.drain(..=line_end).collect();
+                    if line.iter().all(|byte| byte.is_ascii_whitespace()) {
+                        continue;
+                    }
+                    let item: Result<ChatEvent, Error> = match serde_json::from_slice::<OllamaStreamLine>(&line) {
+                        Ok(OllamaStreamLine { error: Some(e), .. }) => Err(e.into()),
+                        Ok(OllamaStreamLine { message: Some(message), .. }) if !message.tool_calls.is_empty() => {
+                            let tool_calls = message.tool_calls.into_iter()
+                                .map(|tool_call| {
+                                    tool_call_count += 1;
+                                    ChatCompletionMessageToolCall {
+                                        id: format!("call_{}", tool_call_count),
+                                        r#type: ChatCompletionToolType::Function,
+                                        function: FunctionCall { name: tool_call.function.name, arguments: tool_call.function.arguments.to_string() },
+                                    }
+                                })
+                                .collect();
+                            Ok(ChatEvent::ToolCalls(tool_calls))
+                        },
+                        Ok(t) => Ok(ChatEvent::Text(t.message.map(|message| message.content).unwrap_or_default())),
+                        Err(e) => Err(e.into()),
+                    };
+                    return Some((item, Some((response, buffer, tool_call_count))));
+                }
+                match response.chunk().await {
+                    Ok(Some(t)) => buffer.extend_from_slice(&t),
+                    // The last line may not end with a new line, it is finished here so it is read like the others
+                    Ok(None) if !buffer.iter().all(|byte| byte.is_ascii_whitespace()) => buffer.push(b'\n'),
+                    Ok(None) => return None,
+                    Err(e) => return Some((Err(e.into()), None)),
+                }
+            }
+        });
+
+        Ok(Box::pin(event_stream))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{routing::post, Router};
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn ollama_reads_a_last_line_without_a_new_line() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base_url = format!("http://{}", listener.local_addr().unwrap());
+        let router = Router::new().route("/api/chat", post(|| async {
+            concat!(
+                r#"{"message":{"role":"assistant","content":"Hello "},"done":false}"#, "\n",
+                r#"{"message":{"role":"assistant","content":"world"},"done":true}"#
+            )
+        }));
+        tokio::spawn(async move { axum::serve(listener, router).await.unwrap() });
+
+        let ollama_backend = OllamaBackend::new(base_url);
+        let reply = ollama_backend.complete(ChatRequest {
+            model: "llama3".to_owned(),
+            messages: Vec::new(),
+            max_tokens: 100,
+            temperature: 1.0,
+            tools: Vec::new(),
+        }).await.unwrap();
+        assert_eq!(reply, "Hello world");
+    }
+}
@@ -0,0 +1,597 @@
+use std::{collections::HashMap, env, fs, path::{Path, PathBuf}};
+
+use crate::{tasks::handle_errors::BotError, Error};
+
+/*
+    The bot configuration, loaded once at startup and stored in the poise Data struct
+    Values are read from config.toml (next to the executable, or the path in DELTA_CONFIG)
+    and then any of the original environment variables are applied on top as overrides
+*/
+#[derive(serde::Deserialize, Clone, Debug, Default)]
+#[serde(default)]
+pub struct Config {
+    pub discord: DiscordConfig,
+    pub openai: OpenAiConfig,
+    pub runpod: RunpodConfig,
+    pub text_generation: TextGenerationConfig,
+    pub database: DatabaseConfig,
+    pub conversations: ConversationConfig,
+    pub logging: LoggingConfig,
+    pub http: HttpConfig,
+    pub quotas: QuotaConfig,
+    pub pricing: PricingConfig,
+}
+
+#[derive(serde::Deserialize, Clone, Debug, Default)]
+#[serde(default)]
+pub struct DiscordConfig {
+    pub token: String,
+    // If enabled then the bot only replies to debug_user_id and prepends all messages with "DEBUG: "
+    pub debug: bool,
+    pub debug_user_id: u64,
+}
+
+#[derive(serde::Deserialize, Clone, Debug, Default)]
+#[serde(default)]
+pub struct OpenAiConfig {
+    pub api_key: String,
+}
+
+#[derive(serde::Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct RunpodConfig {
+    pub api_key: String,
+    // The serverless API, this only needs changing to test against a local mock server
+    pub base_url: String,
+    // Jobs that have not finished after this long are cancelled
+    pub max_wait_seconds: u64,
+    // The first wait between status checks, this doubles after each check up to max_poll_interval_ms
+    pub poll_interval_ms: u64,
+    pub max_poll_interval_ms: u64,
+    // The endpoint ID of an upscaler for the Upscale buttons under generated images, empty hides the buttons
+    pub upscale_endpoint: String,
+    // How many times larger the upscaler makes images
+    pub upscale_factor: u32,
+}
+
+impl Default for RunpodConfig {
+    fn default() -> Self {
+        RunpodConfig {
+            api_key: String::new(),
+            base_url: "https://api.runpod.ai/v2".to_owned(),
+            max_wait_seconds: 300,
+            poll_interval_ms: 1000,
+            max_poll_interval_ms: 10000,
+            upscale_endpoint: String::new(),
+            upscale_factor: 2,
+        }
+    }
+}
+
+/*
+    The backends that text replies can be generated with
+        - openai - api.openai.com using openai.api_key
+        - openai_compatible - Any server with an OpenAI compatible API at base_url (llama.cpp, vLLM, Ollama's /v1 and so on)
+        - ollama - Ollama's native API at base_url
+        - runpod - A Runpod serverless vLLM endpoint using runpod.api_key
+*/
+#[derive(serde::Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+pub enum ChatBackendKind {
+    #[default]
+    #[serde(rename = "openai")]
+    OpenAi,
+    #[serde(rename = "openai_compatible")]
+    OpenAiCompatible,
+    #[serde(rename = "ollama")]
+    Ollama,
+    #[serde(rename = "runpod")]
+    Runpod,
+}
+
+#[derive(serde::Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct TextGenerationConfig {
+    pub backend: ChatBackendKind,
+    // Used by the openai_compatible and ollama backends
+    pub base_url: String,
+    // Used by the openai_compatible backend, most local servers ignore this
+    pub api_key: String,
+    // Used by the runpod backend
+    pub runpod_endpoint_id: String,
+    pub model: String,
+    pub max_tokens: u16,
+    pub temperature: f32,
+    // The system message used for text generation, this details the personality and style of the bot
+    pub system_details: String,
+    // Replies that need more messages than this are sent as a file attachment instead, 0 disables this
+    pub attachment_threshold: usize,
+    // The most tokens the conversation sent to the model can use, the reply (max_tokens) is not included
+    pub context_tokens: usize,
+    // Any single text attachment is cut down to this many tokens
+    pub max_attachment_tokens: usize,
+    // If enabled, turns that do not fit in context_tokens are summarised instead of being dropped
+    pub summarise_dropped_turns: bool,
+    // Lets the model call the tools in tools.rs, the model and backend must support tool calling
+    pub enable_tools: bool,
+    // The most times the model can call tools for one reply before it has to answer
+    pub max_tool_rounds: usize,
+}
+
+impl Default for TextGenerationConfig {
+    fn default() -> Self {
+        TextGenerationConfig {
+            backend: ChatBackendKind::OpenAi,
+            base_url: String::new(),
+            api_key: String::new(),
+            runpod_endpoint_id: String::new(),
+            model: "gpt-4o".to_owned(),
+            max_tokens: 4096,
+            temperature: 1.0,
+            system_details: String::new(),
+            attachment_threshold: 3,
+            context_tokens: 32000,
+            max_attachment_tokens: 8000,
+            summarise_dropped_turns: false,
+            enable_tools: true,
+            max_tool_rounds: 4,
+        }
+    }
+}
+
+#[derive(serde::Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct DatabaseConfig {
+    // Relative paths are from the folder the executable is in, the same as the assets folder
+    pub path: PathBuf,
+}
+
+impl Default for DatabaseConfig {
+    fn default() -> Self {
+        DatabaseConfig { path: PathBuf::from("delta.db") }
+    }
+}
+
+#[derive(serde::Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct ConversationConfig {
+    // If enabled, a mention that is not a reply continues from the recent conversation in the channel
+    pub continue_from_mentions: bool,
+    // How many earlier messages in the channel are used when continuing from a mention
+    pub mention_history_messages: usize,
+    // How far back (in minutes) earlier messages are used when continuing from a mention
+    pub mention_history_minutes: i64,
+    // Stored conversations older than this are removed at startup, 0 keeps them forever
+    pub retention_days: i64,
+}
+
+impl Default for ConversationConfig {
+    fn default() -> Self {
+        ConversationConfig {
+            continue_from_mentions: false,
+            mention_history_messages: 10,
+            mention_history_minutes: 60,
+            retention_days: 30,
+        }
+    }
+}
+
+/*
+    How the logs are written
+        - pretty - Multi-line and easy to read, best for running in a terminal
+        - compact - One line per event
+        - json - One JSON object per line, for log collectors
+*/
+#[derive(serde::Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    Pretty,
+    Compact,
+    Json,
+}
+
+// How often a new log file is started
+#[derive(serde::Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogRotation {
+    Hourly,
+    Daily,
+    Never,
+}
+
+#[derive(serde::Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct LoggingConfig {
+    // A level (error, warn, info, debug, trace) or filter directives such as "info,serenity=warn", RUST_LOG overrides this
+    pub level: String,
+    pub format: LogFormat,
+    // Logs are also written to files in this folder if it is set, relative paths are from the folder the executable is in
+    pub directory: PathBuf,
+    pub file_format: LogFormat,
+    pub rotation: LogRotation,
+    // The most log files kept in the folder, 0 keeps them all
+    pub max_files: usize,
+}
+
+impl Default for LoggingConfig {
+    fn default() -> Self {
+        LoggingConfig {
+            level: "info".to_owned(),
+            format: LogFormat::Compact,
+            directory: PathBuf::new(),
+            file_format: LogFormat::Json,
+            rotation: LogRotation::Daily,
+            max_files: 14,
+        }
+    }
+}
+
+/*
+    The default limits for every user, these are per user in each server (DMs count as their own server)
+    Server admins can replace these for their server, a role or a user with /quota
+    A limit of 0 is unlimited, daily limits reset at midnight UTC
+*/
+#[derive(serde::Deserialize, Clone, Copy, Debug, Default)]
+#[serde(default)]
+pub struct QuotaConfig {
+    // Mentions and commands that use a backend (image generation, TTS and transcription)
+    pub requests_per_minute: u64,
+    pub daily_images: u64,
+    // Prompt and reply tokens for text replies
+    pub daily_tokens: u64,
+    pub daily_tts_characters: u64,
+}
+
+/*
+    Prices (in USD) used to estimate the cost of each request in the usage ledger
+    Chat models and image models that are not listed are counted as free, such as models run locally
+*/
+#[derive(serde::Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct PricingConfig {
+    // By model name
+    pub chat_models: HashMap<String, TokenPricing>,
+    // The price of one image, by function_command in functions.json
+    pub images: HashMap<String, f64>,
+    pub tts_per_million_characters: f64,
+    pub transcription_per_minute: f64,
+}
+
+#[derive(serde::Deserialize, Clone, Copy, Debug, Default)]
+#[serde(default)]
+pub struct TokenPricing {
+    // Per million tokens
+    pub prompt: f64,
+    pub completion: f64,
+}
+
+impl Default for PricingConfig {
+    fn default() -> Self {
+        PricingConfig {
+            chat_models: HashMap::from([
+                ("gpt-4o".to_owned(), TokenPricing { prompt: 2.5, completion: 10.0 }),
+                ("gpt-4o-mini".to_owned(), TokenPricing { prompt: 0.15, completion: 0.6 }),
+            ]),
+            images: HashMap::from([("!delta-dalle".to_owned(), 0.04)]),
+            // tts-1-hd and whisper-1
+            tts_per_million_characters: 30.0,
+            transcription_per_minute: 0.006,
+        }
+    }
+}
+
+#[derive(serde::Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct HttpConfig {
+    // The address the HTTP server listens on (for example 127.0.0.1:9090), empty turns the server off
+    pub listen_address: String,
+    // Serve the Prometheus metrics at /metrics
+    pub enable_metrics: bool,
+    // The URL Runpod can reach the HTTP server at (for example https://bot.example.com), this turns on Runpod webhooks
+    // Empty checks the status of Runpod jobs instead
+    pub public_url: String,
+}
+
+impl Default for HttpConfig {
+    fn default() -> Self {
+        HttpConfig {
+            listen_address: String::new(),
+            enable_metrics: true,
+            public_url: String::new(),
+        }
+    }
+}
+
+impl Config {
+    /*
+        Loads the config file (if there is one), applies the environment overrides and validates the result
+        Any problem is returned as an error so it can be reported before the bot connects to Discord
+    */
+    pub fn load() -> Result<Config, Error> {
+        let config_path = match env::var("DELTA_CONFIG") {
+            Ok(t) => Some(PathBuf::from(t)),
+            Err(_) => default_config_path().filter(|path| path.exists()),
+        };
+
+        let mut config = match config_path {
+            Some(path) => {
+                let config_string = fs::read_to_string(&path)
+                    .map_err(|e| BotError::Config(format!("Unable to read the config file {}: {}", path.display(), e)))?;
+                toml::from_str(&config_string)
+                    .map_err(|e| BotError::Config(format!("Unable to parse the config file {}: {}", path.display(), e)))?
+            },
+            None => Config::default(),
+        };
+
+        config.apply_env_overrides()?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /*
+        The environment variables from before the config file existed still work and take priority
+    */
+    fn apply_env_overrides(&mut self) -> Result<(), Error> {
+        if let Ok(t) = env::var("DISCORD_TOKEN") {
+            self.discord.token = t;
+        }
+        if let Ok(t) = env::var("DEBUG") {
+            self.discord.debug = t == "1" || t.eq_ignore_ascii_case("true");
+        }
+        if let Ok(t) = env::var("USER_ID") {
+            self.discord.debug_user_id = t.parse()
+                .map_err(|_| BotError::Config(format!("USER_ID must be a Discord user ID, got \"{}\"", t)))?;
+        }
+        if let Ok(t) = env::var("OPENAI_API_KEY") {
+            self.openai.api_key = t;
+        }
+        if let Ok(t) = env::var("RUNPOD_API_KEY") {
+            self.runpod.api_key = t;
+        }
+        if let Ok(t) = env::var("SYSTEM_DETAILS") {
+            self.text_generation.system_details = t;
+        }
+        Ok(())
+    }
+
+    fn validate(&self) -> Result<(), Error> {
+        let mut problems: Vec<String> = Vec::new();
+
+        if self.discord.token.trim().is_empty() {
+            problems.push("discord.token (or DISCORD_TOKEN) must be set".to_owned());
+        }
+        if self.discord.debug && self.discord.debug_user_id == 0 {
+            problems.push("discord.debug_user_id (or USER_ID) must be set when debug mode is enabled".to_owned());
+        }
+        match self.text_generation.backend {
+            ChatBackendKind::OpenAi => {
+                if self.openai.api_key.trim().is_empty() {
+                    problems.push("openai.api_key (or OPENAI_API_KEY) must be set when using the openai text backend".to_owned());
+                }
+            },
+            ChatBackendKind::OpenAiCompatible => {
+                if self.text_generation.base_url.trim().is_empty() {
+                    problems.push("text_generation.base_url must be set when using the openai_compatible text backend".to_owned());
+                }
+            },
+            // Ollama falls back to the default local address
+            ChatBackendKind::Ollama => {},
+            ChatBackendKind::Runpod => {
+                if self.text_generation.runpod_endpoint_id.trim().is_empty() {
+                    problems.push("text_generation.runpod_endpoint_id must be set when using the runpod text backend".to_owned());
+                }
+                if self.runpod.api_key.trim().is_empty() {
+                    problems.push("runpod.api_key (or RUNPOD_API_KEY) must be set when using the runpod text backend".to_owned());
+                }
+            },
+        }
+        if !self.text_generation.base_url.trim().is_empty() && reqwest::Url::parse(&self.text_generation.base_url).is_err() {
+            problems.push(format!("text_generation.base_url is not a valid URL: \"{}\"", self.text_generation.base_url));
+        }
+        if self.text_generation.model.trim().is_empty() {
+            problems.push("text_generation.model must not be empty".to_owned());
+        }
+        if self.text_generation.max_tokens == 0 {
+            problems.push("text_generation.max_tokens must be greater than 0".to_owned());
+        }
+        if !(0.0..=2.0).contains(&self.text_generation.temperature) {
+            problems.push(format!("text_generation.temperature must be between 0 and 2, got {}", self.text_generation.temperature));
+        }
+
+        if self.text_generation.context_tokens < 1000 {
+            problems.push(format!("text_generation.context_tokens must be at least 1000, got {}", self.text_generation.context_tokens));
+        }
+        if self.text_generation.max_attachment_tokens == 0 {
+            problems.push("text_generation.max_attachment_tokens must be greater than 0".to_owned());
+        }
+        if reqwest::Url::parse(&self.runpod.base_url).is_err() {
+            problems.push(format!("runpod.base_url is not a valid URL: \"{}\"", self.runpod.base_url));
+        }
+        if self.runpod.max_wait_seconds == 0 {
+            problems.push("runpod.max_wait_seconds must be greater than 0".to_owned());
+        }
+        if self.runpod.poll_interval_ms == 0 || self.runpod.max_poll_interval_ms < self.runpod.poll_interval_ms {
+            problems.push("runpod.poll_interval_ms must be greater than 0 and no more than runpod.max_poll_interval_ms".to_owned());
+        }
+        if !(2..=8).contains(&self.runpod.upscale_factor) {
+            problems.push("runpod.upscale_factor must be from 2 to 8".to_owned());
+        }
+        if self.conversations.mention_history_minutes < 0 {
+            problems.push("conversations.mention_history_minutes must not be negative".to_owned());
+        }
+        if self.conversations.retention_days < 0 {
+            problems.push("conversations.retention_days must not be negative".to_owned());
+        }
+        if !self.http.listen_address.trim().is_empty() && self.http.listen_address.parse::<std::net::SocketAddr>().is_err() {
+            problems.push(format!("http.listen_address must be an IP address and port such as 127.0.0.1:9090, got \"{}\"", self.http.listen_address));
+        }
+        if !self.http.public_url.trim().is_empty() {
+            if self.http.listen_address.trim().is_empty() {
+                problems.push("http.listen_address must be set to use http.public_url, Runpod sends finished jobs to the HTTP server".to_owned());
+            }
+            if reqwest::Url::parse(&self.http.public_url).is_err() {
+                problems.push(format!("http.public_url is not a valid URL: \"{}\"", self.http.public_url));
+            }
+        }
+        for (model, token_pricing) in &self.pricing.chat_models {
+            if token_pricing.prompt < 0.0 || token_pricing.completion < 0.0 {
+                problems.push(format!("pricing.chat_models.\"{}\" must not have negative prices", model));
+            }
+        }
+        for (function_command, image_price) in &self.pricing.images {
+            if *image_price < 0.0 {
+                problems.push(format!("pricing.images.\"{}\" must not be negative", function_command));
+            }
+        }
+        if self.pricing.tts_per_million_characters < 0.0 || self.pricing.transcription_per_minute < 0.0 {
+            problems.push("pricing.tts_per_million_characters and pricing.transcription_per_minute must not be negative".to_owned());
+        }
+        if let Err(e) = tracing_subscriber::EnvFilter::try_new(&self.logging.level) {
+            problems.push(format!("logging.level is not a valid log level or filter: {}", e));
+        }
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(BotError::Config(format!("Invalid configuration:\n - {}", problems.join("\n - "))))
+        }
+    }
+
+    /*
+        Checks openai.api_key is set when anything other than text replies uses OpenAI
+        This is checked after loading as it depends on whether FFmpeg was found and which image models are in functions.json
+    */
+    pub fn check_openai_features(&self, ffmpeg_available: bool, dalle_models: bool) -> Result<(), Error> {
+        if !self.openai.api_key.trim().is_empty() {
+            return Ok(());
+        }
+        let openai_features: Vec<&str> = [
+            (ffmpeg_available, "the TTS and transcription commands (FFmpeg is installed)"),
+            (dalle_models, "the DALL-E models in assets/functions.json"),
+        ].into_iter().filter_map(|(enabled, feature)| enabled.then_some(feature)).collect();
+
+        if openai_features.is_empty() {
+            Ok(())
+        } else {
+            Err(BotError::Config(format!("openai.api_key (or OPENAI_API_KEY) must be set for {}", openai_features.join(" and "))))
+        }
+    }
+
+    /*
+        The OpenAI client config, this replaces the library reading OPENAI_API_KEY by itself
+    */
+    pub fn openai_client_config(&self) -> async_openai::config::OpenAIConfig {
+        async_openai::config::OpenAIConfig::new().with_api_key(self.openai.api_key.clone())
+    }
+}
+
+impl DatabaseConfig {
+    pub fn resolved_path(&self) -> PathBuf {
+        resolve_from_exe_folder(&self.path)
+    }
+}
+
+impl LoggingConfig {
+    // None if logging to files is turned off
+    pub fn resolved_directory(&self) -> Option<PathBuf> {
+        if self.directory.as_os_str().is_empty() {
+            return None;
+        }
+        Some(resolve_from_exe_folder(&self.directory))
+    }
+}
+
+/*
+    Relative paths in the config are from the folder the executable is in, the same as the assets folder
+*/
+fn resolve_from_exe_folder(path: &Path) -> PathBuf {
+    if path.is_absolute() {
+        return path.to_path_buf();
+    }
+    match env::current_exe().ok().as_ref().and_then(|t| t.parent()) {
+        Some(t) => t.join(path),
+        None => path.to_path_buf(),
+    }
+}
+
+/*
+    config.toml is expected in the same folder as the executable, the same as the assets folder
+*/
+fn default_config_path() -> Option<PathBuf> {
+    let current_exe = env::current_exe().ok()?;
+    Some(current_exe.parent()?.join("config.toml"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn valid_config() -> Config {
+        let mut config = Config::default();
+        config.discord.token = "token".to_owned();
+        config.openai.api_key = "key".to_owned();
+        config
+    }
+
+    fn problems(config: &Config) -> String {
+        config.validate().unwrap_err().to_string()
+    }
+
+    #[test]
+    fn environment_variables_override_the_file() {
+        let mut config = valid_config();
+        env::set_var("DISCORD_TOKEN", "token from the environment");
+        env::set_var("DEBUG", "TRUE");
+        env::set_var("USER_ID", "1234");
+        config.apply_env_overrides().unwrap();
+        assert_eq!(config.discord.token, "token from the environment");
+        assert!(config.discord.debug);
+        assert_eq!(config.discord.debug_user_id, 1234);
+        // Anything not set in the environment is left alone
+        assert_eq!(config.openai.api_key, "key");
+
+        env::set_var("USER_ID", "not a user");
+        assert!(config.apply_env_overrides().is_err());
+        for name in ["DISCORD_TOKEN", "DEBUG", "USER_ID"] {
+            env::remove_var(name);
+        }
+    }
+
+    #[test]
+    fn problems_are_listed_together() {
+        assert!(valid_config().validate().is_ok());
+
+        let mut config = valid_config();
+        config.discord.token = String::new();
+        config.text_generation.temperature = 3.0;
+        let config_problems = problems(&config);
+        assert!(config_problems.contains("discord.token"));
+        assert!(config_problems.contains("text_generation.temperature"));
+    }
+
+    #[test]
+    fn backends_need_their_own_settings() {
+        let mut config = valid_config();
+        config.openai.api_key = String::new();
+        assert!(problems(&config).contains("openai.api_key"));
+        config.text_generation.backend = ChatBackendKind::Ollama;
+        assert!(config.validate().is_ok());
+        config.text_generation.backend = ChatBackendKind::OpenAiCompatible;
+        assert!(problems(&config).contains("text_generation.base_url must be set"));
+        config.text_generation.base_url = "not a url".to_owned();
+        assert!(problems(&config).contains("text_generation.base_url is not a valid URL"));
+        config.text_generation.backend = ChatBackendKind::Runpod;
+        config.text_generation.base_url = String::new();
+        let config_problems = problems(&config);
+        assert!(config_problems.contains("runpod_endpoint_id") && config_problems.contains("runpod.api_key"));
+    }
+
+    #[test]
+    fn openai_features_need_the_openai_key() {
+        let mut config = valid_config();
+        assert!(config.check_openai_features(true, true).is_ok());
+        config.openai.api_key = String::new();
+        assert!(config.check_openai_features(false, false).is_ok());
+        assert!(config.check_openai_features(true, false).unwrap_err().to_string().contains("TTS and transcription"));
+        assert!(config.check_openai_features(false, true).unwrap_err().to_string().contains("DALL-E"));
+    }
+}
@@ -0,0 +1,420 @@
+use std::{collections::HashMap, sync::{Arc, Mutex}};
+
+use async_openai::types::{ChatCompletionRequestMessage, ChatCompletionRequestSystemMessageArgs, ChatCompletionRequestUserMessageArgs};
+use tiktoken_rs::CoreBPE;
+
+use crate::Error;
+
+use super::{chat_backend::{ChatBackend, ChatRequest}, config::TextGenerationConfig, conversation_store::{ConversationTurn, TurnRole}};
+
+// Images are not text so an estimate is used, this is the cost of a 1024x1024 image at high detail with OpenAI
+const IMAGE_TOKEN_ESTIMATE: usize = 765;
+// Every message has a few tokens of overhead for the role and separators
+const MESSAGE_TOKEN_OVERHEAD: usize = 4;
+// The longest a summary of the older part of a conversation can be
+const SUMMARY_MAX_TOKENS: u16 = 400;
+const SUMMARY_INSTRUCTION: &str = "Summarise the following conversation in a short paragraph, keeping any names, facts, decisions and open questions that later messages may refer to.";
+
+/*
+    Counts tokens the same way OpenAI models do
+    Models that tiktoken does not know (local models and so on) fall back to cl100k_base, this is only an estimate
+    for those models but it is close enough to keep the context inside the budget
+*/
+pub struct TokenCounter {
+    bpe: CoreBPE,
+}
+
+/*
+    A token counter for each model, personas can use a different model to the config and models can count tokens differently
+    Each counter is made the first time its model is used and kept for the rest of the run
+*/
+pub struct TokenCounters {
+    counters: Mutex<HashMap<String, Arc<TokenCounter>>>,
+}
+
+impl TokenCounters {
+    // The config's model is loaded straight away so a tokenizer that can't be loaded stops the bot from starting
+    pub fn new(default_model: &str) -> Result<TokenCounters, Error> {
+        let token_counters = TokenCounters { counters: Mutex::new(HashMap::new()) };
+        token_counters.for_model(default_model)?;
+        Ok(token_counters)
+    }
+
+    pub fn for_model(&self, model: &str) -> Result<Arc<TokenCounter>, Error> {
+        // A poisoned lock only means another request panicked while holding it, the counters in it are still usable
+        let mut counters = self.counters.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(token_counter) = counters.get(model) {
+            return Ok(token_counter.clone());
+        }
+        let token_counter = Arc::new(TokenCounter::for_model(model)?);
+        counters.insert(model.to_owned(), token_counter.clone());
+        Ok(token_counter)
+    }
+}
+
+impl TokenCounter {
+    pub fn for_model(model: &str) -> Result<TokenCounter, Error> {
+        let bpe = match tiktoken_rs::get_bpe_from_model(model) {
+            Ok(t) => t,
+            Err(_) => tiktoken_rs::cl100k_base().map_err(|e| e.to_string())?,
+        };
+        Ok(TokenCounter { bpe })
+    }
+
+    pub fn count(&self, text: &str) -> usize {
+        self.bpe.encode_with_special_tokens(text).len()
+    }
+
+    /*
+        Cuts text down to the given number of tokens
+        A cut can land part way through a multi-byte character so tokens are dropped until the text decodes
+    */
+    pub fn truncate(&self, text: &str, max_tokens: usize) -> String {
+        let mut tokens = self.bpe.encode_with_special_tokens(text);
+        if tokens.len() <= max_tokens {
+            return text.to_owned();
+        }
+        tokens.truncate(max_tokens);
+        while !tokens.is_empty() {
+            if let Ok(t) = self.bpe.decode(tokens.clone()) {
+                return t;
+            }
+            tokens.pop();
+        }
+        String::new()
+    }
+
+    pub fn turn_tokens(&self, turn: &ConversationTurn) -> usize {
+        MESSAGE_TOKEN_OVERHEAD
+            + self.count(&turn.content)
+            + turn.attachments.iter().map(|attachment| self.count(attachment)).sum::<usize>()
+            + turn.image_urls.len() * IMAGE_TOKEN_ESTIMATE
+    }
+}
+
+/*
+    The turns that fit in the context along with a summary of any that did not (if summaries are enabled)
+    The summary's token counts are estimates kept for the usage ledger, they are 0 if there is no summary
+*/
+pub struct AssembledContext {
+    pub summary: Option<String>,
+    pub turns: Vec<ConversationTurn>,
+    pub summary_prompt_tokens: usize,
+    pub summary_completion_tokens: usize,
+}
+
+/*
+    Fits the conversation into text_generation.context_tokens
+        - Every attachment is cut down to text_generation.max_attachment_tokens
+        - The system prompt and newest turns are always kept
+        - Older turns are kept for as long as they fit, if a turn does not fit then it is tried without its attachments
+        - Anything older than that is dropped, or summarised into a note for the system prompt if summarise_dropped_turns is enabled
+    Turns are given and returned oldest first, the last turn is the message being replied to
+*/
+pub async fn assemble_context(
+    turns: Vec<ConversationTurn>,
+    system_prompt: &str,
+    token_counter: &TokenCounter,
+    text_config: &TextGenerationConfig,
+    chat_backend: &dyn ChatBackend,
+) -> Result<AssembledContext, Error> {
+    let turns: Vec<ConversationTurn> = turns.into_iter()
+        .map(|turn| truncate_attachments(turn, token_counter, text_config.max_attachment_tokens))
+        .collect();
+    let budget = text_config.context_tokens.saturating_sub(token_counter.count(system_prompt) + MESSAGE_TOKEN_OVERHEAD);
+
+    let (kept_turns, dropped_turns) = select_turns(turns.clone(), token_counter, budget);
+    if dropped_turns.is_empty() || !text_config.summarise_dropped_turns {
+        return Ok(AssembledContext { summary: None, turns: kept_turns, summary_prompt_tokens: 0, summary_completion_tokens: 0 });
+    }
+
+    // Room is made for the summary and the turns are selected again
+    let summary_budget = SUMMARY_MAX_TOKENS as usize + MESSAGE_TOKEN_OVERHEAD;
+    let (kept_turns, dropped_turns) = select_turns(turns, token_counter, budget.saturating_sub(summary_budget));
+    let (summary, summary_prompt_tokens) = summarise_turns(&dropped_turns, token_counter, text_config, chat_backend).await?;
+    let summary_completion_tokens = token_counter.count(&summary);
+
+    Ok(AssembledContext { summary: Some(summary), turns: kept_turns, summary_prompt_tokens, summary_completion_tokens })
+}
+
+/*
+    Returns the turns that fit in the budget and the turns that were dropped, both oldest first
+*/
+fn select_turns(turns: Vec<ConversationTurn>, token_counter: &TokenCounter, budget: usize) -> (Vec<ConversationTurn>, Vec<ConversationTurn>) {
+    let mut kept_turns: Vec<ConversationTurn> = Vec::new();
+    let mut used_tokens = 0;
+    let mut newest_first = turns.into_iter().rev();
+
+    // The message being replied to is always kept, cut down if it is too big by itself
+    if let Some(mut current_turn) = newest_first.next() {
+        if token_counter.turn_tokens(&current_turn) > budget {
+            current_turn = strip_attachments(current_turn);
+            let overhead_tokens = token_counter.turn_tokens(&current_turn) - token_counter.count(&current_turn.content);
+            current_turn.content = token_counter.truncate(&current_turn.content, budget.saturating_sub(overhead_tokens));
+        }
+        used_tokens += token_counter.turn_tokens(&current_turn);
+        kept_turns.push(current_turn);
+    }
+
+    let mut dropped_turns: Vec<ConversationTurn> = Vec::new();
+    for turn in newest_first.by_ref() {
+        let turn_tokens = token_counter.turn_tokens(&turn);
+        if used_tokens + turn_tokens <= budget {
+            used_tokens += turn_tokens;
+            kept_turns.push(turn);
+            continue;
+        }
+        let stripped_turn = strip_attachments(turn.clone());
+        let stripped_tokens = token_counter.turn_tokens(&stripped_turn);
+        if used_tokens + stripped_tokens <= budget {
+            used_tokens += stripped_tokens;
+            kept_turns.push(stripped_turn);
+            continue;
+        }
+        dropped_turns.push(turn);
+        break;
+    }
+    dropped_turns.extend(newest_first);
+
+    kept_turns.reverse();
+    dropped_turns.reverse();
+    (kept_turns, dropped_turns)
+}
+
+fn truncate_attachments(mut turn: ConversationTurn, token_counter: &TokenCounter, max_attachment_tokens: usize) -> ConversationTurn {
+    turn.attachments = turn.attachments.into_iter()
+        .map(|attachment| {
+            if token_counter.count(&attachment) <= max_attachment_tokens {
+                attachment
+            } else {
+                format!("{}\n[The rest of this file has been cut to fit the context]", token_counter.truncate(&attachment, max_attachment_tokens))
+            }
+        })
+        .collect();
+    turn
+}
+
+/*
+    Replaces attachments with a note so the model knows there was something there
+*/
+fn strip_attachments(mut turn: ConversationTurn) -> ConversationTurn {
+    let removed_count = turn.attachments.len() + turn.image_urls.len();
+    if removed_count > 0 {
+        turn.content = format!("{}\n[{} attachment(s) removed to fit the context]", turn.content, removed_count);
+    }
+    turn.attachments = Vec::new();
+    turn.image_urls = Vec::new();
+    turn
+}
+
+/*
+    Asks the chat backend to summarise the turns that did not fit
+    The turns are sent as a plain transcript without attachments, cut down to fit the context if needed
+    The estimated prompt tokens are returned with the summary
+*/
+async fn summarise_turns(
+    dropped_turns: &[ConversationTurn],
+    token_counter: &TokenCounter,
+    text_config: &TextGenerationConfig,
+    chat_backend: &dyn ChatBackend,
+) -> Result<(String, usize), Error> {
+    let transcript = dropped_turns.iter()
+        .map(|turn| match turn.role {
+            TurnRole::User => turn.content.clone(),
+            TurnRole::Assistant => format!("Assistant: {}", turn.content),
+        })
+        .collect::<Vec<String>>()
+        .join("\n");
+    let transcript_budget = text_config.context_tokens.saturating_sub(SUMMARY_MAX_TOKENS as usize + 200);
+    let transcript = token_counter.truncate(&transcript, transcript_budget);
+    let prompt_tokens = token_counter.count(SUMMARY_INSTRUCTION) + token_counter.count(&transcript) + 2 * MESSAGE_TOKEN_OVERHEAD;
+
+    let messages: Vec<ChatCompletionRequestMessage> = vec![
+        ChatCompletionRequestSystemMessageArgs::default()
+            .content(SUMMARY_INSTRUCTION)
+            .build()?
+            .into(),
+        ChatCompletionRequestUserMessageArgs::default()
+            .content(transcript)
+            .build()?
+            .into(),
+    ];
+
+    let summary = chat_backend.complete(ChatRequest {
+        model: text_config.model.clone(),
+        messages,
+        max_tokens: SUMMARY_MAX_TOKENS,
+        temperature: 0.3,
+        tools: Vec::new(),
+    }).await?;
+
+    Ok((format!("Summary of the earlier part of this conversation: {}", summary.trim()), prompt_tokens))
+}
+
+#[cfg(test)]
+mod tests {
+    use async_openai::types::ChatCompletionRequestUserMessageContent;
+    use async_trait::async_trait;
+    use futures::stream;
+
+    use super::*;
+    use crate::tasks::chat_backend::{ChatEvent, ChatStream};
+
+    // Summaries are turned off in these tests so the backend is never used
+    struct UnusedBackend;
+
+    #[async_trait]
+    impl ChatBackend for UnusedBackend {
+        async fn complete_stream(&self, _request: ChatRequest) -> Result<ChatStream, Error> {
+            unreachable!("summaries are turned off")
+        }
+    }
+
+    // Replies with a set summary and keeps the transcript it was sent
+    struct SummaryBackend {
+        transcripts: Mutex<Vec<String>>,
+    }
+
+    #[async_trait]
+    impl ChatBackend for SummaryBackend {
+        async fn complete_stream(&self, request: ChatRequest) -> Result<ChatStream, Error> {
+            assert_eq!(request.max_tokens, SUMMARY_MAX_TOKENS);
+            if let Some(ChatCompletionRequestMessage::User(user_message)) = request.messages.last() {
+                if let ChatCompletionRequestUserMessageContent::Text(t) = &user_message.content {
+                    self.transcripts.lock().unwrap().push(t.clone());
+                }
+            }
+            let events = vec![Ok(ChatEvent::Text(" They talked".to_owned())), Ok(ChatEvent::Text(" about cats. ".to_owned()))];
+            Ok(Box::pin(stream::iter(events)))
+        }
+    }
+
+    fn turn(message_id: u64, content: &str, attachments: Vec<String>) -> ConversationTurn {
+        ConversationTurn {
+            message_id,
+            channel_id: 1,
+            author_id: 1,
+            role: TurnRole::User,
+            content: content.to_owned(),
+            attachments,
+            image_urls: Vec::new(),
+            reply_to: None,
+            created_at: 0,
+        }
+    }
+
+    fn message_ids(turns: &[ConversationTurn]) -> Vec<u64> {
+        turns.iter().map(|t| t.message_id).collect()
+    }
+
+    #[test]
+    fn token_counters_are_kept_for_each_model() {
+        let token_counters = TokenCounters::new("gpt-4o").unwrap();
+        let gpt_4o_counter = token_counters.for_model("gpt-4o").unwrap();
+        assert!(Arc::ptr_eq(&gpt_4o_counter, &token_counters.for_model("gpt-4o").unwrap()));
+        // GPT-4o and GPT-4 use different tokenizers, unknown models fall back to GPT-4's
+        let text = "Bonjour, comment ça va aujourd'hui ? 今日はいい天気ですね";
+        let gpt_4_counter = token_counters.for_model("gpt-4").unwrap();
+        assert_ne!(gpt_4o_counter.count(text), gpt_4_counter.count(text));
+        assert_eq!(token_counters.for_model("llama3").unwrap().count(text), gpt_4_counter.count(text));
+    }
+
+    #[test]
+    fn newest_turns_are_kept_first() {
+        let token_counter = TokenCounter::for_model("gpt-4o").unwrap();
+        let turns: Vec<ConversationTurn> = (1..=4).map(|t| turn(t, &"word ".repeat(20), Vec::new())).collect();
+        let turn_tokens = token_counter.turn_tokens(&turns[0]);
+
+        let (kept_turns, dropped_turns) = select_turns(turns, &token_counter, turn_tokens * 2 + turn_tokens / 2);
+        assert_eq!(message_ids(&kept_turns), vec![3, 4]);
+        assert_eq!(message_ids(&dropped_turns), vec![1, 2]);
+    }
+
+    #[test]
+    fn the_newest_turn_is_cut_down_when_it_is_bigger_than_the_budget() {
+        let token_counter = TokenCounter::for_model("gpt-4o").unwrap();
+        let turns = vec![
+            turn(1, "an older message", Vec::new()),
+            turn(2, &"word ".repeat(200), vec!["attached text ".repeat(50)]),
+        ];
+
+        let (kept_turns, dropped_turns) = select_turns(turns, &token_counter, 30);
+        assert_eq!(message_ids(&kept_turns), vec![2]);
+        assert_eq!(message_ids(&dropped_turns), vec![1]);
+        assert!(kept_turns[0].attachments.is_empty());
+        assert!(token_counter.turn_tokens(&kept_turns[0]) <= 30);
+    }
+
+    #[test]
+    fn attachments_are_cut_to_the_limit() {
+        let token_counter = TokenCounter::for_model("gpt-4o").unwrap();
+        let long_attachment = "line of a file\n".repeat(100);
+        let truncated_turn = truncate_attachments(turn(1, "", vec![long_attachment.clone(), "short".to_owned()]), &token_counter, 20);
+
+        let (kept_text, note) = truncated_turn.attachments[0].split_once("\n[The rest of this file").unwrap();
+        assert!(long_attachment.starts_with(kept_text));
+        assert!(token_counter.count(kept_text) <= 20);
+        assert!(note.ends_with("has been cut to fit the context]"));
+        assert_eq!(truncated_turn.attachments[1], "short");
+    }
+
+    #[tokio::test]
+    async fn the_system_prompt_is_kept_and_takes_from_the_budget() {
+        let token_counter = TokenCounter::for_model("gpt-4o").unwrap();
+        let system_prompt = "You are a helpful bot. ".repeat(20);
+        let turns: Vec<ConversationTurn> = (1..=3).map(|t| turn(t, &"word ".repeat(20), Vec::new())).collect();
+        let turn_tokens = token_counter.turn_tokens(&turns[0]);
+        let system_tokens = token_counter.count(&system_prompt) + MESSAGE_TOKEN_OVERHEAD;
+
+        // Room for the system prompt and two turns
+        let text_config = TextGenerationConfig { context_tokens: system_tokens + turn_tokens * 2, ..Default::default() };
+        let assembled_context = assemble_context(turns.clone(), &system_prompt, &token_counter, &text_config, &UnusedBackend).await.unwrap();
+        assert_eq!(message_ids(&assembled_context.turns), vec![2, 3]);
+        assert!(assembled_context.summary.is_none());
+
+        // A system prompt bigger than the whole context is never cut, only the message being replied to is kept with it
+        let text_config = TextGenerationConfig { context_tokens: system_tokens / 2, ..Default::default() };
+        let assembled_context = assemble_context(turns, &system_prompt, &token_counter, &text_config, &UnusedBackend).await.unwrap();
+        assert_eq!(message_ids(&assembled_context.turns), vec![3]);
+    }
+
+    #[tokio::test]
+    async fn dropped_turns_are_summarised() {
+        let token_counter = TokenCounter::for_model("gpt-4o").unwrap();
+        let mut turns: Vec<ConversationTurn> = (1..=6).map(|t| turn(t, &format!("Message {} {}", t, "word ".repeat(300)), Vec::new())).collect();
+        turns[1].role = TurnRole::Assistant;
+        let turn_tokens = token_counter.turn_tokens(&turns[0]);
+        let backend = SummaryBackend { transcripts: Mutex::new(Vec::new()) };
+
+        // Three turns fit without a summary and the newest two once space is made for it
+        let text_config = TextGenerationConfig {
+            context_tokens: MESSAGE_TOKEN_OVERHEAD * 2 + SUMMARY_MAX_TOKENS as usize + turn_tokens * 2,
+            summarise_dropped_turns: true,
+            ..Default::default()
+        };
+        let assembled_context = assemble_context(turns, "", &token_counter, &text_config, &backend).await.unwrap();
+        assert_eq!(message_ids(&assembled_context.turns), vec![5, 6]);
+        let summary = assembled_context.summary.unwrap();
+        assert_eq!(summary, "Summary of the earlier part of this conversation: They talked about cats.");
+        assert_eq!(assembled_context.summary_completion_tokens, token_counter.count(&summary));
+
+        let transcripts = backend.transcripts.lock().unwrap();
+        let transcript_lines: Vec<&str> = transcripts[0].lines().map(|t| t.trim_end()).collect();
+        // The transcript starts from the oldest turn, it is cut down to fit this small context
+        assert!(transcript_lines[0].starts_with("Message 1 "));
+        assert!(transcript_lines[1].starts_with("Assistant: Message 2 "));
+        assert!(!transcripts[0].contains("Message 5"));
+        assert!(assembled_context.summary_prompt_tokens > token_counter.count(&transcripts[0]));
+    }
+
+    #[tokio::test]
+    async fn the_summary_transcript_is_cut_to_fit_the_context() {
+        let token_counter = TokenCounter::for_model("gpt-4o").unwrap();
+        let turns: Vec<ConversationTurn> = (1..=3).map(|t| turn(t, &"word ".repeat(200), Vec::new())).collect();
+        let backend = SummaryBackend { transcripts: Mutex::new(Vec::new()) };
+
+        let text_config = TextGenerationConfig { context_tokens: SUMMARY_MAX_TOKENS as usize + 300, ..Default::default() };
+        summarise_turns(&turns, &token_counter, &text_config, &backend).await.unwrap();
+        assert_eq!(token_counter.count(&backend.transcripts.lock().unwrap()[0]), 100);
+    }
+}
@@ -0,0 +1,205 @@
+use rusqlite::{params, OptionalExtension, Row};
+
+use crate::Error;
+
+use super::database::Database;
+
+// Discord's attachment links are signed and stop working after about a day, this leaves some time for the request
+const IMAGE_URL_LIFETIME_SECONDS: i64 = 23 * 60 * 60;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TurnRole {
+    User,
+    Assistant,
+}
+
+/*
+    A single message in a conversation, either a prompt sent to the bot or one of the bot's replies
+    Attachments are kept as the text that is sent to the model so files do not need to be downloaded again
+*/
+#[derive(Clone, Debug)]
+pub struct ConversationTurn {
+    pub message_id: u64,
+    pub channel_id: u64,
+    pub author_id: u64,
+    pub role: TurnRole,
+    pub content: String,
+    pub attachments: Vec<String>,
+    pub image_urls: Vec<String>,
+    pub reply_to: Option<u64>,
+    pub created_at: i64,
+}
+
+pub fn save_turn(database: &Database, turn: &ConversationTurn) -> Result<(), Error> {
+    let role = match turn.role {
+        TurnRole::User => "user",
+        TurnRole::Assistant => "assistant",
+    };
+    let attachments = serde_json::to_string(&turn.attachments)?;
+    let image_urls = serde_json::to_string(&turn.image_urls)?;
+
+    database.run(|connection| connection.execute(
+        "INSERT OR REPLACE INTO conversation_messages
+            (message_id, channel_id, author_id, role, content, attachments, image_urls, reply_to, created_at)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+        params![
+            turn.message_id as i64,
+            turn.channel_id as i64,
+            turn.author_id as i64,
+            role,
+            turn.content,
+            attachments,
+            image_urls,
+            turn.reply_to.map(|t| t as i64),
+            turn.created_at
+        ],
+    ))?;
+    Ok(())
+}
+
+pub fn get_turn(database: &Database, message_id: u64) -> Result<Option<ConversationTurn>, Error> {
+    let row = database.run(|connection| connection.query_row(
+        "SELECT message_id, channel_id, author_id, role, content, attachments, image_urls, reply_to, created_at
+            FROM conversation_messages WHERE message_id = ?1",
+        params![message_id as i64],
+        read_row,
+    ).optional())?;
+
+    row.map(turn_from_row).transpose()
+}
+
+/*
+    The most recent turns in a channel before the given message and after the given time, newest first
+    This is used to continue a conversation from a mention that is not a reply
+*/
+pub fn recent_turns(database: &Database, channel_id: u64, before_message_id: u64, since: i64, limit: usize) -> Result<Vec<ConversationTurn>, Error> {
+    let rows = database.run(|connection| {
+        let mut statement = connection.prepare(
+            "SELECT message_id, channel_id, author_id, role, content, attachments, image_urls, reply_to, created_at
+                FROM conversation_messages
+                WHERE channel_id = ?1 AND message_id < ?2 AND created_at >= ?3
+                ORDER BY message_id DESC LIMIT ?4",
+        )?;
+        let rows = statement.query_map(params![channel_id as i64, before_message_id as i64, since, limit as i64], read_row)?;
+        rows.collect::<rusqlite::Result<Vec<StoredRow>>>()
+    })?;
+
+    rows.into_iter().map(turn_from_row).collect()
+}
+
+/*
+    Removes turns older than the given time, returns how many were removed
+*/
+pub fn prune_turns(database: &Database, older_than: i64) -> Result<usize, Error> {
+    database.run(|connection| connection.execute(
+        "DELETE FROM conversation_messages WHERE created_at < ?1",
+        params![older_than],
+    ))
+}
+
+/*
+    Replaces the images of a stored turn with a note once their links may have expired
+    The model can't open an expired link and some backends fail the whole request because of it
+*/
+pub fn drop_expired_images(mut turn: ConversationTurn, now: i64) -> ConversationTurn {
+    if !turn.image_urls.is_empty() && now - turn.created_at > IMAGE_URL_LIFETIME_SECONDS {
+        turn.content = format!("{}\n[{} image(s) removed as they are too old to open]", turn.content, turn.image_urls.len());
+        turn.image_urls = Vec::new();
+    }
+    turn
+}
+
+// The raw columns, kept separate so JSON errors can be returned as normal errors outside of rusqlite
+type StoredRow = (i64, i64, i64, String, String, String, String, Option<i64>, i64);
+
+fn read_row(row: &Row) -> rusqlite::Result<StoredRow> {
+    Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?, row.get(4)?, row.get(5)?, row.get(6)?, row.get(7)?, row.get(8)?))
+}
+
+fn turn_from_row(row: StoredRow) -> Result<ConversationTurn, Error> {
+    let (message_id, channel_id, author_id, role, content, attachments, image_urls, reply_to, created_at) = row;
+    Ok(ConversationTurn {
+        message_id: message_id as u64,
+        channel_id: channel_id as u64,
+        author_id: author_id as u64,
+        role: if role == "assistant" { TurnRole::Assistant } else { TurnRole::User },
+        content,
+        attachments: serde_json::from_str(&attachments)?,
+        image_urls: serde_json::from_str(&image_urls)?,
+        reply_to: reply_to.map(|t| t as u64),
+        created_at,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::*;
+
+    fn turn(message_id: u64, channel_id: u64, created_at: i64) -> ConversationTurn {
+        ConversationTurn {
+            message_id,
+            channel_id,
+            author_id: 1,
+            role: TurnRole::User,
+            content: format!("Message {}", message_id),
+            attachments: vec!["Attached file: notes.txt".to_owned()],
+            image_urls: vec!["https://cdn.discordapp.com/attachments/1/2/cat.png".to_owned()],
+            reply_to: message_id.checked_sub(1),
+            created_at,
+        }
+    }
+
+    fn message_ids(turns: &[ConversationTurn]) -> Vec<u64> {
+        turns.iter().map(|t| t.message_id).collect()
+    }
+
+    #[test]
+    fn saved_turns_load_newest_first() {
+        let database = Database::open(Path::new(":memory:")).unwrap();
+        for message_id in [3, 1, 4, 2] {
+            save_turn(&database, &turn(message_id, 10, message_id as i64 * 60)).unwrap();
+        }
+        let saved_turn = get_turn(&database, 2).unwrap().unwrap();
+        assert_eq!((saved_turn.reply_to, saved_turn.attachments.len(), saved_turn.image_urls.len()), (Some(1), 1, 1));
+        assert!(get_turn(&database, 5).unwrap().is_none());
+
+        assert_eq!(message_ids(&recent_turns(&database, 10, 5, 0, 10).unwrap()), vec![4, 3, 2, 1]);
+        // Only turns before the message and inside the time window are used, up to the limit
+        assert_eq!(message_ids(&recent_turns(&database, 10, 4, 0, 2).unwrap()), vec![3, 2]);
+        assert_eq!(message_ids(&recent_turns(&database, 10, 5, 180, 10).unwrap()), vec![4, 3]);
+    }
+
+    #[test]
+    fn turns_are_kept_to_their_channel() {
+        let database = Database::open(Path::new(":memory:")).unwrap();
+        save_turn(&database, &turn(1, 10, 60)).unwrap();
+        // Threads have their own channel ID
+        save_turn(&database, &turn(2, 11, 120)).unwrap();
+        save_turn(&database, &turn(3, 10, 180)).unwrap();
+        assert_eq!(message_ids(&recent_turns(&database, 10, 4, 0, 10).unwrap()), vec![3, 1]);
+        assert_eq!(message_ids(&recent_turns(&database, 11, 4, 0, 10).unwrap()), vec![2]);
+    }
+
+    #[test]
+    fn pruning_removes_only_older_turns() {
+        let database = Database::open(Path::new(":memory:")).unwrap();
+        for message_id in 1..=3 {
+            save_turn(&database, &turn(message_id, 10, message_id as i64 * 60)).unwrap();
+        }
+        assert_eq!(prune_turns(&database, 120).unwrap(), 1);
+        assert!(get_turn(&database, 1).unwrap().is_none());
+        assert_eq!(message_ids(&recent_turns(&database, 10, 4, 0, 10).unwrap()), vec![3, 2]);
+    }
+
+    #[test]
+    fn old_images_are_dropped() {
+        let fresh_turn = drop_expired_images(turn(1, 10, 0), IMAGE_URL_LIFETIME_SECONDS);
+        assert_eq!(fresh_turn.image_urls.len(), 1);
+        let old_turn = drop_expired_images(turn(1, 10, 0), IMAGE_URL_LIFETIME_SECONDS + 1);
+        assert!(old_turn.image_urls.is_empty());
+        assert_eq!(old_turn.attachments.len(), 1);
+        assert!(old_turn.content.contains("1 image(s) removed"));
+    }
+}
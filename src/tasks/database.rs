@@ -0,0 +1,126 @@
+use std::{path::Path, sync::Mutex};
+
+use rusqlite::Connection;
+
+use crate::Error;
+
+/*
+    The schema of the database, each entry is run once and in order
+    The number of entries that have been run is kept in SQLite's user_version so new entries
+    can be added to the end to update an existing database
+*/
+const MIGRATIONS: &[&str] = &[
+    // Conversation store, every prompt the bot has replied to and every reply it has sent
+    "CREATE TABLE conversation_messages (
+        message_id INTEGER PRIMARY KEY,
+        channel_id INTEGER NOT NULL,
+        author_id INTEGER NOT NULL,
+        role TEXT NOT NULL,
+        content TEXT NOT NULL,
+        attachments TEXT NOT NULL,
+        image_urls TEXT NOT NULL,
+        reply_to INTEGER,
+        created_at INTEGER NOT NULL
+    );
+    CREATE INDEX conversation_messages_channel ON conversation_messages (channel_id, created_at);",
+    // Personas, the system prompt and model settings used in a server or channel
+    "CREATE TABLE personas (
+        guild_id INTEGER NOT NULL,
+        name TEXT NOT NULL,
+        system_prompt TEXT NOT NULL,
+        model TEXT,
+        temperature REAL,
+        max_tokens INTEGER,
+        PRIMARY KEY (guild_id, name)
+    );
+    CREATE TABLE persona_assignments (
+        guild_id INTEGER NOT NULL,
+        channel_id INTEGER NOT NULL,
+        persona_name TEXT NOT NULL,
+        PRIMARY KEY (guild_id, channel_id)
+    );",
+    // Quotas, limits set with /quota that replace the config for a server, role or user and how much each user has used each day
+    "CREATE TABLE quota_overrides (
+        guild_id INTEGER NOT NULL,
+        scope TEXT NOT NULL,
+        target_id INTEGER NOT NULL,
+        requests_per_minute INTEGER,
+        daily_images INTEGER,
+        daily_tokens INTEGER,
+        daily_tts_characters INTEGER,
+        PRIMARY KEY (guild_id, scope, target_id)
+    );
+    CREATE TABLE quota_usage (
+        guild_id INTEGER NOT NULL,
+        user_id INTEGER NOT NULL,
+        day INTEGER NOT NULL,
+        images INTEGER NOT NULL DEFAULT 0,
+        tokens INTEGER NOT NULL DEFAULT 0,
+        tts_characters INTEGER NOT NULL DEFAULT 0,
+        PRIMARY KEY (guild_id, user_id, day)
+    );",
+    // Usage ledger, every request made to a backend with what it used and an estimated cost
+    "CREATE TABLE usage_ledger (
+        id INTEGER PRIMARY KEY,
+        guild_id INTEGER NOT NULL,
+        user_id INTEGER NOT NULL,
+        feature TEXT NOT NULL,
+        model TEXT NOT NULL,
+        prompt_tokens INTEGER NOT NULL,
+        completion_tokens INTEGER NOT NULL,
+        characters INTEGER NOT NULL,
+        images INTEGER NOT NULL,
+        audio_seconds REAL NOT NULL,
+        estimated_cost REAL NOT NULL,
+        created_at INTEGER NOT NULL
+    );
+    CREATE INDEX usage_ledger_guild ON usage_ledger (guild_id, created_at);
+    CREATE INDEX usage_ledger_user ON usage_ledger (user_id, created_at);",
+    // Permission rules, set with /permissions to allow or deny a command for a role or channel
+    "CREATE TABLE command_permissions (
+        guild_id INTEGER NOT NULL,
+        command TEXT NOT NULL,
+        scope TEXT NOT NULL,
+        target_id INTEGER NOT NULL,
+        allowed INTEGER NOT NULL,
+        PRIMARY KEY (guild_id, command, scope, target_id)
+    );",
+    // Image generations, the settings of each set of images so the buttons under them keep working after a restart
+    "CREATE TABLE image_generations (
+        id INTEGER PRIMARY KEY,
+        guild_id INTEGER NOT NULL,
+        channel_id INTEGER NOT NULL,
+        user_id INTEGER NOT NULL,
+        settings TEXT NOT NULL,
+        created_at INTEGER NOT NULL
+    );",
+];
+
+/*
+    The local SQLite database used for anything that needs to be kept between messages and restarts
+    rusqlite is not async so queries are kept short and the connection is shared behind a mutex
+*/
+pub struct Database {
+    connection: Mutex<Connection>,
+}
+
+impl Database {
+    pub fn open(path: &Path) -> Result<Database, Error> {
+        let connection = Connection::open(path)
+            .map_err(|e| format!("Unable to open the database at {}: {}", path.display(), e))?;
+        connection.pragma_update(None, "journal_mode", "WAL")?;
+
+        let current_version: i64 = connection.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+        for (index, migration) in MIGRATIONS.iter().enumerate().skip(current_version as usize) {
+            connection.execute_batch(migration)?;
+            connection.pragma_update(None, "user_version", (index + 1) as i64)?;
+        }
+
+        Ok(Database { connection: Mutex::new(connection) })
+    }
+
+    pub fn run<T>(&self, query: impl FnOnce(&Connection) -> rusqlite::Result<T>) -> Result<T, Error> {
+        let connection = self.connection.lock().map_err(|_| "The database connection has been poisoned")?;
+        Ok(query(&connection)?)
+    }
+}
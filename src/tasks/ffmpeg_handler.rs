@@ -1,70 +1,88 @@
-use std::{env, io::Write, path::PathBuf, process::{Command, Stdio}, u8};
-use serenity::all::{ChannelId, UserId};
+use std::{process::Stdio, time::Instant};
+use tokio::{io::AsyncWriteExt, process::Command};
 use which::which;
 use shell_words::split;
+use tracing::{debug, Level};
 
-use super::handle_errors::return_error;
+use crate::Error;
 
-pub async fn run_ffmpeg(file_input: Option<Vec<u8>>, url_input: Option<String>, command: String, user_id: UserId, message_channel_id: ChannelId) -> Vec<u8> {
+use super::{handle_errors::BotError, metrics::metrics};
+
+/*
+    Runs FFmpeg on either the given bytes (sent through stdin) or a URL and returns what FFmpeg writes to stdout
+    An empty output is treated as an error as it means FFmpeg was unable to convert the input
+    FFmpeg's own output is logged at debug level
+    FFmpeg is run as a tokio process so the runtime is not blocked while it runs, it is killed if this is dropped (such as by a timeout)
+*/
+pub async fn run_ffmpeg(file_input: Option<Vec<u8>>, url_input: Option<String>, command: String) -> Result<Vec<u8>, Error> {
+    let ffmpeg_started = Instant::now();
+    let ffmpeg_result = ffmpeg_convert(file_input, url_input, command).await;
+    metrics().ffmpeg_runs.observe(&[], ffmpeg_started, ffmpeg_result.is_ok());
+    ffmpeg_result
+}
+
+async fn ffmpeg_convert(file_input: Option<Vec<u8>>, url_input: Option<String>, command: String) -> Result<Vec<u8>, Error> {
 
     if file_input.is_none() && url_input.is_none() {
-        let _: u8 = return_error(user_id, message_channel_id, "No file or URL has been provided for FFmpeg to ".to_owned()).await.unwrap();
+        return Err(BotError::Ffmpeg("No file or URL has been provided for FFmpeg to convert".to_owned()));
     }
 
-    let ffmpeg_location = which("ffmpeg").unwrap_or(PathBuf::default());
-    let ffmpeg_input_args: Vec<String> = split(&command).expect("Woopsie");
+    let ffmpeg_location = which("ffmpeg").map_err(|e| BotError::Ffmpeg(format!("FFmpeg could not be found: {}", e)))?;
+    let ffmpeg_input_args: Vec<String> = split(&command).map_err(|e| BotError::Ffmpeg(format!("Unable to read the FFmpeg arguments: {}", e)))?;
     let mut ffmpeg_full_args: Vec<String> = Vec::new();
-    let debug_enabled: String = env::var("DEBUG").unwrap_or("0".to_owned());
 
     // This adds in the default args, leaving only the FFmpeg args to be passed to the function
-    if debug_enabled != "1" {
+    if !tracing::enabled!(Level::DEBUG) {
         ffmpeg_full_args.push("-hide_banner".to_owned());
         ffmpeg_full_args.push("-loglevel".to_owned());
-        ffmpeg_full_args.push("panic".to_owned());
+        ffmpeg_full_args.push("error".to_owned());
     }
     ffmpeg_full_args.push("-i".to_owned());
-    if url_input.is_none() {
-        ffmpeg_full_args.push("pipe:0".to_owned());
-    } else {
-        // Using unwrap as the value cannot be None
-        ffmpeg_full_args.push(format!(r#"{}"#, url_input.clone().unwrap()));
+    match &url_input {
+        Some(t) => ffmpeg_full_args.push(t.clone()),
+        None => ffmpeg_full_args.push("pipe:0".to_owned()),
     }
-    
+
     ffmpeg_full_args.extend(ffmpeg_input_args);
     ffmpeg_full_args.push("pipe:1".to_owned());
 
-    if ffmpeg_location != PathBuf::default() {
-        let mut ffmpeg_run = match Command::new("ffmpeg")
-            .args(ffmpeg_full_args)
-            .stdin(Stdio::piped())
-            .stdout(Stdio::piped())
-            .spawn()
-                {
-                    Ok(t) => t,
-                    Err(e) => return_error(user_id, message_channel_id, e.to_string()).await.unwrap(),
-                };
-        if url_input.is_none() {
-            let mut ffmpeg_stdin = match ffmpeg_run.stdin.take()
-                {
-                    Some(t) => t,
-                    None => return_error(user_id, message_channel_id, "Unable to take control of the FFmpeg stdin".to_owned()).await.unwrap(),
-                };
-            // Using unwrap as the value cannot be None
-            match ffmpeg_stdin.write_all(&file_input.unwrap())
-                {
-                    Ok(t) => t,
-                    Err(e) => return_error(user_id, message_channel_id, e.to_string()).await.unwrap(),
-                };
-            drop(ffmpeg_stdin);
-        }
+    // The file is only used when there is no URL
+    let stdin_input = match url_input {
+        Some(_) => None,
+        None => file_input,
+    };
+    let mut ffmpeg_run = Command::new(ffmpeg_location)
+        .args(ffmpeg_full_args)
+        .stdin(if stdin_input.is_some() { Stdio::piped() } else { Stdio::null() })
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .map_err(|e| BotError::Ffmpeg(format!("Unable to start FFmpeg: {}", e)))?;
 
-        let ffmpeg_output = match ffmpeg_run.wait_with_output()
-            {
-                Ok(t) => t,
-                Err(e) => return_error(user_id, message_channel_id, e.to_string()).await.unwrap(),
-            };
-        return ffmpeg_output.stdout;
-    }
+    // The file is written from another task while the output is read, FFmpeg stops reading its input when its output
+    // is not being read so writing it all first could wait forever
+    let stdin_writer = match (stdin_input, ffmpeg_run.stdin.take()) {
+        (Some(stdin_input), Some(mut ffmpeg_stdin)) => Some(tokio::spawn(async move {
+            // FFmpeg can close its input early once it has read enough, the output says if it worked
+            if let Err(e) = ffmpeg_stdin.write_all(&stdin_input).await {
+                debug!("FFmpeg stopped reading the file: {}", e);
+            }
+        })),
+        (Some(_), None) => return Err(BotError::Ffmpeg("Unable to take control of the FFmpeg stdin".to_owned())),
+        (None, _) => None,
+    };
 
-   return Vec::new(); 
-}
\ No newline at end of file
+    let ffmpeg_output = ffmpeg_run.wait_with_output().await.map_err(|e| BotError::Ffmpeg(format!("FFmpeg did not finish: {}", e)))?;
+    if let Some(stdin_writer) = stdin_writer {
+        stdin_writer.abort();
+    }
+    let ffmpeg_log = String::from_utf8_lossy(&ffmpeg_output.stderr);
+    if !ffmpeg_log.trim().is_empty() {
+        debug!("FFmpeg output:\n{}", ffmpeg_log.trim_end());
+    }
+    if ffmpeg_output.stdout.is_empty() {
+        return Err(BotError::Ffmpeg(format!("FFmpeg returned no output ({}): {}", ffmpeg_output.status, ffmpeg_log.trim())));
+    }
+    Ok(ffmpeg_output.stdout)
+}
@@ -1,49 +1,195 @@
-use std::env;
+use async_openai::error::OpenAIError;
+use poise::{CreateReply, FrameworkError};
+use serenity::all::{CacheHttp, ComponentInteraction, CreateInteractionResponse, CreateInteractionResponseFollowup, CreateInteractionResponseMessage, Message};
+use tracing::{debug, error, warn};
 
-use poise::serenity_prelude as serenity;
-use ::serenity::all::{ChannelId, UserId};
-use serenity::all::{Http, Message};
+use crate::Data;
+
+use super::metrics::metrics;
 
 /*
-    This function handles errors from Results<> and Nones from Option<>
-    Either using the error message or a custom string that is sent instead of the expected reply
+    Every error the bot can run into
+    Errors are passed back up with Result and shown to the user once, either by on_error for commands,
+    reply_with_error for messages or respond_with_error for buttons
+    Only UserInput, QuotaExceeded, PermissionDenied and Config errors are shown to the user as they are, the rest are logged and the user
+    is given a general message so internal details are not posted in Discord
 */
-pub async fn return_error_reply<T> (msg: Message, error_msg : String) -> Option<T> {
-    let current_http = Http::new(&env::var("DISCORD_TOKEN")
-    .expect("Expected a token in the environment - ERROR HANDLER"));    
-    // Not using the return_error function as it leads here and if there's an issue here, it'll just loop
-    msg.reply(current_http, format!("Apologies, your request cannot be completed, the error is as follows:\n```{}```", error_msg))
-    .await
-    .expect("Error showing an error - ERROR HANDLER");
+#[derive(Debug, thiserror::Error)]
+pub enum BotError {
+    #[error("Discord error: {0}")]
+    Discord(Box<serenity::Error>),
+    #[error("OpenAI error: {0}")]
+    OpenAi(#[from] OpenAIError),
+    #[error("Runpod error: {0}")]
+    Runpod(String),
+    #[error("FFmpeg error: {0}")]
+    Ffmpeg(String),
+    #[error("Configuration error: {0}")]
+    Config(String),
+    // Something the user asked for that cannot be done, the message is shown to the user
+    #[error("{0}")]
+    UserInput(String),
+    // The user has hit a rate limit or quota, the message is shown to the user
+    #[error("{0}")]
+    QuotaExceeded(String),
+    // A permission rule set with /permissions blocks the command here, the message is shown to the user
+    #[error("{0}")]
+    PermissionDenied(String),
+    // The user cancelled the request (such as with a cancel button), this is not shown as an error
+    #[error("The request was cancelled")]
+    Cancelled,
+    #[error("Database error: {0}")]
+    Database(#[from] rusqlite::Error),
+    #[error("HTTP error: {0}")]
+    Http(#[from] reqwest::Error),
+    #[error("{0}")]
+    Internal(String),
+}
+
+impl BotError {
+    /*
+        The message shown in Discord
+    */
+    pub fn user_message(&self) -> String {
+        match self {
+            BotError::UserInput(t) | BotError::QuotaExceeded(t) | BotError::PermissionDenied(t) => t.clone(),
+            BotError::Config(t) => format!("The bot is not set up for this: {}", t),
+            BotError::Cancelled => "The request was cancelled".to_owned(),
+            BotError::Discord(_) => "Discord was unable to complete the request, please try again".to_owned(),
+            // OpenAI's own messages are written for users (content policy, rate limits and so on)
+            BotError::OpenAi(OpenAIError::ApiError(t)) => format!("OpenAI returned an error: {}", t.message),
+            BotError::OpenAi(_) => "Unable to get a response from OpenAI, please try again later".to_owned(),
+            BotError::Runpod(_) => "Runpod was unable to complete the request, please try again later".to_owned(),
+            BotError::Ffmpeg(_) => "Unable to convert the file, make sure it is an audio or video file".to_owned(),
+            BotError::Database(_) | BotError::Http(_) | BotError::Internal(_) => "Something went wrong on my end, the details have been logged".to_owned(),
+        }
+    }
+
+    // The kind label used in the error metrics
+    pub fn kind(&self) -> &'static str {
+        match self {
+            BotError::Discord(_) => "discord",
+            BotError::OpenAi(_) => "openai",
+            BotError::Runpod(_) => "runpod",
+            BotError::Ffmpeg(_) => "ffmpeg",
+            BotError::Config(_) => "config",
+            BotError::UserInput(_) => "user_input",
+            BotError::QuotaExceeded(_) => "quota",
+            BotError::PermissionDenied(_) => "permission",
+            BotError::Cancelled => "cancelled",
+            BotError::Database(_) => "database",
+            BotError::Http(_) => "http",
+            BotError::Internal(_) => "internal",
+        }
+    }
 
-    panic!("{}", format!("An error has occured: {}", error_msg))
+    // User input errors are expected so they are only logged at debug level, the span has the details of the request
+    fn log(&self, location: &str) {
+        metrics().errors.with_label_values(&[self.kind()]).inc();
+        match self {
+            BotError::UserInput(_) | BotError::QuotaExceeded(_) | BotError::PermissionDenied(_) | BotError::Cancelled => debug!(location, "Rejected a request: {}", self),
+            BotError::Config(_) => warn!(location, "{}", self),
+            _ => error!(location, "{}", self),
+        }
+    }
+}
+
+impl From<serenity::Error> for BotError {
+    fn from(error: serenity::Error) -> Self {
+        BotError::Discord(Box::new(error))
+    }
+}
+
+impl From<serde_json::Error> for BotError {
+    fn from(error: serde_json::Error) -> Self {
+        BotError::Internal(format!("JSON error: {}", error))
+    }
+}
+
+impl From<std::io::Error> for BotError {
+    fn from(error: std::io::Error) -> Self {
+        BotError::Internal(format!("IO error: {}", error))
+    }
+}
+
+impl From<String> for BotError {
+    fn from(error: String) -> Self {
+        BotError::Internal(error)
+    }
+}
+
+impl From<&str> for BotError {
+    fn from(error: &str) -> Self {
+        BotError::Internal(error.to_owned())
+    }
+}
+
+/*
+    Hitting a limit, being blocked by a permission rule or cancelling are not errors to the user so they are shown as plain text
+*/
+fn error_text(error: &BotError) -> String {
+    match error {
+        BotError::QuotaExceeded(_) | BotError::PermissionDenied(_) | BotError::Cancelled => error.user_message(),
+        _ => format!("Apologies, your request cannot be completed, the error is as follows:\n```{}```", error.user_message()),
+    }
 }
 
 /*
-    This function handles errors from Results<> and Nones from Option<>
-    Either using the error message or a custom string that is sent instead of the expected reply
+    Shows an error from a message reply (a mention) to the user
 */
-pub async fn return_error<T> (user_id: UserId, message_channel_id: ChannelId, error_msg : String) -> Option<T> {
-    let current_http = Http::new(&env::var("DISCORD_TOKEN")
-    .expect("Expected a token in the environment - ERROR HANDLER"));    
-    // Not using the return_error function as it leads here and if there's an issue here, it'll just loop
-    message_channel_id.say(current_http, format!("Apologies <@{}>, your request cannot be completed, the error is as follows:\n```{}```", user_id, error_msg))
-    .await
-    .expect("Error showing an error - ERROR HANDLER");
+pub async fn reply_with_error(cache_http: impl CacheHttp, msg: &Message, error: BotError) {
+    error.log("a message reply");
+    if let Err(e) = msg.reply(cache_http, error_text(&error)).await {
+        warn!("Unable to show an error to the user: {}", e);
+    }
+}
 
-    panic!("{}", format!("An error has occured: {}", error_msg))
+/*
+    Shows an error from a button to the user who pressed it, only they can see it
+    The interaction may already have been responded to (with a modal or an acknowledgement), the error is sent as a followup then
+*/
+pub async fn respond_with_error(cache_http: impl CacheHttp, interaction: &ComponentInteraction, error: BotError) {
+    error.log("a button");
+    let response = CreateInteractionResponse::Message(CreateInteractionResponseMessage::new().content(error_text(&error)).ephemeral(true));
+    if interaction.create_response(&cache_http, response).await.is_ok() {
+        return;
+    }
+    let followup = CreateInteractionResponseFollowup::new().content(error_text(&error)).ephemeral(true);
+    if let Err(e) = interaction.create_followup(&cache_http, followup).await {
+        warn!("Unable to show an error to the user: {}", e);
+    }
 }
 
 /*
-    This function handles errors from Results<> and Nones from Option<>
-    Either using the error message or a custom string that is sent instead of the expected reply
-    This is for slash command responses
+    Shows an error from a command to the user, this is set as poise's on_error
+    Errors from command_check (permission rules) are shown the same way
+    Anything else (missing Discord permissions, bad arguments and so on) is left to poise's default handler
 */
-pub async fn return_error_command<T> (ctx: crate::Context<'_>, error_msg : String) -> Option<T> {
-    // Not using the return_error function as it leads here and if there's an issue here, it'll just loop
-    ctx.reply(format!("Apologies, your request cannot be completed, the error is as follows:\n```{}```", error_msg))
-    .await
-    .expect("Error showing an error - ERROR HANDLER");
-
-    panic!("{}", format!("An error has occured: {}", error_msg))
-}
\ No newline at end of file
+pub async fn on_error(error: FrameworkError<'_, Data, BotError>) {
+    match error {
+        FrameworkError::Command { error, ctx, .. } | FrameworkError::CommandCheckFailed { error: Some(error), ctx, .. } => {
+            error.log(&format!("the {} command", ctx.command().qualified_name));
+            if let Err(e) = ctx.send(CreateReply::default().content(error_text(&error)).reply(true)).await {
+                warn!("Unable to show an error to the user: {}", e);
+            }
+        },
+        error => {
+            if let Err(e) = poise::builtins::on_error(error).await {
+                error!("Unable to handle an error: {}", e);
+            }
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn limits_are_plain_text_and_errors_are_framed() {
+        let quota_text = error_text(&BotError::QuotaExceeded("You've hit your limit of 5 images per day, it resets in 3 hours".to_owned()));
+        assert_eq!(quota_text, "You've hit your limit of 5 images per day, it resets in 3 hours");
+        assert_eq!(error_text(&BotError::Cancelled), "The request was cancelled");
+        assert!(error_text(&BotError::Runpod("worker crashed".to_owned())).starts_with("Apologies, your request cannot be completed"));
+    }
+}
@@ -0,0 +1,64 @@
+use std::sync::Arc;
+
+use axum::{extract::{Path, State}, http::{header::CONTENT_TYPE, StatusCode}, response::IntoResponse, routing::{get, post}, Router};
+use tokio::net::TcpListener;
+use tracing::{debug, error, info, warn};
+
+use crate::{tasks::handle_errors::BotError, Error};
+
+use super::{config::HttpConfig, metrics::metrics, runpod::RunpodWebhooks};
+
+/*
+    Starts the optional HTTP server, this serves the Prometheus metrics at /metrics and the Runpod webhooks
+    The address is bound before returning so a bad address stops the bot at startup, the server then runs in the background
+*/
+pub async fn start_http_server(http_config: &HttpConfig, runpod_webhooks: Option<Arc<RunpodWebhooks>>) -> Result<(), Error> {
+    let router = build_router(http_config.enable_metrics, runpod_webhooks);
+
+    let listener = TcpListener::bind(&http_config.listen_address).await
+        .map_err(|e| BotError::Config(format!("Unable to listen on {}: {}", http_config.listen_address, e)))?;
+    info!("The HTTP server is listening on {}", http_config.listen_address);
+
+    tokio::spawn(async move {
+        if let Err(e) = axum::serve(listener, router).await {
+            error!("The HTTP server has stopped: {}", e);
+        }
+    });
+    Ok(())
+}
+
+pub fn build_router(enable_metrics: bool, runpod_webhooks: Option<Arc<RunpodWebhooks>>) -> Router {
+    let mut router = Router::new();
+    if enable_metrics {
+        router = router.route("/metrics", get(metrics_handler));
+    }
+    if let Some(runpod_webhooks) = runpod_webhooks {
+        router = router.merge(
+            Router::new()
+                .route("/runpod/webhook/:webhook_id", post(runpod_webhook_handler))
+                .with_state(runpod_webhooks)
+        );
+    }
+    router
+}
+
+async fn metrics_handler() -> impl IntoResponse {
+    ([(CONTENT_TYPE, "text/plain; version=0.0.4")], metrics().encode())
+}
+
+/*
+    Runpod posts the finished job here, the body is the same as the response from /status
+*/
+async fn runpod_webhook_handler(State(runpod_webhooks): State<Arc<RunpodWebhooks>>, Path(webhook_id): Path<String>, body: String) -> StatusCode {
+    match runpod_webhooks.complete(&webhook_id, &body) {
+        Ok(true) => StatusCode::OK,
+        Ok(false) => {
+            debug!("Runpod sent a job to a webhook that is not waiting");
+            StatusCode::NOT_FOUND
+        },
+        Err(e) => {
+            warn!("Unable to read a Runpod webhook: {}", e);
+            StatusCode::BAD_REQUEST
+        },
+    }
+}
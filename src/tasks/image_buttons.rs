@@ -0,0 +1,260 @@
+use std::time::Instant;
+
+use base64::prelude::*;
+use poise::serenity_prelude as serenity;
+use serenity::all::{ButtonStyle, ComponentInteraction, CreateActionRow, CreateAllowedMentions, CreateAttachment, CreateButton, CreateInteractionResponse, CreateMessage, Typing};
+
+use crate::{tasks::handle_errors::{respond_with_error, BotError}, Data, Error};
+
+use super::{
+    config::RunpodConfig,
+    image_generation::{ask_for_settings, download_input_image, load_function_data, random_seed, run_generation, with_progress_message, GenerationSource, InputImage, InputImages},
+    image_generation_store::get_image_generation,
+    metrics::metrics,
+    permissions::check_permission,
+    quota_store::QuotaKind,
+    quotas::{check_daily_quota, record_daily_usage, start_request, QuotaUser},
+    runpod::JobRequest,
+    usage::record_image_usage
+};
+
+// Every image button's custom ID starts with this, followed by the action, the generation ID and the image (for buttons on one image)
+pub const IMAGE_BUTTON_PREFIX: &str = "imagegen:";
+// Upscaling is recorded under this name in the usage ledger and metrics, it can be priced in pricing.images
+pub const UPSCALE_USAGE_NAME: &str = "upscale";
+// How much a variation changes the image it is made from
+const VARIATION_PROMPT_STRENGTH: f32 = 0.6;
+// Discord allows 5 buttons in a row
+const MAX_BUTTONS_PER_ROW: usize = 5;
+
+/*
+    What a button under generated images does
+        - Regenerate - The same settings with a new seed
+        - EditPrompt - The model's modal filled in with the settings, the seed is kept so only the changes make a difference
+        - Variation - One of the images used as the input, DALL-E makes a variation and Stable Diffusion uses it for img2img
+        - Upscale - One of the images made larger with runpod.upscale_endpoint
+*/
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum ImageAction {
+    Regenerate,
+    EditPrompt,
+    Variation,
+    Upscale,
+}
+
+impl ImageAction {
+    fn as_str(&self) -> &'static str {
+        match self {
+            ImageAction::Regenerate => "regenerate",
+            ImageAction::EditPrompt => "edit",
+            ImageAction::Variation => "variation",
+            ImageAction::Upscale => "upscale",
+        }
+    }
+
+    fn parse(action: &str) -> Option<ImageAction> {
+        match action {
+            "regenerate" => Some(ImageAction::Regenerate),
+            "edit" => Some(ImageAction::EditPrompt),
+            "variation" => Some(ImageAction::Variation),
+            "upscale" => Some(ImageAction::Upscale),
+            _ => None,
+        }
+    }
+}
+
+#[derive(serde::Serialize)]
+struct UpscaleRunInput {
+    image: String,
+    scale: u32,
+}
+
+/*
+    The body sent to the upscaler, the worker is given a base64 image and the scale and returns a base64 image
+*/
+#[derive(serde::Serialize)]
+struct UpscaleRequest {
+    input: UpscaleRunInput,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    webhook: Option<String>,
+}
+
+impl JobRequest for UpscaleRequest {
+    fn set_webhook(&mut self, webhook: String) {
+        self.webhook = Some(webhook);
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct UpscaleOutput {
+    image: String,
+}
+
+fn button_id(action: ImageAction, generation_id: i64, image_index: Option<usize>) -> String {
+    match image_index {
+        Some(image_index) => format!("{}{}:{}:{}", IMAGE_BUTTON_PREFIX, action.as_str(), generation_id, image_index),
+        None => format!("{}{}:{}", IMAGE_BUTTON_PREFIX, action.as_str(), generation_id),
+    }
+}
+
+fn parse_button_id(custom_id: &str) -> Option<(ImageAction, i64, Option<usize>)> {
+    let mut parts = custom_id.strip_prefix(IMAGE_BUTTON_PREFIX)?.split(':');
+    let action = ImageAction::parse(parts.next()?)?;
+    let generation_id = parts.next()?.parse().ok()?;
+    let image_index = match parts.next() {
+        Some(t) => Some(t.parse().ok()?),
+        None => None,
+    };
+    Some((action, generation_id, image_index))
+}
+
+/*
+    The buttons under a set of generated images
+    Images made from an input image can't be regenerated as the input image is not saved, so they only get the buttons for each image
+*/
+pub fn image_buttons(generation_id: i64, image_count: usize, can_regenerate: bool, runpod_config: &RunpodConfig) -> Vec<CreateActionRow> {
+    let mut action_rows = Vec::new();
+    if can_regenerate {
+        action_rows.push(CreateActionRow::Buttons(vec![
+            CreateButton::new(button_id(ImageAction::Regenerate, generation_id, None)).label("Regenerate").style(ButtonStyle::Primary),
+            CreateButton::new(button_id(ImageAction::EditPrompt, generation_id, None)).label("Edit prompt").style(ButtonStyle::Secondary),
+        ]));
+    }
+    let image_buttons = |action: ImageAction, label: &str| {
+        (0..image_count.min(MAX_BUTTONS_PER_ROW))
+            .map(|t| CreateButton::new(button_id(action, generation_id, Some(t))).label(format!("{} {}", label, t + 1)).style(ButtonStyle::Secondary))
+            .collect()
+    };
+    action_rows.push(CreateActionRow::Buttons(image_buttons(ImageAction::Variation, "Variation")));
+    if !runpod_config.upscale_endpoint.trim().is_empty() {
+        action_rows.push(CreateActionRow::Buttons(image_buttons(ImageAction::Upscale, "Upscale")));
+    }
+    action_rows
+}
+
+/*
+    Handles a press of a button under generated images, this is called from the event handler so the buttons work after a restart
+    The settings are loaded from the database with the generation ID in the button, the user who pressed it is charged for it
+*/
+pub async fn handle_image_button(ctx: &serenity::Context, data: &Data, interaction: &ComponentInteraction) {
+    if let Err(e) = run_image_button(ctx, data, interaction).await {
+        respond_with_error(ctx, interaction, e).await;
+    }
+}
+
+async fn run_image_button(ctx: &serenity::Context, data: &Data, interaction: &ComponentInteraction) -> Result<(), Error> {
+    let (action, generation_id, image_index) = parse_button_id(&interaction.data.custom_id)
+        .ok_or(BotError::UserInput("This button is from an older version of the bot and no longer works".to_owned()))?;
+    let quota_user = QuotaUser::from_interaction(interaction);
+    check_permission(data, &quota_user, interaction.channel_id.get(), "imagegen")?;
+
+    let mut settings = get_image_generation(&data.database, generation_id)?
+        .ok_or(BotError::UserInput("The settings for these images are no longer available".to_owned()))?;
+    let function = load_function_data()?.into_iter()
+        .find(|t| t.function_command == settings.function_command)
+        .ok_or(BotError::UserInput(format!("The model {} is no longer available", settings.function_command)))?;
+
+    let input_image = match image_index {
+        Some(image_index) => Some(message_image(interaction, image_index).await?),
+        None => None,
+    };
+
+    match (action, input_image) {
+        (ImageAction::Regenerate, _) => {
+            interaction.create_response(ctx, CreateInteractionResponse::Acknowledge).await?;
+            settings.seed = random_seed();
+            run_generation(GenerationSource::Component(ctx, interaction), data, &quota_user, &function, settings, &InputImages::default()).await
+        },
+        (ImageAction::EditPrompt, _) => {
+            if !ask_for_settings(ctx, interaction, &function, &mut settings, true).await? {
+                return Ok(());
+            }
+            run_generation(GenerationSource::Component(ctx, interaction), data, &quota_user, &function, settings, &InputImages::default()).await
+        },
+        (ImageAction::Variation, Some(input_image)) => {
+            interaction.create_response(ctx, CreateInteractionResponse::Acknowledge).await?;
+            settings.seed = random_seed();
+            if function.function_type == "openai_dalle" {
+                settings.prompt = None;
+            }
+            let input_images = InputImages { image: Some(input_image), mask: None, prompt_strength: Some(VARIATION_PROMPT_STRENGTH) };
+            run_generation(GenerationSource::Component(ctx, interaction), data, &quota_user, &function, settings, &input_images).await
+        },
+        (ImageAction::Upscale, Some(input_image)) => upscale_image(ctx, data, interaction, &quota_user, input_image, image_index.unwrap_or_default()).await,
+        (_, None) => Err(BotError::UserInput("This button needs an image".to_owned())),
+    }
+}
+
+/*
+    One of the generated images on the message the button is on, the images are named by their position when they are sent
+*/
+async fn message_image(interaction: &ComponentInteraction, image_index: usize) -> Result<InputImage, Error> {
+    let attachment = interaction.message.attachments.iter()
+        .find(|t| t.filename == format!("image_output_{}.png", image_index))
+        .cloned()
+        .ok_or(BotError::UserInput("That image is no longer on the message".to_owned()))?;
+    download_input_image(attachment, "image").await
+}
+
+/*
+    Makes one of the images larger with the upscaler and sends it as a new message
+*/
+async fn upscale_image(ctx: &serenity::Context, data: &Data, interaction: &ComponentInteraction, quota_user: &QuotaUser, input_image: InputImage, image_index: usize) -> Result<(), Error> {
+    let runpod_config = &data.config.runpod;
+    if runpod_config.upscale_endpoint.trim().is_empty() {
+        return Err(BotError::Config("No upscaler has been set, runpod.upscale_endpoint is empty".to_owned()));
+    }
+    let quota_limits = start_request(data, quota_user)?;
+    check_daily_quota(data, quota_user, &quota_limits, QuotaKind::Images, 1)?;
+    interaction.create_response(ctx, CreateInteractionResponse::Acknowledge).await?;
+
+    let typing = Typing::start(ctx.http.clone(), interaction.channel_id);
+    let upscale_result = run_upscale(ctx, data, interaction, quota_user, input_image, image_index).await;
+    typing.stop();
+    upscale_result
+}
+
+async fn run_upscale(ctx: &serenity::Context, data: &Data, interaction: &ComponentInteraction, quota_user: &QuotaUser, input_image: InputImage, image_index: usize) -> Result<(), Error> {
+    let runpod_config = &data.config.runpod;
+    let upscale_request = UpscaleRequest {
+        input: UpscaleRunInput { image: BASE64_STANDARD.encode(&input_image.bytes), scale: runpod_config.upscale_factor },
+        webhook: None,
+    };
+    let upscale_started = Instant::now();
+    let upscale_result = with_progress_message(GenerationSource::Component(ctx, interaction), "Upscaling your image, this can take a minute", true, |cancelled| {
+        data.runpod.run::<_, UpscaleOutput>(&runpod_config.upscale_endpoint, upscale_request, false, cancelled)
+    }).await;
+    let upscale_output = match upscale_result {
+        Err(BotError::Cancelled) => return Ok(()),
+        upscale_result => {
+            metrics().image_generations.observe(&[UPSCALE_USAGE_NAME], upscale_started, upscale_result.is_ok());
+            upscale_result?
+        },
+    };
+    let image_bytes = BASE64_STANDARD.decode(upscale_output.image.replace("data:image/png;base64,", ""))
+        .map_err(|e| BotError::Runpod(format!("The upscaled image could not be decoded: {}", e)))?;
+
+    record_daily_usage(data, quota_user, QuotaKind::Images, 1);
+    record_image_usage(data, quota_user, UPSCALE_USAGE_NAME, 1);
+
+    let requester_id = interaction.user.id;
+    let message_builder = CreateMessage::new()
+        .allowed_mentions(CreateAllowedMentions::new().users(vec![requester_id]))
+        .content(format!("<@{}> here is image {} upscaled {}x", requester_id, image_index + 1, runpod_config.upscale_factor))
+        .add_file(CreateAttachment::bytes(image_bytes, format!("image_upscaled_{}.png", image_index)));
+    interaction.channel_id.send_message(ctx, message_builder).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn button_ids_round_trip() {
+        assert_eq!(parse_button_id(&button_id(ImageAction::Regenerate, 12, None)), Some((ImageAction::Regenerate, 12, None)));
+        assert_eq!(parse_button_id(&button_id(ImageAction::Upscale, 12, Some(3))), Some((ImageAction::Upscale, 12, Some(3))));
+        assert_eq!(parse_button_id("imagegen:unknown:12"), None);
+        assert_eq!(parse_button_id("model_select"), None);
+    }
+}
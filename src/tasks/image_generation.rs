@@ -1,52 +1,193 @@
-use std::{env, fs, sync::Arc, time::Duration, u32};
+use std::{env, fs, future::{pending, Future}, time::{Duration, Instant}};
 
 use poise::serenity_prelude as serenity;
-use async_openai::{types::{CreateImageRequestArgs, Image, ImageModel, ImageQuality, ImageSize, ImageStyle, ResponseFormat}, Client};
+use async_openai::{config::OpenAIConfig, types::{CreateImageEditRequestArgs, CreateImageRequestArgs, CreateImageVariationRequestArgs, DallE2ImageSize, Image, ImageInput, ImageModel, ImageQuality, ImageSize, ImageStyle, ImagesResponse, ResponseFormat}, Client};
 use base64::prelude::*;
-use reqwest::{header::HeaderValue, Response};
-use ::serenity::all::{ComponentInteractionDataKind, CreateAllowedMentions, CreateEmbed, CreateMessage, CreateSelectMenuOption, Typing};
+use poise::CreateReply;
+use ::serenity::all::{AutocompleteChoice, ButtonStyle, ChannelId, ComponentInteraction, ComponentInteractionDataKind, CreateAllowedMentions, CreateButton, CreateEmbed, CreateInteractionResponse, CreateInteractionResponseFollowup, CreateMessage, CreateSelectMenuOption, MessageId, Timestamp, Typing, UserId};
+use futures::future::BoxFuture;
 use serenity::all::CreateAttachment;
-use tokio::time::sleep;
+use tracing::{instrument, warn};
 
-use crate::{tasks::handle_errors::return_error, Error, FunctionData, JsonObject};
+use crate::{tasks::{handle_errors::BotError, image_buttons::image_buttons, image_generation_store::save_image_generation, metrics::metrics, quota_store::QuotaKind, quotas::{check_daily_quota, record_daily_usage, start_request, QuotaUser}, runpod::{JobRequest, RunpodClient}, usage::record_image_usage}, Data, Error, FunctionData, JsonObject};
 
 
-#[derive(serde::Deserialize)]
-struct RunResponseObject {
-    id: String,
-}
-
+/*
+    The output of a Stable Diffusion worker, each image is a base64 string
+    Workers that report the seed they used give it back with the images
+*/
 #[derive(serde::Deserialize)]
 struct ImageGenOutput {
     images: Vec<String>,
+    #[serde(default)]
+    seed: Option<u64>,
 }
 
-#[derive(serde::Deserialize)]
-struct OutputResponseObject {
-    output: Option<ImageGenOutput>,
-    status: Option<String>
+/*
+    The input of a Stable Diffusion worker based on the official Runpod API
+    The optional fields are left out of the request when they are not set so the worker uses its own defaults,
+    init_image and mask are base64 images (or URLs) for img2img and inpainting
+*/
+#[derive(serde::Serialize, Clone, Debug)]
+pub struct ImageGenRunInput {
+    pub prompt: String,
+    pub negative_prompt: String,
+    pub width: u32,
+    pub height: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub init_image: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mask: Option<String>,
+    pub guidance_scale: f32,
+    pub num_inference_steps: u32,
+    pub num_images: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub prompt_strength: Option<f32>,
+    pub scheduler: String,
+    // A random seed is picked by the worker when this is not set
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub seed: Option<u64>,
 }
 
-#[derive(serde::Serialize)]
-struct ImageGenRunInput {
-    prompt: String,
-    negative_prompt: String,
-    width: u32,
-    height: u32,
-    init_image: String,
-    mask: String,
-    guidance_scale: f32,
-    num_inference_steps: u32,
-    num_outputs: u32,
-    prompt_strength: f32,
-    scheduler: String,
-    seed: u32
-}
-
-#[derive(serde::Serialize)]
+impl Default for ImageGenRunInput {
+    fn default() -> Self {
+        ImageGenRunInput {
+            prompt: String::new(),
+            negative_prompt: String::new(),
+            width: 1024,
+            height: 1024,
+            init_image: None,
+            mask: None,
+            guidance_scale: 7.5,
+            num_inference_steps: 40,
+            num_images: 1,
+            prompt_strength: None,
+            scheduler: "K_EULER".to_owned(),
+            seed: None,
+        }
+    }
+}
+
+/*
+    The body sent to /run and /runsync, the Runpod client sets the webhook when Runpod webhooks are turned on
+*/
+#[derive(serde::Serialize, Debug)]
 struct ImageGenRequest {
     input: ImageGenRunInput,
-    webhook: String
+    #[serde(skip_serializing_if = "Option::is_none")]
+    webhook: Option<String>,
+}
+
+impl JobRequest for ImageGenRequest {
+    fn set_webhook(&mut self, webhook: String) {
+        self.webhook = Some(webhook);
+    }
+}
+
+/*
+    The Stable Diffusion parameters for a runpod_image model in functions.json
+    Users can pick any value in the ranges and any of the schedulers, the first scheduler is the default
+    The image size is worked out from the aspect ratio, either by fitting it to pixel_budget with both sides a multiple of size_divisor
+    or by picking the closest of the resolutions the model was trained on (such as SDXL's buckets) when they are listed
+*/
+#[derive(serde::Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct ImageParameters {
+    pub steps: ParameterRange,
+    pub images: ParameterRange,
+    pub schedulers: Vec<String>,
+    pub pixel_budget: u32,
+    pub size_divisor: u32,
+    pub resolutions: Vec<(u32, u32)>,
+}
+
+#[derive(serde::Deserialize, Clone, Copy, Debug)]
+pub struct ParameterRange {
+    pub default: u32,
+    pub min: u32,
+    pub max: u32,
+}
+
+impl Default for ImageParameters {
+    fn default() -> Self {
+        ImageParameters {
+            steps: ParameterRange { default: 40, min: 1, max: 100 },
+            images: ParameterRange { default: 2, min: 1, max: 4 },
+            schedulers: ["K_EULER", "K_EULER_ANCESTRAL", "DDIM", "DPMSolverMultistep", "KLMS", "PNDM"].map(str::to_owned).to_vec(),
+            // 1024x1024, the size Stable Diffusion XL is trained on
+            pixel_budget: 1024 * 1024,
+            size_divisor: 8,
+            resolutions: Vec::new(),
+        }
+    }
+}
+
+impl ImageParameters {
+    /*
+        Fills in the model's defaults for anything that was not given and checks the rest are allowed for this model
+        Returns the steps, scheduler and image count
+    */
+    pub fn resolve(&self, steps: Option<u32>, scheduler: Option<&str>, image_count: Option<u32>) -> Result<(u32, String, u32), Error> {
+        let steps = self.steps.resolve("steps", steps)?;
+        let image_count = self.images.resolve("image count", image_count)?;
+        let scheduler = match scheduler {
+            Some(scheduler) => self.schedulers.iter()
+                .find(|t| t.eq_ignore_ascii_case(scheduler))
+                .cloned()
+                .ok_or_else(|| BotError::UserInput(format!("This model can't use the {} scheduler, it can be one of: {}", scheduler, self.schedulers.join(", "))))?,
+            None => self.schedulers[0].clone(),
+        };
+        Ok((steps, scheduler, image_count))
+    }
+
+    /*
+        The width and height to generate for an aspect ratio
+        With resolutions the one with the closest aspect ratio is used, otherwise the size is fitted to the pixel budget
+        and each side is rounded to the nearest multiple of the divisor
+        Ratios past 4:1 (or 1:4) are treated as 4:1 so a side can't shrink to nothing or grow past what the models can make
+    */
+    pub fn image_size(&self, width_ratio: f32, height_ratio: f32) -> (u32, u32) {
+        let aspect_ratio = (width_ratio / height_ratio).clamp(1.0 / MAX_ASPECT_RATIO, MAX_ASPECT_RATIO);
+        // Ratios are compared on a log scale so 2:1 and 1:2 are as far from 1:1 as each other
+        let ratio_distance = |&&(width, height): &&(u32, u32)| ((width as f32 / height as f32) / aspect_ratio).ln().abs();
+        if let Some(&resolution) = self.resolutions.iter().min_by(|a, b| ratio_distance(a).total_cmp(&ratio_distance(b))) {
+            return resolution;
+        }
+        let divisor = self.size_divisor as f32;
+        let round_to_divisor = |side: f32| ((side / divisor).round().max(1.0) * divisor) as u32;
+        let height = (self.pixel_budget as f32 / aspect_ratio).sqrt();
+        (round_to_divisor(height * aspect_ratio), round_to_divisor(height))
+    }
+
+    // Problems with the parameters in functions.json, these are found when the file is loaded
+    fn problems(&self, function_command: &str) -> Vec<String> {
+        let mut problems = Vec::new();
+        for (name, range) in [("steps", self.steps), ("images", self.images)] {
+            if range.min == 0 || range.min > range.default || range.default > range.max {
+                problems.push(format!("{} has parameters.{} that are not 0 < min <= default <= max", function_command, name));
+            }
+        }
+        if self.schedulers.is_empty() {
+            problems.push(format!("{} has no parameters.schedulers", function_command));
+        }
+        if self.pixel_budget == 0 || self.size_divisor == 0 {
+            problems.push(format!("{} has a parameters.pixel_budget or parameters.size_divisor of 0", function_command));
+        }
+        if self.resolutions.iter().any(|&(width, height)| width == 0 || height == 0) {
+            problems.push(format!("{} has a resolution in parameters.resolutions with a side of 0", function_command));
+        }
+        problems
+    }
+}
+
+impl ParameterRange {
+    fn resolve(&self, name: &str, value: Option<u32>) -> Result<u32, Error> {
+        match value {
+            Some(t) if t < self.min || t > self.max => Err(BotError::UserInput(format!("The {} must be from {} to {} for this model", name, self.min, self.max))),
+            Some(t) => Ok(t),
+            None => Ok(self.default),
+        }
+    }
 }
 
 #[derive(Debug, poise::Modal)]
@@ -56,9 +197,9 @@ struct ServerlessModal {
     prompt: String,
     #[name = "Negative Prompt (Default: null)"]
     neg_prompt: Option<String>,
-    #[name = "Width Ratio (Default: 1)"]
+    #[name = "Width Ratio (Default: 1, or the image's)"]
     width_ratio: Option<String>,
-    #[name = "Height Ratio (Default: 1)"]
+    #[name = "Height Ratio (Default: 1, or the image's)"]
     height_ratio: Option<String>,
     #[name = "Guidance scale (Default: 7.5)"]
     guide_scale: Option<String>,
@@ -70,198 +211,867 @@ struct DalleModal {
     #[name = "Prompt"]
     prompt: String,}
 
-#[poise::command(prefix_command, slash_command)]
-pub async fn imagegen(ctx: crate::Context<'_>) -> Result<(), Error> {
+/*
+    An image used as the input of a generation (img2img, inpainting or variations), this is downloaded once and used for every generation
+*/
+pub struct InputImage {
+    pub attachment: serenity::Attachment,
+    pub bytes: Vec<u8>,
+}
 
-    //let bug_message = Message::default();
-    let requester_id = ctx.author().id;
-    let channel_id = ctx.channel_id();
+/*
+    The images a generation starts from, a mask needs an image
+*/
+#[derive(Default)]
+pub struct InputImages {
+    pub image: Option<InputImage>,
+    pub mask: Option<InputImage>,
+    // How much the image is changed, Stable Diffusion only
+    pub prompt_strength: Option<f32>,
+}
 
-    let current_exe = match env::current_exe()
-        {
-            Ok(t) => t,
-            Err(e) => return_error(requester_id.clone(), channel_id.clone(), e.to_string()).await.unwrap(),
-        };
-    let current_path = match current_exe.parent() 
-        {
-            Some(t) => t,
-            None => return_error(requester_id.clone(), channel_id.clone(), "Unable to process current function string".to_owned()).await.unwrap(),
-        };
-    let assets_location = current_path.join("assets").join("functions.json");
-    let function_json_string = match fs::read_to_string(assets_location)
-        {
-            Ok(t) => t,
-            Err(e) => return_error(requester_id.clone(), channel_id.clone(), e.to_string()).await.unwrap(),
-        };
-    let function_object: JsonObject = match serde_json::from_str(&function_json_string)
-        {
-            Ok(t) => t,
-            Err(e) => return_error(requester_id.clone(), channel_id.clone(), e.to_string()).await.unwrap(),
-        };
-    let function_data = function_object.function_data;
+/*
+    The settings of a generation, these are saved with the images so the buttons under them can make more
+    Only the model and prompt are used by DALL-E, a DALL-E prompt of None makes variations of the input image
+*/
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+pub struct ImageSettings {
+    pub function_command: String,
+    pub prompt: Option<String>,
+    pub negative_prompt: String,
+    pub width_ratio: f32,
+    pub height_ratio: f32,
+    pub guidance_scale: f32,
+    pub steps: u32,
+    pub scheduler: String,
+    pub image_count: u32,
+    pub seed: u64,
+}
 
-    let mut model_options: Vec<CreateSelectMenuOption> = Vec::new();
-    
-    for command_object in function_data.clone().into_iter() {
-        model_options.push(CreateSelectMenuOption::new(command_object.function_friendly_name, command_object.function_command));
-    };
+/*
+    The arguments of a direct generation, these are the slash command's options and the name:value pairs of the prefix command
+*/
+#[derive(Default, Debug, PartialEq)]
+struct ImageArguments {
+    model: Option<String>,
+    prompt: Option<String>,
+    negative: Option<String>,
+    aspect: Option<String>,
+    guidance: Option<f32>,
+    prompt_strength: Option<f32>,
+    seed: Option<u64>,
+    steps: Option<u32>,
+    scheduler: Option<String>,
+    count: Option<u32>,
+}
+
+/*
+    Where a generation was asked for, the requester and the progress message come from here
+        - Command - /imagegen or !delta imagegen with a model
+        - Component - The model dropdown or a button under earlier images, the interaction must already have been responded to
+*/
+#[derive(Clone, Copy)]
+pub enum GenerationSource<'a> {
+    Command(crate::Context<'a>),
+    Component(&'a serenity::Context, &'a ComponentInteraction),
+}
+
+impl GenerationSource<'_> {
+    fn serenity_context(&self) -> &serenity::Context {
+        match self {
+            GenerationSource::Command(ctx) => ctx.serenity_context(),
+            GenerationSource::Component(ctx, _) => ctx,
+        }
+    }
 
-    let reply = {
-        let components: Vec<serenity::CreateActionRow> = vec![poise::serenity_prelude::CreateActionRow::SelectMenu(serenity::CreateSelectMenu::new("model_select",
-            serenity::builder::CreateSelectMenuKind::String { options: model_options }
-    ))];
+    fn channel_id(&self) -> ChannelId {
+        match self {
+            GenerationSource::Command(ctx) => ctx.channel_id(),
+            GenerationSource::Component(_, interaction) => interaction.channel_id,
+        }
+    }
+
+    fn requester_id(&self) -> UserId {
+        match self {
+            GenerationSource::Command(ctx) => ctx.author().id,
+            GenerationSource::Component(_, interaction) => interaction.user.id,
+        }
+    }
+}
+
+// Runpod limits request bodies to 10 MB and DALL-E limits each image to 4 MB, two images in base64 fit under both
+const MAX_INPUT_IMAGE_BYTES: u32 = 3 * 1024 * 1024;
+const DEFAULT_PROMPT_STRENGTH: f32 = 0.8;
+const DEFAULT_GUIDANCE_SCALE: f32 = 7.5;
+// Stable Diffusion images are burnt out well before this, it stops typos such as 75 from wasting a generation
+const MAX_GUIDANCE_SCALE: f32 = 30.0;
+// The widest (and tallest) aspect ratio an image is made at, Stable Diffusion falls apart well before this
+const MAX_ASPECT_RATIO: f32 = 4.0;
+// The aspect ratios offered for the aspect option by name, label, width and height, any other ratio can still be typed in
+const ASPECT_PRESETS: [(&str, &str, f32, f32); 5] = [
+    ("square", "Square (1:1)", 1.0, 1.0),
+    ("portrait", "Portrait (2:3)", 2.0, 3.0),
+    ("landscape", "Landscape (3:2)", 3.0, 2.0),
+    ("16:9", "Widescreen (16:9)", 16.0, 9.0),
+    ("21:9", "Ultrawide (21:9)", 21.0, 9.0),
+];
+// How long the model dropdown waits for a choice
+const MODEL_SELECT_TIMEOUT: Duration = Duration::from_secs(120);
 
-        poise::CreateReply::default()
+/*
+    The imagegen command, the slash command and the prefix command read their arguments differently so they are separate functions
+    poise finds prefix commands by name, so the prefix command is made part of the slash command instead of being registered with the same name
+*/
+pub fn imagegen_command() -> poise::Command<Data, Error> {
+    let mut command = imagegen();
+    command.prefix_action = imagegen_prefix().prefix_action;
+    command
+}
+
+/*
+    With a model the images are generated straight from the options, otherwise the model is picked from a dropdown and the rest is
+    filled in with a modal (the options given are used as its defaults)
+*/
+// Every argument is a slash command option
+#[allow(clippy::too_many_arguments)]
+#[poise::command(slash_command)]
+pub async fn imagegen(
+    ctx: crate::Context<'_>,
+    #[description = "The model to use, leave this out to pick one from a list"]
+    #[autocomplete = "autocomplete_model"]
+    model: Option<String>,
+    #[description = "What to make, DALL-E variations don't need a prompt"]
+    prompt: Option<String>,
+    #[description = "What you don't want in the images, Stable Diffusion only"]
+    negative: Option<String>,
+    #[description = "A preset or ratio like 4:3, fitted to the model's sizes (Default: square or the image's), SD only"]
+    #[autocomplete = "autocomplete_aspect"]
+    aspect: Option<String>,
+    #[description = "An image to start from (img2img), DALL-E makes variations of it"]
+    image: Option<serenity::Attachment>,
+    #[description = "The areas to redraw (inpainting), white for Stable Diffusion and transparent for DALL-E"]
+    mask: Option<serenity::Attachment>,
+    #[description = "How much the image is changed from 0 to 1 (Default: 0.8), Stable Diffusion only"]
+    #[min = 0]
+    #[max = 1]
+    prompt_strength: Option<f32>,
+    #[description = "The seed to generate from, the same seed and input makes the same images (Default: random)"]
+    #[max = 4294967295_u64]
+    seed: Option<u64>,
+    #[description = "How many denoising steps to run, more is slower but can add detail (Default: set per model)"]
+    #[min = 1]
+    steps: Option<u32>,
+    #[description = "The sampler used for each step (Default: set per model)"]
+    #[autocomplete = "autocomplete_scheduler"]
+    scheduler: Option<String>,
+    #[description = "How many images to make (Default: set per model)"]
+    #[min = 1]
+    count: Option<u32>,
+    #[description = "How closely the images follow the prompt from 0 to 30 (Default: 7.5), Stable Diffusion only"]
+    #[min = 0]
+    #[max = 30]
+    guidance: Option<f32>
+) -> Result<(), Error> {
+    let arguments = ImageArguments { model, prompt, negative, aspect, guidance, prompt_strength, seed, steps, scheduler, count };
+    // Downloading the input images can take longer than Discord waits for a response
+    ctx.defer_ephemeral().await?;
+    let input_images = download_input_images(image, mask, prompt_strength).await?;
+    if arguments.model.is_some() {
+        return generate_from_arguments(ctx, &arguments, &input_images).await;
+    }
+
+    let function_data = load_function_data()?;
+    let quota_user = QuotaUser::from_context(ctx).await;
+
+    // The ID is unique to this command so other uses of /imagegen running at the same time don't see this dropdown
+    let model_select_id = format!("{}_model_select", ctx.id());
+    let reply_handle = ctx.send(
+        CreateReply::default()
             .content("Please select which model to use")
             .ephemeral(true)
-            .components(components)
+            .components(model_select_menu(&model_select_id, &function_data, None, false))
+    ).await?;
+    let dropdown_message_id = reply_handle.message().await?.id;
+
+    let author_id = ctx.author().id;
+    let model_select = serenity::ComponentInteractionCollector::new(ctx.serenity_context())
+        .timeout(MODEL_SELECT_TIMEOUT)
+        .author_id(author_id)
+        .message_id(dropdown_message_id)
+        .custom_ids(vec![model_select_id.clone()])
+        .await;
+    let Some(mci) = model_select else {
+        reply_handle.edit(
+            ctx,
+            CreateReply::default()
+                .content("No model was selected in time, use /imagegen again to make an image")
+                .components(model_select_menu(&model_select_id, &function_data, None, true)))
+        .await?;
+        return Ok(());
+    };
+
+    let current_command = match &mci.data.kind {
+        ComponentInteractionDataKind::StringSelect { values } => values[0].clone(),
+        _ => return Err("An invalid response has been returned from the dropdown".into()),
     };
+    let current_function: FunctionData = function_data.iter()
+        .find(|function| function.function_command == current_command)
+        .cloned()
+        .ok_or("Unable to process current function string")?;
+    // Only one model can be picked for each use of the command, the dropdown shows which one it was
+    let disable_result = reply_handle.edit(
+        ctx,
+        CreateReply::default()
+            .content(format!("Using {}", current_function.function_friendly_name))
+            .components(model_select_menu(&model_select_id, &function_data, Some(&current_command), true))
+    ).await;
+    if let Err(e) = disable_result {
+        warn!("Unable to disable the model dropdown: {}", e);
+    }
+
+    let mut settings = settings_from_arguments(&current_function, &arguments, &input_images)?;
+
+    // DALL-E variations don't have a prompt so there is no modal to reply to the dropdown with
+    let prefill = arguments.prompt.is_some() || arguments.negative.is_some();
+    if current_function.function_type == "openai_dalle" && input_images.image.is_some() && input_images.mask.is_none() && arguments.prompt.is_none() {
+        mci.create_response(ctx, CreateInteractionResponse::Acknowledge).await?;
+    } else if !ask_for_settings(ctx.serenity_context(), &mci, &current_function, &mut settings, prefill).await? {
+        // The modal was closed or timed out
+        return Ok(());
+    }
+
+    run_generation(GenerationSource::Component(ctx.serenity_context(), &mci), ctx.data(), &quota_user, &current_function, settings, &input_images).await
+}
+
+/*
+    The dropdown of models, once a model is picked (or the time runs out) it is disabled with the chosen model selected
+*/
+fn model_select_menu(custom_id: &str, function_data: &[FunctionData], chosen_command: Option<&str>, disabled: bool) -> Vec<serenity::CreateActionRow> {
+    let model_options = function_data.iter()
+        .map(|t| CreateSelectMenuOption::new(&t.function_friendly_name, &t.function_command).default_selection(chosen_command == Some(t.function_command.as_str())))
+        .collect();
+    vec![serenity::CreateActionRow::SelectMenu(
+        serenity::CreateSelectMenu::new(custom_id, serenity::CreateSelectMenuKind::String { options: model_options })
+            .disabled(disabled)
+    )]
+}
 
-    let _sent_message = ctx.send(reply).await?;
-
-    while let Some(mci) = serenity::ComponentInteractionCollector::new(ctx.serenity_context())
-        .timeout(std::time::Duration::from_secs(120))
-        .filter(move |mci| mci.data.custom_id == "model_select")
-        .await
-    {
-        // Cannot get ctx to be used with typing, requires Arc<Http> when the ctx only returns &Http
-        let typing_cache = serenity::Http::new(&env::var("DISCORD_TOKEN").expect("Expected a token in the environment"));
-        let typing_cache_arc: Arc<serenity::Http> = Arc::new(typing_cache);
-        let typing: Typing;
-        let data_kind = mci.clone().data.kind;
-        let current_command = match data_kind {
-            ComponentInteractionDataKind::StringSelect { values } => {values[0].clone()},
-            _ => return_error(requester_id.clone(), channel_id.clone(), "An invalid response has been returned from the dropdown".to_owned()).await.unwrap()
+/*
+    !delta imagegen model:name prompt:"a cat on a mat" aspect:16:9 seed:42
+    The options of the slash command are written as name:value, quotes keep the spaces in a value and words without a name are added to the prompt
+    The first image attached to the message is the input image and the second is the mask
+*/
+#[poise::command(prefix_command, rename = "imagegen")]
+pub async fn imagegen_prefix(
+    ctx: crate::Context<'_>,
+    #[rest]
+    arguments: Option<String>
+) -> Result<(), Error> {
+    let arguments = parse_prefix_arguments(arguments.as_deref().unwrap_or_default())?;
+    if arguments.model.is_none() {
+        let model_names: Vec<String> = load_function_data()?.into_iter().map(|t| t.function_command).collect();
+        return Err(BotError::UserInput(format!(
+            "Choose a model with model:name, for example model:{} prompt:\"a cat on a mat\", the models are: {}",
+            model_names.first().map(String::as_str).unwrap_or("name"),
+            model_names.join(", ")
+        )));
+    }
+    let mut attachments = match ctx {
+        poise::Context::Prefix(prefix_ctx) => prefix_ctx.msg.attachments.clone(),
+        poise::Context::Application(_) => Vec::new(),
+    }.into_iter();
+    let input_images = download_input_images(attachments.next(), attachments.next(), arguments.prompt_strength).await?;
+    generate_from_arguments(ctx, &arguments, &input_images).await
+}
+
+/*
+    Reads the name:value arguments of the prefix command
+    Only the first colon splits the name from the value so aspect:16:9 works, words with a name that isn't an option are part of the prompt
+*/
+fn parse_prefix_arguments(arguments: &str) -> Result<ImageArguments, Error> {
+    let words = shell_words::split(arguments)
+        .map_err(|e| BotError::UserInput(format!("Unable to read the arguments, check that every quote is closed: {}", e)))?;
+    let mut image_arguments = ImageArguments::default();
+    let mut prompt_words: Vec<String> = Vec::new();
+    for word in words {
+        let Some((name, value)) = word.split_once(':') else {
+            prompt_words.push(word);
+            continue;
         };
-    
-        let current_function: FunctionData = match function_data.clone().into_iter()
-            .filter(|function| function.function_command == current_command).next()
-            {
-                Some(t) => t,
-                None => return_error(requester_id.clone(), channel_id.clone(), "Unable to process current function string".to_owned()).await.unwrap(),
-            };
-        let command_function_type = current_function.function_type.as_str();
-        let command_api_str = current_function.function_api_key;
-        let command_data_prefix = current_function.prompt_prefix;
-        let command_data_suffix = current_function.prompt_suffix;
-        let image_attachments: Vec<CreateAttachment>;
-        let mut embed_set: Vec<CreateEmbed> = Vec::new();
-        let prompt: String;
-
-        match command_function_type {
-            "runpod_image" => {
-                typing = Typing::start(typing_cache_arc, channel_id.into());
-                let data =
-                    poise::execute_modal_on_component_interaction::<ServerlessModal>(ctx, mci.clone(), None, None).await?;
-                let data_unwrapped = data.unwrap();
-                let width_ratio: f32 = match data_unwrapped.width_ratio.unwrap_or("1".to_string()).parse()
-                {
-                    Ok(t) => t,
-                    Err(_) => return_error(requester_id.clone(), channel_id.clone(), "Non number entered into width ratio field".to_owned()).await.unwrap(),
-                };
-                let height_ratio: f32 = match data_unwrapped.height_ratio.unwrap_or("1".to_string()).parse()
-                {
-                    Ok(t) => t,
-                    Err(_) => return_error(requester_id.clone(), channel_id.clone(), "Non number entered into height ratio field".to_owned()).await.unwrap(),
-                };
-                prompt = data_unwrapped.prompt;
-                let neg_prompt: String = data_unwrapped.neg_prompt.unwrap_or("".to_string());
-                let guide_scale: f32 = match data_unwrapped.guide_scale.unwrap_or("7.5".to_string()).parse()
-                {
-                    Ok(t) => t,
-                    Err(_) => return_error(requester_id.clone(), channel_id.clone(), "Non number entered into height ratio field".to_owned()).await.unwrap(),
-                };
-                // This is a fixed value from 1024*1024 (this being the default SDXL height and width)
-                let total_pixel_count: f32 = 1048576.0;
-                // Calculate the image size based on the aspect ratio and total number of pixels the model allows
-                // For example, Stable Diffusion XL supports 1024x1024 so the total pixesl would be the result of 1024*1024
-                let height: f32 = ((((total_pixel_count * (height_ratio / width_ratio)).sqrt()).round()as u32) + 7 & !7) as f32;
-                let width: f32 = ((((width_ratio / height_ratio) * height).round() as u32) + 7 & !7) as f32;
-                let full_prompt = format!("{}{}{}", command_data_prefix, prompt, command_data_suffix);
-                
-                image_attachments = generate_runpod_image(full_prompt, command_api_str, width, height, 2, neg_prompt.clone(), guide_scale, ctx).await;
-
-                // First image is pushed with the embed, this is because the content of the embed is dependent on the model selected
-                embed_set.push(
-                    CreateEmbed::new()
-                        .attachment(image_attachments[0].clone().filename)
-                        .url("https://runpod.io")
-                        .description(
-                            format!(
-                                "Congratulations <@{}>, your image has been generated with the following input\n\n> Model: {}\n> Prompt: {}\n> Neg prompt: {}\n> Width ratio: {} (Actual width: {})\n> Height ratio: {} (Actual height: {})\n> Guidance scale: {}", 
-                                requester_id,
-                                current_command,
-                                prompt,
-                                neg_prompt,
-                                width_ratio,
-                                width,
-                                height_ratio,
-                                height,
-                                guide_scale
-                            )
-                        )
-                );
+        let (name, value) = (name.to_lowercase(), value.to_owned());
+        let number_error = || BotError::UserInput(format!("{} must be a number", name));
+        let already_set = match name.as_str() {
+            "model" => image_arguments.model.replace(value).is_some(),
+            "prompt" => image_arguments.prompt.replace(value).is_some(),
+            "negative" => image_arguments.negative.replace(value).is_some(),
+            "aspect" => image_arguments.aspect.replace(value).is_some(),
+            "scheduler" => image_arguments.scheduler.replace(value).is_some(),
+            "guidance" => image_arguments.guidance.replace(value.parse().map_err(|_| number_error())?).is_some(),
+            "prompt_strength" => image_arguments.prompt_strength.replace(value.parse().map_err(|_| number_error())?).is_some(),
+            "seed" => image_arguments.seed.replace(value.parse().map_err(|_| number_error())?).is_some(),
+            "steps" => image_arguments.steps.replace(value.parse().map_err(|_| number_error())?).is_some(),
+            "count" => image_arguments.count.replace(value.parse().map_err(|_| number_error())?).is_some(),
+            _ => {
+                prompt_words.push(word.clone());
+                false
             },
-            "openai_dalle" => {
-                typing = Typing::start(typing_cache_arc, channel_id.into());
-                let data =
-                    poise::execute_modal_on_component_interaction::<DalleModal>(ctx, mci.clone(), None, None).await?;
-                let data_unwrapped = data.unwrap();
-                let prompt = data_unwrapped.prompt;
-                // No option for multiple generations at one time with DALL-E 3
-                image_attachments = generate_dalle(prompt.clone(), ctx).await;
-
-                embed_set.push(
-                    CreateEmbed::new()
-                        .attachment(image_attachments[0].clone().filename)
-                        .url("https://runpod.io")
-                        .description(
-                            format!(
-                                "Congratulations <@{}>, your image has been generated with the following input\n\n> Model: {}\n> Prompt: {}", 
-                                requester_id,
-                                current_command,
-                                prompt
-                            )
-                        )
-                );
-            }
-            _ => {panic!("Oh noes!");}
+        };
+        if already_set {
+            return Err(BotError::UserInput(format!("{} was given more than once", name)));
         }
-        
-        for image_attach in image_attachments.clone().into_iter().skip(1) {
-            embed_set.push(
-                CreateEmbed::new()
-                    .url("https://runpod.io")
-                    .attachment(image_attach.filename)
-            );
+    }
+    if !prompt_words.is_empty() {
+        let prompt = image_arguments.prompt.take().into_iter().chain(prompt_words).collect::<Vec<String>>().join(" ");
+        image_arguments.prompt = Some(prompt);
+    }
+    Ok(image_arguments)
+}
+
+/*
+    Generates images straight from the arguments, this is used by both commands when a model is given
+*/
+async fn generate_from_arguments(ctx: crate::Context<'_>, arguments: &ImageArguments, input_images: &InputImages) -> Result<(), Error> {
+    let function = find_model(&load_function_data()?, arguments.model.as_deref().unwrap_or_default())?;
+    let settings = settings_from_arguments(&function, arguments, input_images)?;
+    let is_dalle_variation = function.function_type == "openai_dalle" && input_images.image.is_some() && input_images.mask.is_none();
+    if settings.prompt.is_none() && !is_dalle_variation {
+        return Err(BotError::UserInput("A prompt is needed to generate images with this model".to_owned()));
+    }
+    let quota_user = QuotaUser::from_context(ctx).await;
+    run_generation(GenerationSource::Command(ctx), ctx.data(), &quota_user, &function, settings, input_images).await
+}
+
+/*
+    Finds a model by its command or friendly name, ignoring case
+*/
+fn find_model(function_data: &[FunctionData], model: &str) -> Result<FunctionData, Error> {
+    function_data.iter()
+        .find(|t| t.function_command.eq_ignore_ascii_case(model) || t.function_friendly_name.eq_ignore_ascii_case(model))
+        .cloned()
+        .ok_or_else(|| {
+            let model_names: Vec<&str> = function_data.iter().map(|t| t.function_command.as_str()).collect();
+            BotError::UserInput(format!("There is no model called {}, the models are: {}", model, model_names.join(", ")))
+        })
+}
+
+/*
+    The settings for a model from the arguments, the model's defaults are used for anything that was not given
+    The prompt may still be None, the interactive flow asks for it with the modal
+*/
+fn settings_from_arguments(function: &FunctionData, arguments: &ImageArguments, input_images: &InputImages) -> Result<ImageSettings, Error> {
+    if arguments.prompt_strength.is_some_and(|t| !(0.0..=1.0).contains(&t)) {
+        return Err(BotError::UserInput("The prompt strength must be from 0 to 1".to_owned()));
+    }
+    if let Some(guidance) = arguments.guidance {
+        check_guidance(guidance)?;
+    }
+    match function.function_type.as_str() {
+        "runpod_image" => {
+            let (steps, scheduler, image_count) = function.parameters.resolve(arguments.steps, arguments.scheduler.as_deref(), arguments.count)?;
+            // An init image keeps its own shape unless a ratio is given
+            let (width_ratio, height_ratio) = match &arguments.aspect {
+                Some(aspect) => parse_aspect_ratio(aspect)?,
+                None => input_images.image.as_ref()
+                    .and_then(|t| Some((t.attachment.width? as f32, t.attachment.height? as f32)))
+                    .unwrap_or((1.0, 1.0)),
+            };
+            Ok(ImageSettings {
+                function_command: function.function_command.clone(),
+                prompt: arguments.prompt.clone(),
+                negative_prompt: arguments.negative.clone().unwrap_or_default(),
+                width_ratio,
+                height_ratio,
+                guidance_scale: arguments.guidance.unwrap_or(DEFAULT_GUIDANCE_SCALE),
+                steps,
+                scheduler,
+                image_count,
+                // The seed is picked here when it isn't given so it can be shown with the images
+                seed: arguments.seed.unwrap_or_else(random_seed),
+            })
+        },
+        "openai_dalle" => {
+            let sd_only = [
+                arguments.negative.is_some(),
+                arguments.aspect.is_some(),
+                arguments.guidance.is_some(),
+                arguments.prompt_strength.is_some(),
+                arguments.seed.is_some(),
+                arguments.steps.is_some(),
+                arguments.scheduler.is_some(),
+                arguments.count.is_some(),
+            ];
+            if sd_only.contains(&true) {
+                return Err(BotError::UserInput("Negative prompts, aspect, guidance, prompt strength, seed, steps, scheduler and count can only be used with Stable Diffusion models".to_owned()));
+            }
+            let mut settings = dalle_settings(function.function_command.clone());
+            settings.prompt = arguments.prompt.clone();
+            Ok(settings)
+        },
+        function_type => Err(BotError::Config(format!("The image model type {} in functions.json is not supported", function_type))),
+    }
+}
+
+/*
+    Reads an aspect ratio, either one of the presets or a ratio such as 4:3 (or 4x3), into the width and height ratios
+*/
+fn parse_aspect_ratio(aspect: &str) -> Result<(f32, f32), Error> {
+    if let Some(&(_, _, width_ratio, height_ratio)) = ASPECT_PRESETS.iter().find(|t| t.0.eq_ignore_ascii_case(aspect.trim())) {
+        return Ok((width_ratio, height_ratio));
+    }
+    let aspect_error = || BotError::UserInput(format!("{} is not an aspect ratio, it should be a preset (square, portrait, landscape, 16:9 or 21:9) or written like 4:3", aspect));
+    let (width_ratio, height_ratio) = aspect.split_once([':', 'x']).ok_or_else(aspect_error)?;
+    let width_ratio: f32 = width_ratio.trim().parse().map_err(|_| aspect_error())?;
+    let height_ratio: f32 = height_ratio.trim().parse().map_err(|_| aspect_error())?;
+    check_aspect_ratio(width_ratio, height_ratio)?;
+    Ok((width_ratio, height_ratio))
+}
+
+/*
+    Checks a width and height ratio can be made into an image size, "inf" and "NaN" parse as numbers so they are checked for here too
+*/
+pub fn check_aspect_ratio(width_ratio: f32, height_ratio: f32) -> Result<(), Error> {
+    if !(width_ratio.is_finite() && height_ratio.is_finite()) {
+        return Err(BotError::UserInput("The width and height ratios must be numbers".to_owned()));
+    }
+    if width_ratio <= 0.0 || height_ratio <= 0.0 {
+        return Err(BotError::UserInput("The width and height ratios must be greater than 0".to_owned()));
+    }
+    Ok(())
+}
+
+/*
+    Checks a guidance scale is a number from 0 to the maximum, like the aspect ratios "inf" and "NaN" need to be checked for
+*/
+pub fn check_guidance(guidance_scale: f32) -> Result<(), Error> {
+    if !(0.0..=MAX_GUIDANCE_SCALE).contains(&guidance_scale) {
+        return Err(BotError::UserInput(format!("The guidance scale must be a number from 0 to {}", MAX_GUIDANCE_SCALE)));
+    }
+    Ok(())
+}
+
+async fn download_input_images(image: Option<serenity::Attachment>, mask: Option<serenity::Attachment>, prompt_strength: Option<f32>) -> Result<InputImages, Error> {
+    if image.is_none() && (mask.is_some() || prompt_strength.is_some()) {
+        return Err(BotError::UserInput("A mask or prompt strength needs an image to start from".to_owned()));
+    }
+    Ok(InputImages {
+        image: match image {
+            Some(t) => Some(download_input_image(t, "image").await?),
+            None => None,
+        },
+        mask: match mask {
+            Some(t) => Some(download_input_image(t, "mask").await?),
+            None => None,
+        },
+        prompt_strength,
+    })
+}
+
+pub fn dalle_settings(function_command: String) -> ImageSettings {
+    // No option for multiple generations at one time with DALL-E 3
+    ImageSettings {
+        function_command,
+        prompt: None,
+        negative_prompt: String::new(),
+        width_ratio: 1.0,
+        height_ratio: 1.0,
+        guidance_scale: 0.0,
+        steps: 0,
+        scheduler: String::new(),
+        image_count: 1,
+        seed: 0,
+    }
+}
+
+pub fn random_seed() -> u64 {
+    rand::random::<u32>() as u64
+}
+
+// poise's modals need AsRef<serenity::Context>, which a poise context has but the serenity context given to the event handler does not
+struct ModalContext<'a>(&'a serenity::Context);
+
+impl AsRef<serenity::Context> for ModalContext<'_> {
+    fn as_ref(&self) -> &serenity::Context {
+        self.0
+    }
+}
+
+/*
+    Shows the model's modal as the response to the interaction and updates the settings with what was entered
+    Empty fields keep the current settings, with prefill the modal starts with the current settings so they can be edited
+    Returns false if the modal was closed or timed out
+*/
+pub async fn ask_for_settings(
+    ctx: &serenity::Context,
+    interaction: &ComponentInteraction,
+    function: &FunctionData,
+    settings: &mut ImageSettings,
+    prefill: bool
+) -> Result<bool, Error> {
+    if function.function_type == "openai_dalle" {
+        let defaults = prefill.then(|| DalleModal { prompt: settings.prompt.clone().unwrap_or_default() });
+        let Some(data_unwrapped) = poise::execute_modal_on_component_interaction::<DalleModal>(ModalContext(ctx), interaction.clone(), defaults, None).await? else {
+            return Ok(false);
         };
+        settings.prompt = Some(data_unwrapped.prompt);
+        return Ok(true);
+    }
 
-        let message_builder = CreateMessage::new()
-            .allowed_mentions(CreateAllowedMentions::new().users(vec![requester_id]))
-            .content(format!("<@{}>", requester_id))
-            .files(image_attachments)
-            .add_embeds(embed_set);
-        
-        match channel_id.send_message(ctx, message_builder).await
-            {
-                Ok(t) => t,
-                Err(e) => return_error(requester_id.clone(), channel_id.clone(), e.to_string()).await.unwrap(),
+    let defaults = prefill.then(|| ServerlessModal {
+        prompt: settings.prompt.clone().unwrap_or_default(),
+        neg_prompt: Some(settings.negative_prompt.clone()).filter(|t| !t.is_empty()),
+        width_ratio: Some(settings.width_ratio.to_string()),
+        height_ratio: Some(settings.height_ratio.to_string()),
+        guide_scale: Some(settings.guidance_scale.to_string()),
+    });
+    let Some(data_unwrapped) = poise::execute_modal_on_component_interaction::<ServerlessModal>(ModalContext(ctx), interaction.clone(), defaults, None).await? else {
+        return Ok(false);
+    };
+    if let Some(t) = data_unwrapped.width_ratio {
+        settings.width_ratio = t.parse().map_err(|_| BotError::UserInput("Non number entered into width ratio field".to_owned()))?;
+    }
+    if let Some(t) = data_unwrapped.height_ratio {
+        settings.height_ratio = t.parse().map_err(|_| BotError::UserInput("Non number entered into height ratio field".to_owned()))?;
+    }
+    if let Some(t) = data_unwrapped.guide_scale {
+        settings.guidance_scale = t.parse().map_err(|_| BotError::UserInput("Non number entered into guidance scale field".to_owned()))?;
+    }
+    check_aspect_ratio(settings.width_ratio, settings.height_ratio)?;
+    check_guidance(settings.guidance_scale)?;
+    settings.prompt = Some(data_unwrapped.prompt);
+    settings.negative_prompt = data_unwrapped.neg_prompt.unwrap_or_default();
+    Ok(true)
+}
+
+/*
+    Makes the images for a command or an interaction (the model dropdown or a button under earlier images) and posts them with buttons to make more
+    The quota is charged to the user who asked for them
+    While the images are made they are sent a message only they can see, with a button to cancel Runpod jobs
+*/
+pub async fn run_generation(
+    source: GenerationSource<'_>,
+    data: &Data,
+    quota_user: &QuotaUser,
+    function: &FunctionData,
+    mut settings: ImageSettings,
+    input_images: &InputImages
+) -> Result<(), Error> {
+    // Quotas are checked once the modal is filled in, Discord needs the modal as the reply to the interaction
+    let quota_limits = start_request(data, quota_user)?;
+    check_daily_quota(data, quota_user, &quota_limits, QuotaKind::Images, settings.image_count as u64)?;
+
+    let typing = Typing::start(source.serenity_context().http.clone(), source.channel_id());
+    // The model's prefix and suffix are used with every backend, DALL-E variations have no prompt to add them to
+    let full_prompt = settings.prompt.as_ref().map(|t| format!("{}{}{}", function.prompt_prefix, t, function.prompt_suffix));
+    let generation_started = Instant::now();
+    let generation_result = match function.function_type.as_str() {
+        "runpod_image" => {
+            let (width, height) = function.parameters.image_size(settings.width_ratio, settings.height_ratio);
+            let run_input = ImageGenRunInput {
+                prompt: full_prompt.unwrap_or_default(),
+                negative_prompt: settings.negative_prompt.clone(),
+                width,
+                height,
+                guidance_scale: settings.guidance_scale,
+                num_inference_steps: settings.steps,
+                num_images: settings.image_count,
+                scheduler: settings.scheduler.clone(),
+                seed: Some(settings.seed),
+                init_image: input_images.image.as_ref().map(|t| BASE64_STANDARD.encode(&t.bytes)),
+                mask: input_images.mask.as_ref().map(|t| BASE64_STANDARD.encode(&t.bytes)),
+                prompt_strength: input_images.image.as_ref().map(|_| input_images.prompt_strength.unwrap_or(DEFAULT_PROMPT_STRENGTH)),
             };
+            with_progress_message(source, "Generating your images, this can take a minute or two", true, |cancelled| {
+                generate_runpod_image(&data.runpod, &function.function_api_key, run_input, function.use_runsync, cancelled)
+            }).await
+        },
+        "openai_dalle" => {
+            let openai_config = data.config.openai_client_config();
+            // OpenAI requests can't be cancelled
+            with_progress_message(source, "Generating your image, this can take a minute", false, |_| async {
+                match (&input_images.image, full_prompt) {
+                    (Some(init_image), Some(prompt)) => edit_dalle_image(prompt, init_image, input_images.mask.as_ref(), openai_config).await,
+                    (Some(init_image), None) => generate_dalle_variation(init_image, openai_config).await,
+                    (None, Some(prompt)) => generate_dalle(prompt, openai_config).await,
+                    (None, None) => Err("DALL-E needs a prompt or an image".into()),
+                }.map(|t| (t, None))
+            }).await
+        },
+        function_type => Err(BotError::Config(format!("The image model type {} in functions.json is not supported", function_type))),
+    };
+    if !matches!(generation_result, Err(BotError::Cancelled)) {
+        metrics().image_generations.observe(&[&function.function_command], generation_started, generation_result.is_ok());
+    }
+    let (image_attachments, used_seed) = match generation_result {
+        Ok(t) => t,
+        Err(e) => {
+            typing.stop();
+            return match e {
+                BotError::Cancelled => Ok(()),
+                e => Err(e),
+            };
+        },
+    };
+    if image_attachments.is_empty() {
         typing.stop();
-        
+        return Err("No images were generated".into());
+    }
+    if let Some(used_seed) = used_seed {
+        settings.seed = used_seed;
+    }
+
+    record_daily_usage(data, quota_user, QuotaKind::Images, image_attachments.len() as u64);
+    record_image_usage(data, quota_user, &function.function_command, image_attachments.len() as u64);
+
+    let requester_id = source.requester_id();
+    let generation_id = save_image_generation(
+        &data.database,
+        quota_user.guild_id,
+        source.channel_id().get(),
+        requester_id.get(),
+        &settings,
+        Timestamp::now().unix_timestamp()
+    )?;
+    // Regenerating needs the input images, which are not saved, so only the buttons that use the new images are shown
+    let components = image_buttons(generation_id, image_attachments.len(), input_images.image.is_none(), &data.config.runpod);
+
+    // First image is pushed with the embed, this is because the content of the embed is dependent on the model selected
+    let mut embed_set: Vec<CreateEmbed> = vec![
+        CreateEmbed::new()
+            .attachment(image_attachments[0].clone().filename)
+            .url("https://runpod.io")
+            .description(describe_generation(requester_id, function, &settings, input_images))
+    ];
+    for image_attach in image_attachments.clone().into_iter().skip(1) {
+        embed_set.push(
+            CreateEmbed::new()
+                .url("https://runpod.io")
+                .attachment(image_attach.filename)
+        );
+    };
+
+    let message_builder = CreateMessage::new()
+        .allowed_mentions(CreateAllowedMentions::new().users(vec![requester_id]))
+        .content(format!("<@{}>", requester_id))
+        .files(image_attachments)
+        .add_embeds(embed_set)
+        .components(components);
+    
+    let send_result = source.channel_id().send_message(source.serenity_context(), message_builder).await;
+    typing.stop();
+    send_result?;
+    Ok(())
+}
+
+fn describe_generation(requester_id: UserId, function: &FunctionData, settings: &ImageSettings, input_images: &InputImages) -> String {
+    let mut input_description = format!(
+        "Congratulations <@{}>, your image has been generated with the following input\n\n> Model: {}",
+        requester_id,
+        settings.function_command
+    );
+    if function.function_type == "openai_dalle" {
+        match (&input_images.image, &settings.prompt) {
+            (Some(init_image), None) => input_description.push_str(&format!("\n> Variation of: {}", init_image.attachment.filename)),
+            (_, prompt) => input_description.push_str(&format!("\n> Prompt: {}", prompt.as_deref().unwrap_or_default())),
+        }
+    } else {
+        let (width, height) = function.parameters.image_size(settings.width_ratio, settings.height_ratio);
+        input_description.push_str(&format!(
+            "\n> Prompt: {}\n> Neg prompt: {}\n> Width ratio: {} (Actual width: {})\n> Height ratio: {} (Actual height: {})\n> Guidance scale: {}\n> Steps: {}\n> Scheduler: {}\n> Seed: {}",
+            settings.prompt.as_deref().unwrap_or_default(),
+            settings.negative_prompt,
+            settings.width_ratio,
+            width,
+            settings.height_ratio,
+            height,
+            settings.guidance_scale,
+            settings.steps,
+            settings.scheduler,
+            settings.seed
+        ));
+    }
+    // DALL-E variations already name the image
+    if let Some(init_image) = &input_images.image {
+        if function.function_type == "runpod_image" {
+            let prompt_strength = input_images.prompt_strength.unwrap_or(DEFAULT_PROMPT_STRENGTH);
+            input_description.push_str(&format!("\n> Image: {} (Prompt strength: {})", init_image.attachment.filename, prompt_strength));
+        } else if settings.prompt.is_some() {
+            input_description.push_str(&format!("\n> Image: {}", init_image.attachment.filename));
+        }
+    }
+    if let Some(mask_image) = &input_images.mask {
+        input_description.push_str(&format!("\n> Mask: {}", mask_image.attachment.filename));
+    }
+    input_description
+}
+
+// The message sent by with_progress_message, commands reply to themselves and interactions send a followup
+enum ProgressMessage<'a> {
+    Reply(crate::Context<'a>, poise::ReplyHandle<'a>),
+    Followup(&'a serenity::Context, &'a ComponentInteraction, MessageId),
+}
+
+/*
+    Runs a job while the requester has a message (only they can see) saying it is running, cancellable jobs have a button to cancel them
+    The message is removed once the job finishes, or changed to say it was cancelled
+    Messages can't be hidden from the channel for prefix commands, only the requester can press the button
+*/
+pub async fn with_progress_message<'a, T, F: Future<Output = Result<T, Error>>>(
+    source: GenerationSource<'a>,
+    progress_text: &str,
+    cancellable: bool,
+    job: impl FnOnce(BoxFuture<'static, ()>) -> F
+) -> Result<T, Error> {
+    let cancel_button_id = match source {
+        GenerationSource::Command(ctx) => format!("{}_cancel", ctx.id()),
+        GenerationSource::Component(_, interaction) => format!("{}_cancel", interaction.id),
+    };
+    let components = match cancellable {
+        true => vec![serenity::CreateActionRow::Buttons(vec![
+            CreateButton::new(cancel_button_id.clone()).label("Cancel").style(ButtonStyle::Danger)
+        ])],
+        false => Vec::new(),
+    };
+    let progress_message = match source {
+        GenerationSource::Command(ctx) => ProgressMessage::Reply(
+            ctx,
+            ctx.send(CreateReply::default().content(progress_text).ephemeral(true).components(components)).await?
+        ),
+        GenerationSource::Component(ctx, interaction) => ProgressMessage::Followup(
+            ctx,
+            interaction,
+            interaction.create_followup(ctx, CreateInteractionResponseFollowup::new().content(progress_text).ephemeral(true).components(components)).await?.id
+        ),
+    };
+
+    let cancelled: BoxFuture<'static, ()> = match cancellable {
+        true => Box::pin(wait_for_cancel(source.serenity_context().clone(), source.requester_id(), cancel_button_id)),
+        false => Box::pin(pending()),
+    };
+    let job_result = job(cancelled).await;
+    let cancelled_text = "The image generation has been cancelled";
+    let was_cancelled = matches!(job_result, Err(BotError::Cancelled));
+    let progress_result = match progress_message {
+        ProgressMessage::Reply(ctx, reply_handle) if was_cancelled => {
+            reply_handle.edit(ctx, CreateReply::default().content(cancelled_text).components(Vec::new())).await
+        },
+        ProgressMessage::Reply(ctx, reply_handle) => reply_handle.delete(ctx).await,
+        ProgressMessage::Followup(ctx, interaction, message_id) if was_cancelled => interaction.edit_followup(
+            ctx,
+            message_id,
+            CreateInteractionResponseFollowup::new().content(cancelled_text).components(Vec::new())
+        ).await.map(|_| ()),
+        ProgressMessage::Followup(ctx, interaction, message_id) => interaction.delete_followup(ctx, message_id).await,
+    };
+    if let Err(e) = progress_result {
+        warn!("Unable to update the image generation progress message: {}", e);
+    }
+    job_result
+}
+
+/*
+    Finishes when the requester presses the cancel button with this ID, the Runpod client cancels the job when it does
+*/
+async fn wait_for_cancel(ctx: serenity::Context, requester_id: UserId, cancel_button_id: String) {
+    let cancel_press = serenity::ComponentInteractionCollector::new(&ctx)
+        .filter(move |mci| mci.data.custom_id == cancel_button_id && mci.user.id == requester_id)
+        .await;
+    match cancel_press {
+        Some(mci) => {
+            if let Err(e) = mci.create_response(&ctx, CreateInteractionResponse::Acknowledge).await {
+                warn!("Unable to acknowledge the cancel button: {}", e);
+            }
+        },
+        // The collector only stops without a press when the bot is shutting down, the job is left to finish
+        None => pending().await,
+    }
+}
+
+/*
+    Downloads an image to use as an input, name is what it is used as (image or mask)
+*/
+pub async fn download_input_image(attachment: serenity::Attachment, name: &str) -> Result<InputImage, Error> {
+    if !attachment.content_type.as_deref().is_some_and(|t| t.starts_with("image/")) {
+        return Err(BotError::UserInput(format!("The {} must be an image", name)));
+    }
+    if attachment.size > MAX_INPUT_IMAGE_BYTES {
+        return Err(BotError::UserInput(format!("The {} must be smaller than {} MB", name, MAX_INPUT_IMAGE_BYTES / 1024 / 1024)));
+    }
+    let bytes = attachment.download().await?;
+    Ok(InputImage { attachment, bytes })
+}
+
+// DALL-E only edits square PNG images
+fn check_dalle_input_image(input_image: &InputImage) -> Result<(), Error> {
+    if input_image.attachment.content_type.as_deref() != Some("image/png") {
+        return Err(BotError::UserInput(format!("DALL-E can only use PNG images, {} is not a PNG", input_image.attachment.filename)));
+    }
+    if input_image.attachment.width != input_image.attachment.height {
+        return Err(BotError::UserInput(format!("DALL-E can only use square images, {} is not square", input_image.attachment.filename)));
     }
     Ok(())
 }
 
+// The models in functions.json, shown by their friendly names
+async fn autocomplete_model(_ctx: crate::Context<'_>, partial: &str) -> Vec<AutocompleteChoice> {
+    let partial = partial.to_lowercase();
+    load_function_data().unwrap_or_default().into_iter()
+        .filter(|t| t.function_command.to_lowercase().contains(&partial) || t.function_friendly_name.to_lowercase().contains(&partial))
+        .map(|t| AutocompleteChoice::new(t.function_friendly_name, t.function_command))
+        .collect()
+}
+
+// The aspect presets, with what has been typed so far if it isn't one so other ratios can be used
+async fn autocomplete_aspect(_ctx: crate::Context<'_>, partial: &str) -> Vec<AutocompleteChoice> {
+    let partial = partial.trim().to_lowercase();
+    let mut choices: Vec<AutocompleteChoice> = ASPECT_PRESETS.iter()
+        .filter(|t| t.0.contains(&partial) || t.1.to_lowercase().contains(&partial))
+        .map(|t| AutocompleteChoice::new(t.1, t.0))
+        .collect();
+    if !partial.is_empty() && !ASPECT_PRESETS.iter().any(|t| t.0 == partial) {
+        choices.push(AutocompleteChoice::new(partial.clone(), partial));
+    }
+    choices
+}
+
+// The schedulers of every Stable Diffusion model, the model isn't known until after the command is sent
+async fn autocomplete_scheduler(_ctx: crate::Context<'_>, partial: &str) -> Vec<String> {
+    let mut schedulers: Vec<String> = load_function_data().unwrap_or_default().into_iter()
+        .filter(|t| t.function_type == "runpod_image")
+        .flat_map(|t| t.parameters.schedulers)
+        .filter(|t| t.to_lowercase().starts_with(&partial.to_lowercase()))
+        .collect();
+    schedulers.sort();
+    schedulers.dedup();
+    schedulers
+}
+
+/*
+    Reads the image models from assets/functions.json next to the executable
+    These are used by the imagegen command and the generate_image tool in text generation
+*/
+pub fn load_function_data() -> Result<Vec<FunctionData>, Error> {
+    let current_exe = env::current_exe()?;
+    let current_path = current_exe.parent().ok_or("Unable to process current function string")?;
+    let assets_location = current_path.join("assets").join("functions.json");
+    let function_json_string = fs::read_to_string(assets_location)?;
+    let function_object: JsonObject = serde_json::from_str(&function_json_string)?;
+    let problems: Vec<String> = function_object.function_data.iter()
+        .flat_map(|t| t.parameters.problems(&t.function_command))
+        .collect();
+    if !problems.is_empty() {
+        return Err(BotError::Config(format!("assets/functions.json has problems: {}", problems.join(", "))));
+    }
+    Ok(function_object.function_data)
+}
+
 /*
     This generates images using DALL-E
     It uses the openai-async library for making calls
 */
-async fn generate_dalle (prompt_text: String, ctx: crate::Context<'_>) -> Vec<CreateAttachment> {
-    let client = Client::new();
-    let requester_id = ctx.author().id;
-    let channel_id = ctx.channel_id();
-    let request = match CreateImageRequestArgs::default()
+#[instrument(skip_all, fields(model = "dall-e-3"))]
+pub async fn generate_dalle (prompt_text: String, openai_config: OpenAIConfig) -> Result<Vec<CreateAttachment>, Error> {
+    let client = Client::with_config(openai_config);
+    let request = CreateImageRequestArgs::default()
         .prompt(prompt_text)
         .n(1)
         .response_format(ResponseFormat::B64Json)
@@ -270,17 +1080,61 @@ async fn generate_dalle (prompt_text: String, ctx: crate::Context<'_>) -> Vec<Cr
         .model(ImageModel::DallE3)
         .quality(ImageQuality::HD)
         .style(ImageStyle::Vivid)
-        .build()
-        {
-            Ok(t) => t,
-            Err(e) => return_error(requester_id.clone(), channel_id.clone(), e.to_string()).await.unwrap(),
-        };
+        .build()?;
 
-    let response = match client.images().create(request).await
-        {
-            Ok(t) => t,
-            Err(e) => return_error(requester_id.clone(), channel_id.clone(), e.to_string()).await.unwrap(),
-        };
+    let response = client.images().create(request).await?;
+    dalle_attachments(response)
+}
+
+/*
+    This edits the areas of an image that are transparent in the mask (or in the image if there is no mask) using DALL-E 2
+    DALL-E 3 can't edit images
+*/
+#[instrument(skip_all, fields(model = "dall-e-2"))]
+async fn edit_dalle_image(prompt_text: String, init_image: &InputImage, mask_image: Option<&InputImage>, openai_config: OpenAIConfig) -> Result<Vec<CreateAttachment>, Error> {
+    check_dalle_input_image(init_image)?;
+    if let Some(mask_image) = mask_image {
+        check_dalle_input_image(mask_image)?;
+    }
+    let client = Client::with_config(openai_config);
+    let mut request = CreateImageEditRequestArgs::default();
+    request
+        .image(ImageInput::from_vec_u8(init_image.attachment.filename.clone(), init_image.bytes.clone()))
+        .prompt(prompt_text)
+        .n(1)
+        .response_format(ResponseFormat::B64Json)
+        .size(DallE2ImageSize::S1024x1024)
+        .user("Delta-Bot")
+        .model(ImageModel::DallE2);
+    if let Some(mask_image) = mask_image {
+        request.mask(ImageInput::from_vec_u8(mask_image.attachment.filename.clone(), mask_image.bytes.clone()));
+    }
+
+    let response = client.images().create_edit(request.build()?).await?;
+    dalle_attachments(response)
+}
+
+/*
+    This makes a variation of an image using DALL-E 2, variations do not have a prompt
+*/
+#[instrument(skip_all, fields(model = "dall-e-2"))]
+async fn generate_dalle_variation(init_image: &InputImage, openai_config: OpenAIConfig) -> Result<Vec<CreateAttachment>, Error> {
+    check_dalle_input_image(init_image)?;
+    let client = Client::with_config(openai_config);
+    let request = CreateImageVariationRequestArgs::default()
+        .image(ImageInput::from_vec_u8(init_image.attachment.filename.clone(), init_image.bytes.clone()))
+        .n(1)
+        .response_format(ResponseFormat::B64Json)
+        .size(DallE2ImageSize::S1024x1024)
+        .user("Delta-Bot")
+        .model(ImageModel::DallE2)
+        .build()?;
+
+    let response = client.images().create_variation(request).await?;
+    dalle_attachments(response)
+}
+
+fn dalle_attachments(response: ImagesResponse) -> Result<Vec<CreateAttachment>, Error> {
     let mut image_attachments: Vec<CreateAttachment> = Vec::default();
 
     /*
@@ -288,124 +1142,42 @@ async fn generate_dalle (prompt_text: String, ctx: crate::Context<'_>) -> Vec<Cr
         This is then set as an attachment for a Discord message
     */
     for (index, image_data) in response.data.iter().enumerate() {
-        let mut image_data_base_64 = "".to_owned();
-
-        match &**image_data {
-            Image::B64Json {b64_json, revised_prompt: _} => {
-                image_data_base_64 = b64_json.as_str().to_owned();
-            },
-            Image::Url {..} => {
-                return_error(requester_id.clone(), channel_id.clone(), "Expected Base64 from DALL-E, got a different output instead".to_owned()).await.unwrap()
-            }
-        }
+        let image_data_base_64 = match &**image_data {
+            Image::B64Json {b64_json, revised_prompt: _} => b64_json.as_str().to_owned(),
+            Image::Url {..} => return Err("Expected Base64 from DALL-E, got a different output instead".into()),
+        };
         let base64_image_cleaned = image_data_base_64.replace("data:image/png;base64,", "");
         match BASE64_STANDARD.decode(&base64_image_cleaned) {
             Ok(bytes) => image_attachments.push(CreateAttachment::bytes(bytes, format!("image_output_{index}.png"))),
             Err(err) => {
-                println!("At least one image returned an exception/n{}", err);
+                warn!("At least one image could not be decoded: {}", err);
             }
         }
     }
 
-    return image_attachments;
+    Ok(image_attachments)
 }
 
 /*
     This generates images using Runpod serverless
-    This uses reqwest to call the API
+    The job is run with the shared Runpod client, which waits for it with backoff and cancels it if it takes too long or `cancelled` finishes first
+    The seed is returned with the images if the worker reports the one it used
     Note that currently, the serverless implimentation must return a base64 string
     This should work with any Stable Diffusion/Stable Diffusion XL endpoint that is based on the offical API
 */
-async fn generate_runpod_image (
-    prompt_text: String, 
-    model_ref: String, 
-    width: f32, 
-    height: f32, 
-    num_gen: u32,
-    neg_prompt: String,
-    guide_scale: f32,
-    ctx: crate::Context<'_>
-) -> Vec<CreateAttachment> {
-    let requester_id = ctx.author().id;
-    let channel_id = ctx.channel_id();
-    let runpod_auth_key = match env::var("RUNPOD_API_KEY")
-        {
-            Ok(t) => t,
-            Err(_) => return_error(requester_id.clone(), channel_id.clone(), "No runpod API key found".to_owned()).await.unwrap(),
-        };
-    let mut headers = reqwest::header::HeaderMap::new();
-    headers.insert("accept", HeaderValue::from_static("application/json"));
-    headers.insert("authorization", HeaderValue::from_str(&runpod_auth_key).unwrap());
-    headers.insert("content-type", HeaderValue::from_static("application/json"));
-    let client = reqwest::Client::new();
-    let run_response: Response = match client.post(format!("https://api.runpod.ai/v2/{}/run", model_ref))
-        .headers(headers.clone())
-        .body(format!("{{
-            \"input\": {{
-                \"prompt\": \"{}\",
-                \"negative_prompt\": \"{}\",
-                \"height\": {},
-                \"width\": {},
-                \"scheduler\": \"K_EULER\",
-                \"num_inference_steps\": 40,
-                \"guidance_scale\": {},
-                \"num_images\": {}
-            }}
-        }}", prompt_text, neg_prompt, height, width, guide_scale, num_gen))
-        .send()
-        .await
-        {
-            Ok(t) => t,
-            Err(e) => return_error(requester_id.clone(), channel_id.clone(), e.to_string()).await.unwrap(),
-        };
-       
-
-    let run_response_json: RunResponseObject = match run_response.json()
-        .await
-        {
-            Ok(t) => t,
-            Err(e) => return_error(requester_id.clone(), channel_id.clone(), e.to_string()).await.unwrap(),
-        };
-
-    let mut status_response_json: OutputResponseObject;
-    
-    /*
-        This calls the job status endpoint every 2 seconds
-        When the status changes from IN_QUEUE and IN_PROGRESS, the result is then used to get the image information
-    */
-    loop {
-        let status_response = match client.post(format!("https://api.runpod.ai/v2/{}/status/{}", model_ref, run_response_json.id))
-            .headers(headers.clone())
-            .send()
-            .await
-            {
-                Ok(t) => t,
-                Err(e) => return_error(requester_id.clone(), channel_id.clone(), e.to_string()).await.unwrap(),
-            };
-        
-
-        status_response_json = match status_response.json()
-            .await
-            {
-                Ok(t) => t,
-                Err(e) => return_error(requester_id.clone(), channel_id.clone(), e.to_string()).await.unwrap(),
-            };
-
-        if !(status_response_json.status.clone().unwrap() == "IN_QUEUE" || status_response_json.status.clone().unwrap() == "IN_PROGRESS") {
-            break;
-        }
-
-        let _ = sleep(Duration::from_secs(2));
-    }
+#[instrument(skip_all, fields(endpoint = model_ref, width = run_input.width, height = run_input.height))]
+pub async fn generate_runpod_image (
+    runpod_client: &RunpodClient,
+    model_ref: &str,
+    run_input: ImageGenRunInput,
+    use_runsync: bool,
+    cancelled: impl Future<Output = ()>
+) -> Result<(Vec<CreateAttachment>, Option<u64>), Error> {
+    let image_request = ImageGenRequest { input: run_input, webhook: None };
+    let image_output: ImageGenOutput = runpod_client.run(model_ref, image_request, use_runsync, cancelled).await?;
 
     let mut image_attachments: Vec<CreateAttachment> = Vec::default();
 
-    let image_output = match status_response_json.output
-    {
-        Some(t) => t,
-        None => return_error(requester_id.clone(), channel_id.clone(), "No generated image data found".to_owned()).await.unwrap(),
-    };
-
     /*
         This loop goes through every image in the reply and converts it from base64 to bytes
         This is then set as an attachment for a Discord message
@@ -415,10 +1187,106 @@ async fn generate_runpod_image (
         match BASE64_STANDARD.decode(&base64_image_cleaned) {
             Ok(bytes) => image_attachments.push(CreateAttachment::bytes(bytes, format!("image_output_{index}.png"))),
             Err(err) => {
-                println!("At least one image returned an exception/n{}", err);
+                warn!("At least one image could not be decoded: {}", err);
             }
         }
     }
     
-    return image_attachments;
-}
\ No newline at end of file
+    Ok((image_attachments, image_output.seed))
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+
+    use super::*;
+
+    #[test]
+    fn runpod_request_escapes_the_prompt_and_skips_unset_fields() {
+        let prompt = r#"a "quoted" cat \ on a mat"#;
+        let image_request = ImageGenRequest {
+            input: ImageGenRunInput { prompt: prompt.to_owned(), num_images: 2, ..Default::default() },
+            webhook: None,
+        };
+        let request_body: Value = serde_json::from_str(&serde_json::to_string(&image_request).unwrap()).unwrap();
+        assert_eq!(request_body, json!({
+            "input": {
+                "prompt": prompt,
+                "negative_prompt": "",
+                "width": 1024,
+                "height": 1024,
+                "guidance_scale": 7.5,
+                "num_inference_steps": 40,
+                "num_images": 2,
+                "scheduler": "K_EULER"
+            }
+        }));
+    }
+
+    #[test]
+    fn prefix_arguments_are_read_as_options_and_prompt_words() {
+        let arguments = parse_prefix_arguments(r#"model:!delta-imagegen prompt:"a cat" on a mat aspect:16:9 Seed:42 style:photo"#).unwrap();
+        assert_eq!(arguments, ImageArguments {
+            model: Some("!delta-imagegen".to_owned()),
+            prompt: Some("a cat on a mat style:photo".to_owned()),
+            aspect: Some("16:9".to_owned()),
+            seed: Some(42),
+            ..Default::default()
+        });
+        assert!(parse_prefix_arguments("seed:forty-two").is_err());
+        assert!(parse_prefix_arguments("seed:1 seed:2").is_err());
+        assert!(parse_prefix_arguments(r#"prompt:"unclosed"#).is_err());
+        assert_eq!(parse_aspect_ratio("16x9").unwrap(), (16.0, 9.0));
+        assert_eq!(parse_aspect_ratio("Portrait").unwrap(), (2.0, 3.0));
+        assert!(parse_aspect_ratio("16:0").is_err());
+        assert!(parse_aspect_ratio("inf:1").is_err());
+        assert!(parse_aspect_ratio("1:NaN").is_err());
+    }
+
+    #[test]
+    fn guidance_must_be_a_number_in_range() {
+        assert!(check_guidance(0.0).is_ok());
+        assert!(check_guidance(DEFAULT_GUIDANCE_SCALE).is_ok());
+        assert!(check_guidance(MAX_GUIDANCE_SCALE).is_ok());
+        assert!(check_guidance(-1.0).is_err());
+        assert!(check_guidance(MAX_GUIDANCE_SCALE + 1.0).is_err());
+        assert!(check_guidance(f32::NAN).is_err());
+        assert!(check_guidance(f32::INFINITY).is_err());
+    }
+
+    #[test]
+    fn image_sizes_fit_the_budget_or_snap_to_a_resolution() {
+        let mut image_parameters = ImageParameters::default();
+        assert_eq!(image_parameters.image_size(1.0, 1.0), (1024, 1024));
+        assert_eq!(image_parameters.image_size(16.0, 9.0), (1368, 768));
+        // Stable Diffusion 1.5
+        image_parameters.pixel_budget = 512 * 512;
+        image_parameters.size_divisor = 64;
+        assert_eq!(image_parameters.image_size(2.0, 3.0), (448, 640));
+        image_parameters.resolutions = vec![(1024, 1024), (1216, 832), (832, 1216), (1344, 768), (1536, 640)];
+        assert_eq!(image_parameters.image_size(16.0, 9.0), (1344, 768));
+        assert_eq!(image_parameters.image_size(21.0, 9.0), (1536, 640));
+        assert_eq!(image_parameters.image_size(2.0, 3.0), (832, 1216));
+    }
+
+    #[test]
+    fn extreme_image_sizes_are_clamped() {
+        let mut image_parameters = ImageParameters::default();
+        assert_eq!(image_parameters.image_size(4.0, 1.0), (2048, 512));
+        assert_eq!(image_parameters.image_size(1000.0, 1.0), (2048, 512));
+        assert_eq!(image_parameters.image_size(1.0, 1000.0), (512, 2048));
+        assert_eq!(image_parameters.image_size(f32::MAX, f32::MIN_POSITIVE), (2048, 512));
+        image_parameters.resolutions = vec![(1024, 1024), (1536, 640)];
+        assert_eq!(image_parameters.image_size(1.0, 1000.0), (1024, 1024));
+    }
+
+    #[test]
+    fn parameters_use_defaults_and_check_ranges() {
+        let image_parameters = ImageParameters::default();
+        assert_eq!(image_parameters.resolve(None, None, None).unwrap(), (40, "K_EULER".to_owned(), 2));
+        assert_eq!(image_parameters.resolve(Some(20), Some("ddim"), Some(4)).unwrap(), (20, "DDIM".to_owned(), 4));
+        assert!(image_parameters.resolve(Some(101), None, None).is_err());
+        assert!(image_parameters.resolve(None, None, Some(5)).is_err());
+        assert!(image_parameters.resolve(None, Some("NOT_A_SCHEDULER"), None).is_err());
+    }
+}
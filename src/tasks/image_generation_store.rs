@@ -0,0 +1,38 @@
+use rusqlite::{params, OptionalExtension};
+use tracing::warn;
+
+use crate::Error;
+
+use super::{database::Database, image_generation::ImageSettings};
+
+/*
+    Saves the settings of a generation and returns its ID, the ID is put in the buttons under the images
+*/
+pub fn save_image_generation(database: &Database, guild_id: u64, channel_id: u64, user_id: u64, settings: &ImageSettings, created_at: i64) -> Result<i64, Error> {
+    let settings_json = serde_json::to_string(settings)?;
+    database.run(|connection| {
+        connection.execute(
+            "INSERT INTO image_generations (guild_id, channel_id, user_id, settings, created_at) VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![guild_id as i64, channel_id as i64, user_id as i64, settings_json, created_at],
+        )?;
+        Ok(connection.last_insert_rowid())
+    })
+}
+
+/*
+    The settings of a saved generation, settings that can no longer be read (from an older version of the bot) are treated as missing
+*/
+pub fn get_image_generation(database: &Database, generation_id: i64) -> Result<Option<ImageSettings>, Error> {
+    let settings_json: Option<String> = database.run(|connection| connection.query_row(
+        "SELECT settings FROM image_generations WHERE id = ?1",
+        params![generation_id],
+        |row| row.get(0),
+    ).optional())?;
+    Ok(settings_json.and_then(|t| match serde_json::from_str(&t) {
+        Ok(settings) => Some(settings),
+        Err(e) => {
+            warn!(generation_id, "Unable to read the settings of an image generation: {}", e);
+            None
+        },
+    }))
+}
@@ -0,0 +1,124 @@
+use std::env;
+
+use async_trait::async_trait;
+use serenity::{all::{Context, FullEvent, Interaction}, framework::Framework, Client};
+use tracing::{field::Empty, info_span, Instrument, Span};
+use tracing_appender::{non_blocking::WorkerGuard, rolling::{RollingFileAppender, Rotation}};
+use tracing_subscriber::{fmt, layer::SubscriberExt, util::SubscriberInitExt, EnvFilter, Layer, Registry};
+
+use crate::{tasks::handle_errors::BotError, Error};
+
+use super::config::{LogFormat, LogRotation, LoggingConfig};
+
+type BoxedLayer = Box<dyn Layer<Registry> + Send + Sync>;
+
+/*
+    Sets up the tracing subscriber from the logging config
+    RUST_LOG replaces logging.level if it is set, and debug mode turns on debug logs for the bot itself
+    The returned guard flushes the log file when dropped so it has to be kept until the bot exits
+*/
+pub fn init_logging(logging_config: &LoggingConfig, debug_enabled: bool) -> Result<Option<WorkerGuard>, Error> {
+    let filter_directives = env::var("RUST_LOG").unwrap_or_else(|_| logging_config.level.clone());
+    let mut filter = EnvFilter::try_new(&filter_directives)
+        .map_err(|e| BotError::Config(format!("Invalid log filter \"{}\": {}", filter_directives, e)))?;
+    if debug_enabled {
+        filter = filter.add_directive("delta_bot_rusty=debug".parse().map_err(|e| BotError::Config(format!("{}", e)))?);
+    }
+
+    let mut layers: Vec<BoxedLayer> = vec![format_layer(logging_config.format, std::io::stdout, true)];
+
+    let mut file_guard = None;
+    if let Some(directory) = logging_config.resolved_directory() {
+        let rotation = match logging_config.rotation {
+            LogRotation::Hourly => Rotation::HOURLY,
+            LogRotation::Daily => Rotation::DAILY,
+            LogRotation::Never => Rotation::NEVER,
+        };
+        let mut appender_builder = RollingFileAppender::builder()
+            .rotation(rotation)
+            .filename_prefix("delta-bot")
+            .filename_suffix("log");
+        if logging_config.max_files > 0 {
+            appender_builder = appender_builder.max_log_files(logging_config.max_files);
+        }
+        let file_appender = appender_builder.build(&directory)
+            .map_err(|e| BotError::Config(format!("Unable to write logs to {}: {}", directory.display(), e)))?;
+
+        // Writing to the file happens on its own thread so logging never blocks the bot
+        let (file_writer, guard) = tracing_appender::non_blocking(file_appender);
+        layers.push(format_layer(logging_config.file_format, file_writer, false));
+        file_guard = Some(guard);
+    }
+
+    tracing_subscriber::registry()
+        .with(layers)
+        .with(filter)
+        .try_init()
+        .map_err(|e| BotError::Internal(format!("Unable to start logging: {}", e)))?;
+
+    Ok(file_guard)
+}
+
+fn format_layer<W>(format: LogFormat, writer: W, ansi: bool) -> BoxedLayer
+where
+    W: for<'writer> fmt::MakeWriter<'writer> + Send + Sync + 'static,
+{
+    let layer = fmt::layer().with_writer(writer).with_ansi(ansi);
+    match format {
+        LogFormat::Pretty => layer.pretty().boxed(),
+        LogFormat::Compact => layer.compact().boxed(),
+        LogFormat::Json => layer.json().with_current_span(true).with_span_list(true).boxed(),
+    }
+}
+
+/*
+    Runs the poise framework with every slash command and message inside a span
+    The span has who sent it and where, so every log from a command or reply can be traced back to it
+    Anything further down (the model, token usage, Runpod job IDs and so on) is added in spans of their own
+*/
+pub struct TracedFramework<F: Framework>(pub F);
+
+#[async_trait]
+impl<F: Framework> Framework for TracedFramework<F> {
+    async fn init(&mut self, client: &Client) {
+        self.0.init(client).await;
+    }
+
+    async fn dispatch(&self, ctx: Context, event: FullEvent) {
+        let span = event_span(&ctx, &event);
+        self.0.dispatch(ctx, event).instrument(span).await;
+    }
+}
+
+fn event_span(ctx: &Context, event: &FullEvent) -> Span {
+    match event {
+        FullEvent::InteractionCreate { interaction: Interaction::Command(command_interaction) } => info_span!(
+            "command",
+            command = %command_interaction.data.name,
+            user_id = command_interaction.user.id.get(),
+            guild_id = command_interaction.guild_id.map(|t| t.get()),
+            channel_id = command_interaction.channel_id.get(),
+        ),
+        FullEvent::InteractionCreate { interaction: Interaction::Component(component_interaction) } => info_span!(
+            "button",
+            custom_id = %component_interaction.data.custom_id,
+            user_id = component_interaction.user.id.get(),
+            guild_id = component_interaction.guild_id.map(|t| t.get()),
+            channel_id = component_interaction.channel_id.get(),
+        ),
+        FullEvent::Message { new_message } if !new_message.author.bot => {
+            let kind = if new_message.mentions_user_id(ctx.cache.current_user().id) { "mention" } else { "message" };
+            // Prefix commands fill in the command field from pre_command
+            info_span!(
+                "message",
+                kind,
+                command = Empty,
+                message_id = new_message.id.get(),
+                user_id = new_message.author.id.get(),
+                guild_id = new_message.guild_id.map(|t| t.get()),
+                channel_id = new_message.channel_id.get(),
+            )
+        },
+        _ => Span::none(),
+    }
+}
@@ -0,0 +1,382 @@
+/*
+    Splits long replies into messages that fit in Discord's character limit
+    Splits are made at the nicest point available, in order of preference
+        - Between paragraphs
+        - Between lines
+        - Between sentences
+        - Between words
+        - Anywhere that is not inside inline code, a link, a bare URL or a mention
+    Code blocks that are split are closed at the end of one message and reopened (with the same language tag) in the next
+    Inside code blocks, splits are made between lines where possible as spaces and full stops mean nothing there
+    All lengths are counted in characters rather than bytes so multi-byte characters are never cut in half
+*/
+
+// Added to the end of a message that ends inside a code block
+const FENCE_CLOSE: &str = "\n```";
+
+pub fn split_message(text: &str, limit: usize) -> Vec<String> {
+    let chars: Vec<char> = text.chars().collect();
+    if chars.len() <= limit {
+        return vec![text.to_owned()];
+    }
+
+    let analysis = analyse(&chars);
+    let mut chunks: Vec<String> = Vec::new();
+    let mut start = 0;
+
+    while start < chars.len() {
+        // A split just before the end of a code block has already closed it, reopening it would only leave an empty block
+        if let Some(fence_end) = closing_fence_end(&chars, &analysis, start) {
+            start = fence_end;
+            continue;
+        }
+
+        // If the last message ended inside a code block, this one starts by reopening it
+        let reopen = match analysis.fence_at[start] {
+            Some(tag_index) => format!("{}\n", analysis.fence_tags[tag_index]),
+            None => String::new(),
+        };
+        let reopen_length = reopen.chars().count();
+
+        if reopen_length + chars.len() - start <= limit {
+            chunks.push(reopen + &chars[start..].iter().collect::<String>());
+            break;
+        }
+
+        let budget = limit.saturating_sub(reopen_length + FENCE_CLOSE.len()).max(1);
+        let (chunk_end, next_start) = find_break(&chars, &analysis, start, start + budget);
+
+        let mut chunk = reopen + &chars[start..chunk_end].iter().collect::<String>();
+        if analysis.fence_at[chunk_end].is_some() {
+            chunk.push_str(FENCE_CLOSE);
+        }
+        chunks.push(chunk);
+        start = next_start;
+    }
+
+    chunks
+}
+
+struct Analysis {
+    // protected[i] is true if the text must not be split directly before character i
+    protected: Vec<bool>,
+    // fence_at[i] is the code block that is open directly before character i, as an index into fence_tags
+    // This has one more entry than the text so the end of the text can be checked
+    fence_at: Vec<Option<usize>>,
+    // The opening line of each code block, for example ```rust
+    fence_tags: Vec<String>,
+}
+
+fn analyse(chars: &[char]) -> Analysis {
+    let mut protected = vec![false; chars.len() + 1];
+    let mut fence_at: Vec<Option<usize>> = vec![None; chars.len() + 1];
+    let mut fence_tags: Vec<String> = Vec::new();
+    let mut open_fence: Option<usize> = None;
+    let mut index = 0;
+
+    while index < chars.len() {
+        fence_at[index] = open_fence;
+        let at_line_start = index == 0 || chars[index - 1] == '\n';
+
+        // Code block fences toggle at the start of a line, the fence line itself is never split
+        if at_line_start {
+            let indent = chars[index..].iter().take_while(|t| **t == ' ' || **t == '\t').count();
+            if starts_with(chars, index + indent, "```") {
+                let line_end = find_from(chars, index, '\n').unwrap_or(chars.len());
+                protect(&mut protected, index + 1, line_end);
+                if open_fence.is_some() {
+                    open_fence = None;
+                } else {
+                    fence_tags.push(chars[index + indent..line_end].iter().collect::<String>().trim_end().to_owned());
+                    open_fence = Some(fence_tags.len() - 1);
+                }
+                for fence_state in fence_at.iter_mut().take(line_end + 1).skip(index + 1) {
+                    *fence_state = open_fence;
+                }
+                index = line_end;
+                continue;
+            }
+        }
+
+        // Inside a code block, markdown is not parsed so only the line breaks matter
+        if open_fence.is_some() {
+            index += 1;
+            continue;
+        }
+
+        let span_end = match chars[index] {
+            '`' => inline_code_end(chars, index),
+            '[' => link_end(chars, index),
+            '<' => mention_end(chars, index),
+            'h' if starts_with(chars, index, "http://") || starts_with(chars, index, "https://") => {
+                Some(index + chars[index..].iter().take_while(|t| !t.is_whitespace()).count())
+            },
+            _ => None,
+        };
+
+        match span_end {
+            Some(end) => {
+                protect(&mut protected, index + 1, end);
+                index = end;
+            },
+            None => index += 1,
+        }
+    }
+    fence_at[chars.len()] = open_fence;
+
+    Analysis { protected, fence_at, fence_tags }
+}
+
+/*
+    Returns the end of the chunk (exclusive) and where the next chunk starts
+    The separator between the two (a new line, a space and so on) is dropped
+*/
+fn find_break(chars: &[char], analysis: &Analysis, start: usize, window_end: usize) -> (usize, usize) {
+    let allowed = |position: usize| position > start && position <= window_end && !analysis.protected[position];
+
+    let mut paragraph_breaks: Vec<(usize, usize)> = Vec::new();
+    let mut line_breaks: Vec<(usize, usize)> = Vec::new();
+    let mut sentence_breaks: Vec<(usize, usize)> = Vec::new();
+    let mut word_breaks: Vec<(usize, usize)> = Vec::new();
+    // Spaces inside code blocks are only used when there is no line break at all
+    let mut code_word_breaks: Vec<(usize, usize)> = Vec::new();
+
+    for index in start..window_end.min(chars.len()) {
+        let next = chars.get(index + 1).copied();
+        let in_code_block = analysis.fence_at[index].is_some();
+        match chars[index] {
+            '\n' if next == Some('\n') && allowed(index) => {
+                let next_start = index + chars[index..].iter().take_while(|t| **t == '\n').count();
+                paragraph_breaks.push((index, next_start));
+            },
+            '\n' if next != Some('\n') && allowed(index) => line_breaks.push((index, index + 1)),
+            '.' | '!' | '?' if !in_code_block && next == Some(' ') && allowed(index + 1) => sentence_breaks.push((index + 1, index + 2)),
+            ' ' if in_code_block && allowed(index) => code_word_breaks.push((index, index + 1)),
+            ' ' if allowed(index) => word_breaks.push((index, index + 1)),
+            _ => {}
+        }
+    }
+
+    let tiers = [&paragraph_breaks, &line_breaks, &sentence_breaks, &word_breaks, &code_word_breaks];
+
+    // Prefer the best kind of break that still fills at least half of the message
+    let half_full = start + (window_end - start) / 2;
+    for tier in &tiers[..4] {
+        if let Some(found) = tier.iter().rev().find(|(end, _)| *end >= half_full) {
+            return *found;
+        }
+    }
+    for tier in tiers {
+        if let Some(found) = tier.last() {
+            return *found;
+        }
+    }
+
+    // No natural break, split at the latest point that is not inside something that would break
+    match (start + 1..=window_end).rev().find(|position| allowed(*position)) {
+        Some(position) => (position, position),
+        None => (window_end, window_end),
+    }
+}
+
+/*
+    If a message would start with the fence that closes a code block, returns where the text after that fence starts
+    The message before already closed the block so the fence line is not needed
+*/
+fn closing_fence_end(chars: &[char], analysis: &Analysis, start: usize) -> Option<usize> {
+    if analysis.fence_at[start].is_none() || (start > 0 && chars[start - 1] != '\n') {
+        return None;
+    }
+    let indent = chars[start..].iter().take_while(|t| **t == ' ' || **t == '\t').count();
+    if !starts_with(chars, start + indent, "```") {
+        return None;
+    }
+    let line_end = find_from(chars, start, '\n').unwrap_or(chars.len());
+    Some(line_end + chars[line_end..].iter().take_while(|t| **t == '\n').count())
+}
+
+// Inline code ends at the next run of the same number of backticks
+fn inline_code_end(chars: &[char], start: usize) -> Option<usize> {
+    let run_length = chars[start..].iter().take_while(|t| **t == '`').count();
+    let mut index = start + run_length;
+    while index < chars.len() {
+        if chars[index] == '`' {
+            let closing_length = chars[index..].iter().take_while(|t| **t == '`').count();
+            if closing_length == run_length {
+                return Some(index + closing_length);
+            }
+            index += closing_length;
+        } else {
+            index += 1;
+        }
+    }
+    None
+}
+
+// Links are [text](url) on a single line
+fn link_end(chars: &[char], start: usize) -> Option<usize> {
+    let text_end = (start + 1..chars.len()).find(|index| chars[*index] == ']' || chars[*index] == '\n')?;
+    if chars[text_end] != ']' || chars.get(text_end + 1) != Some(&'(') {
+        return None;
+    }
+    let url_end = (text_end + 2..chars.len()).find(|index| chars[*index] == ')' || chars[*index].is_whitespace())?;
+    if chars[url_end] != ')' {
+        return None;
+    }
+    Some(url_end + 1)
+}
+
+// Mentions, channels, emojis, timestamps and <url> links are all wrapped in <>
+fn mention_end(chars: &[char], start: usize) -> Option<usize> {
+    let next = chars.get(start + 1)?;
+    if !matches!(next, '@' | '#' | ':' | 'a' | 't' | 'h') {
+        return None;
+    }
+    let end = (start + 1..chars.len()).find(|index| chars[*index] == '>' || chars[*index].is_whitespace())?;
+    if chars[end] != '>' {
+        return None;
+    }
+    Some(end + 1)
+}
+
+fn starts_with(chars: &[char], start: usize, pattern: &str) -> bool {
+    let pattern_chars: Vec<char> = pattern.chars().collect();
+    chars.len() >= start + pattern_chars.len() && chars[start..start + pattern_chars.len()] == pattern_chars[..]
+}
+
+fn find_from(chars: &[char], start: usize, target: char) -> Option<usize> {
+    (start..chars.len()).find(|index| chars[*index] == target)
+}
+
+fn protect(protected: &mut [bool], from: usize, to: usize) {
+    for flag in protected.iter_mut().take(to).skip(from) {
+        *flag = true;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::split_message;
+
+    fn assert_within_limit(chunks: &[String], limit: usize) {
+        for chunk in chunks {
+            assert!(chunk.chars().count() <= limit, "chunk of {} characters is over the limit of {}", chunk.chars().count(), limit);
+        }
+    }
+
+    fn assert_fences_balanced(chunks: &[String]) {
+        for chunk in chunks {
+            let fence_count = chunk.lines().filter(|line| line.trim_start().starts_with("```")).count();
+            assert_eq!(fence_count % 2, 0, "unbalanced code block in chunk:\n{}", chunk);
+        }
+    }
+
+    #[test]
+    fn short_text_is_unchanged() {
+        assert_eq!(split_message("Hello there", 2000), vec!["Hello there".to_owned()]);
+    }
+
+    #[test]
+    fn prefers_paragraph_breaks() {
+        let first = "a".repeat(60);
+        let second = "b ".repeat(40);
+        let text = format!("{}\n\n{}", first, second.trim_end());
+        let chunks = split_message(&text, 100);
+        assert_eq!(chunks[0], first);
+        assert_within_limit(&chunks, 100);
+    }
+
+    #[test]
+    fn never_breaks_words_when_there_are_spaces() {
+        let text = "lorem ipsum dolor sit amet consectetur adipiscing elit ".repeat(20);
+        let chunks = split_message(text.trim_end(), 90);
+        assert_within_limit(&chunks, 90);
+        let rejoined: Vec<&str> = chunks.iter().flat_map(|chunk| chunk.split_whitespace()).collect();
+        let original: Vec<&str> = text.split_whitespace().collect();
+        assert_eq!(rejoined, original);
+    }
+
+    #[test]
+    fn prefers_sentence_breaks_over_word_breaks() {
+        let text = format!("{}. {}", "word ".repeat(12).trim_end(), "more words here and there ".repeat(4).trim_end());
+        let chunks = split_message(&text, 80);
+        assert!(chunks[0].ends_with('.'), "expected a sentence break, got {:?}", chunks[0]);
+    }
+
+    #[test]
+    fn counts_characters_not_bytes() {
+        let text = "🦀é漢字 ".repeat(200);
+        let chunks = split_message(&text, 50);
+        assert_within_limit(&chunks, 50);
+        assert_eq!(chunks.concat().replace(' ', ""), text.replace(' ', ""));
+    }
+
+    #[test]
+    fn hard_splits_text_without_spaces() {
+        let text = "x".repeat(250);
+        let chunks = split_message(&text, 100);
+        assert_within_limit(&chunks, 100);
+        assert_eq!(chunks.concat(), text);
+    }
+
+    #[test]
+    fn reopens_code_blocks_with_the_language() {
+        let code_lines: Vec<String> = (0..40).map(|index| format!("    let value_{} = {};", index, index)).collect();
+        let text = format!("Here is the code:\n```rust\n{}\n```\nDone", code_lines.join("\n"));
+        let chunks = split_message(&text, 300);
+        assert!(chunks.len() > 1);
+        assert_within_limit(&chunks, 300);
+        assert_fences_balanced(&chunks);
+        for chunk in chunks.iter().skip(1).filter(|chunk| chunk.contains("let value_")) {
+            assert!(chunk.starts_with("```rust\n"), "chunk did not reopen the code block:\n{}", chunk);
+        }
+        // Indentation inside the code block is kept
+        assert!(chunks.iter().all(|chunk| !chunk.contains("\nlet value_")));
+    }
+
+    #[test]
+    fn code_blocks_split_between_lines() {
+        let long_line = "    let sentence = \"Words. More words\"; ".repeat(6);
+        let text = format!("```rust\nfn main() {{\n{}\n}}\n```", long_line.trim_end());
+        let chunks = split_message(&text, 200);
+        // The only line break in the first window is before the halfway point, it is still used over the spaces
+        assert_eq!(chunks[0], "```rust\nfn main() {\n```");
+        assert_within_limit(&chunks, 200);
+        assert_fences_balanced(&chunks);
+    }
+
+    #[test]
+    fn does_not_reopen_a_code_block_that_has_ended() {
+        let text = format!("```\n{}\n```\n{}", "x".repeat(90), "y".repeat(50));
+        let chunks = split_message(&text, 100);
+        assert_eq!(chunks, vec![format!("```\n{}\n```", "x".repeat(90)), "y".repeat(50)]);
+    }
+
+    #[test]
+    fn does_not_split_inline_code() {
+        let inline = "`some inline code that is long`";
+        let text = format!("{} {} {}", "a".repeat(30), inline, "b".repeat(30));
+        let chunks = split_message(&text, 50);
+        assert!(chunks.iter().any(|chunk| chunk.contains(inline)), "inline code was split: {:?}", chunks);
+    }
+
+    #[test]
+    fn does_not_split_links_or_mentions() {
+        let link = "[the docs](https://example.com/a/very/long/path)";
+        let mention = "<@123456789012345678>";
+        let text = format!("{} {} {} {} {}", "a".repeat(20), link, "b".repeat(20), mention, "c".repeat(40));
+        let chunks = split_message(&text, 60);
+        assert!(chunks.iter().any(|chunk| chunk.contains(link)), "link was split: {:?}", chunks);
+        assert!(chunks.iter().any(|chunk| chunk.contains(mention)), "mention was split: {:?}", chunks);
+    }
+
+    #[test]
+    fn keeps_list_items_together() {
+        let text = (0..30).map(|index| format!("- item number {} in the list", index)).collect::<Vec<_>>().join("\n");
+        let chunks = split_message(&text, 120);
+        assert_within_limit(&chunks, 120);
+        for chunk in chunks {
+            assert!(chunk.lines().all(|line| line.starts_with("- item number")), "list item was split: {:?}", chunk);
+        }
+    }
+}
@@ -0,0 +1,103 @@
+use std::{sync::LazyLock, time::Instant};
+
+use prometheus::{exponential_buckets, HistogramOpts, HistogramVec, IntCounterVec, Opts, Registry, TextEncoder};
+
+/*
+    The Prometheus metrics for the bot, served at /metrics by the HTTP server
+    These are global so anything can record to them, even code without access to the poise Data (such as FFmpeg)
+*/
+pub struct Metrics {
+    registry: Registry,
+    pub chat_completions: RequestMetric,
+    pub image_generations: RequestMetric,
+    pub tts: RequestMetric,
+    pub transcriptions: RequestMetric,
+    pub ffmpeg_runs: RequestMetric,
+    pub errors: IntCounterVec,
+    pub tokens: IntCounterVec,
+}
+
+/*
+    A count of requests (labelled with whether they succeeded) and how long they took
+*/
+pub struct RequestMetric {
+    count: IntCounterVec,
+    duration: HistogramVec,
+}
+
+impl RequestMetric {
+    fn new(registry: &Registry, name: &str, help: &str, labels: &[&str]) -> RequestMetric {
+        let mut count_labels = labels.to_vec();
+        count_labels.push("outcome");
+        let count = IntCounterVec::new(Opts::new(format!("delta_{}_total", name), help), &count_labels)
+            .expect("The metric options are valid");
+        // 0.1 seconds to about 7 minutes, image generation and long transcriptions can take minutes
+        let duration = HistogramVec::new(
+            HistogramOpts::new(format!("delta_{}_duration_seconds", name), format!("{} (seconds taken)", help))
+                .buckets(exponential_buckets(0.1, 2.0, 13).expect("The buckets are valid")),
+            labels,
+        ).expect("The metric options are valid");
+
+        registry.register(Box::new(count.clone())).expect("Every metric has a unique name");
+        registry.register(Box::new(duration.clone())).expect("Every metric has a unique name");
+        RequestMetric { count, duration }
+    }
+
+    /*
+        Records a finished request, the labels are the ones given to new (without the outcome)
+    */
+    pub fn observe(&self, labels: &[&str], started: Instant, success: bool) {
+        let mut count_labels = labels.to_vec();
+        count_labels.push(if success { "success" } else { "error" });
+        self.count.with_label_values(&count_labels).inc();
+        self.duration.with_label_values(labels).observe(started.elapsed().as_secs_f64());
+    }
+}
+
+static METRICS: LazyLock<Metrics> = LazyLock::new(|| {
+    let registry = Registry::new();
+    let chat_completions = RequestMetric::new(&registry, "chat_completions", "Chat completion requests to the text backend", &["model"]);
+    let image_generations = RequestMetric::new(&registry, "image_generations", "Image generation requests", &["function_command"]);
+    let tts = RequestMetric::new(&registry, "tts_requests", "Text to speech requests", &[]);
+    let transcriptions = RequestMetric::new(&registry, "transcriptions", "Transcription requests", &[]);
+    let ffmpeg_runs = RequestMetric::new(&registry, "ffmpeg_runs", "FFmpeg conversions", &[]);
+
+    let errors = IntCounterVec::new(Opts::new("delta_errors_total", "Errors returned by commands and replies"), &["kind"])
+        .expect("The metric options are valid");
+    // These are estimated with the tokenizer as streamed replies do not include usage
+    let tokens = IntCounterVec::new(Opts::new("delta_tokens_total", "Tokens sent to and generated by the text backend"), &["model", "kind"])
+        .expect("The metric options are valid");
+    registry.register(Box::new(errors.clone())).expect("Every metric has a unique name");
+    registry.register(Box::new(tokens.clone())).expect("Every metric has a unique name");
+
+    Metrics { registry, chat_completions, image_generations, tts, transcriptions, ffmpeg_runs, errors, tokens }
+});
+
+pub fn metrics() -> &'static Metrics {
+    &METRICS
+}
+
+impl Metrics {
+    /*
+        Every metric in the Prometheus text format
+    */
+    pub fn encode(&self) -> String {
+        TextEncoder::new().encode_to_string(&self.registry.gather()).unwrap_or_else(|e| format!("# Unable to encode the metrics: {}\n", e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn records_and_encodes_metrics() {
+        metrics().image_generations.observe(&["!delta-dalle"], Instant::now(), true);
+        metrics().errors.with_label_values(&["runpod"]).inc();
+
+        let encoded = metrics().encode();
+        assert!(encoded.contains("delta_image_generations_total{function_command=\"!delta-dalle\",outcome=\"success\"} 1"));
+        assert!(encoded.contains("delta_image_generations_duration_seconds_bucket"));
+        assert!(encoded.contains("delta_errors_total{kind=\"runpod\"} 1"));
+    }
+}
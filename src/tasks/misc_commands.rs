@@ -2,7 +2,7 @@ use std::{env, fs};
 
 use tokio::fs::try_exists;
 
-use crate::{tasks::handle_errors::return_error, Error};
+use crate::{tasks::handle_errors::BotError, Error};
 
 /// Show help message
 #[poise::command(prefix_command, slash_command)]
@@ -11,33 +11,17 @@ pub async fn help(
     #[description = "Command to get help for (default shows general help)"]
     command: Option<String>,
 ) -> Result<(), Error> {
-    let requester_id = ctx.author().id;
     let channel_id = ctx.channel_id();
-    let current_exe = match env::current_exe()
-        {
-            Ok(t) => t,
-            Err(e) => return_error(requester_id.clone(), channel_id.clone(), e.to_string()).await.unwrap(),
-        };
-    let current_path = match current_exe.parent() 
-        {
-            Some(t) => t,
-            None => return_error(requester_id.clone(), channel_id.clone(), "Unable to process current function string".to_owned()).await.unwrap(),
-        };
+    let current_exe = env::current_exe()?;
+    let current_path = current_exe.parent().ok_or("Unable to process current function string")?;
     let command_to_help = command.unwrap_or("help".to_owned());
     let help_file_location = current_path.join("assets").join("help").join(format!("{}.md", command_to_help));
-    let help_file_exists = match try_exists(help_file_location.clone()).await
-        {
-            Ok(t) => t,
-            Err(e) => return_error(requester_id.clone(), channel_id.clone(), e.to_string()).await.unwrap(),
-        };
-    if help_file_exists {
-        let help_full_markdown = match fs::read_to_string(help_file_location)
-        {
-            Ok(t) => t,
-            Err(e) => return_error(requester_id.clone(), channel_id.clone(), e.to_string()).await.unwrap(),
-        };
-        let _ = channel_id.say(ctx, format!("{}", help_full_markdown)).await;
+    let help_file_exists = try_exists(help_file_location.clone()).await?;
+    if !help_file_exists {
+        return Err(BotError::UserInput(format!("There is no help for {}, use help without a command to see every command", command_to_help)));
     }
+    let help_full_markdown = fs::read_to_string(help_file_location)?;
+    channel_id.say(ctx, help_full_markdown).await?;
     
     Ok(())
 }
\ No newline at end of file
@@ -0,0 +1,181 @@
+use poise::CreateReply;
+use serenity::all::{GuildChannel, GuildId, Role};
+
+use crate::{tasks::handle_errors::BotError, Error};
+
+use super::{message_splitter::split_message, permission_store::{delete_command_rules, delete_permission_rule, list_permission_rules, save_permission_rule, PermissionRule, PermissionScope}, permissions::{CHAT_PERMISSION, PERMISSIONS_COMMAND}};
+
+/*
+    Permission management, this needs the Manage Server permission
+    Rules allow or deny a command (or chat replies) for a role or a channel in this server
+*/
+#[poise::command(
+    slash_command,
+    guild_only,
+    subcommands("permissions_allow", "permissions_deny", "permissions_clear", "permissions_list"),
+    subcommand_required,
+    default_member_permissions = "MANAGE_GUILD",
+    required_permissions = "MANAGE_GUILD"
+)]
+pub async fn permissions(_ctx: crate::Context<'_>) -> Result<(), Error> {
+    Ok(())
+}
+
+/// Allow a command for a role or channel, once any are allowed only those can use it
+#[poise::command(slash_command, guild_only, rename = "allow", required_permissions = "MANAGE_GUILD")]
+async fn permissions_allow(
+    ctx: crate::Context<'_>,
+    #[description = "The command, or chat for replies to mentions"]
+    #[autocomplete = "autocomplete_command"]
+    command: String,
+    #[description = "Role to allow (@everyone is the whole server)"]
+    role: Option<Role>,
+    #[description = "Channel to allow"]
+    #[channel_types("Text", "Voice", "PublicThread", "PrivateThread", "NewsThread", "News", "Forum")]
+    channel: Option<GuildChannel>
+) -> Result<(), Error> {
+    set_rule(ctx, command, role, channel, true).await
+}
+
+/// Deny a command for a role or channel
+#[poise::command(slash_command, guild_only, rename = "deny", required_permissions = "MANAGE_GUILD")]
+async fn permissions_deny(
+    ctx: crate::Context<'_>,
+    #[description = "The command, or chat for replies to mentions"]
+    #[autocomplete = "autocomplete_command"]
+    command: String,
+    #[description = "Role to deny (@everyone is the whole server)"]
+    role: Option<Role>,
+    #[description = "Channel to deny"]
+    #[channel_types("Text", "Voice", "PublicThread", "PrivateThread", "NewsThread", "News", "Forum")]
+    channel: Option<GuildChannel>
+) -> Result<(), Error> {
+    set_rule(ctx, command, role, channel, false).await
+}
+
+/// Remove the rule for a role or channel, or every rule for the command if neither is given
+#[poise::command(slash_command, guild_only, rename = "clear", required_permissions = "MANAGE_GUILD")]
+async fn permissions_clear(
+    ctx: crate::Context<'_>,
+    #[description = "The command, or chat for replies to mentions"]
+    #[autocomplete = "autocomplete_command"]
+    command: String,
+    #[description = "Role to remove the rule for"]
+    role: Option<Role>,
+    #[description = "Channel to remove the rule for"]
+    #[channel_types("Text", "Voice", "PublicThread", "PrivateThread", "NewsThread", "News", "Forum")]
+    channel: Option<GuildChannel>
+) -> Result<(), Error> {
+    let guild_id = command_guild_id(ctx)?;
+    let command = validate_command(ctx, &command)?;
+    let database = &ctx.data().database;
+
+    if role.is_none() && channel.is_none() {
+        let deleted_count = delete_command_rules(database, guild_id.get(), &command)?;
+        return send_ephemeral(ctx, format!("Removed {} rule(s) for {}, it can be used everywhere again", deleted_count, command)).await;
+    }
+    let (scope, target_id) = rule_target(role.as_ref(), channel.as_ref())?;
+    if delete_permission_rule(database, guild_id.get(), &command, scope, target_id)? {
+        send_ephemeral(ctx, format!("The rule for {} in {} has been removed", command, target_name(guild_id, scope, target_id))).await
+    } else {
+        send_ephemeral(ctx, format!("There was no rule for {} in {}", command, target_name(guild_id, scope, target_id))).await
+    }
+}
+
+/// List the permission rules in this server
+#[poise::command(slash_command, guild_only, rename = "list", required_permissions = "MANAGE_GUILD")]
+async fn permissions_list(ctx: crate::Context<'_>) -> Result<(), Error> {
+    let guild_id = command_guild_id(ctx)?;
+    let permission_rules = list_permission_rules(&ctx.data().database, guild_id.get())?;
+    if permission_rules.is_empty() {
+        return send_ephemeral(ctx, "There are no permission rules in this server, everything can be used everywhere".to_owned()).await;
+    }
+
+    let mut rule_list = String::new();
+    let mut current_command = "";
+    for permission_rule in &permission_rules {
+        if permission_rule.command != current_command {
+            current_command = &permission_rule.command;
+            rule_list.push_str(&format!("\n**{}**\n", current_command));
+        }
+        rule_list.push_str(&format!(
+            "> {} {}\n",
+            if permission_rule.allowed { "Allowed for" } else { "Denied for" },
+            target_name(guild_id, permission_rule.scope, permission_rule.target_id)
+        ));
+    }
+
+    for rule_list_chunk in split_message(rule_list.trim_start(), 1900) {
+        send_ephemeral(ctx, rule_list_chunk).await?;
+    }
+    Ok(())
+}
+
+async fn set_rule(ctx: crate::Context<'_>, command: String, role: Option<Role>, channel: Option<GuildChannel>, allowed: bool) -> Result<(), Error> {
+    let guild_id = command_guild_id(ctx)?;
+    let command = validate_command(ctx, &command)?;
+    let (scope, target_id) = rule_target(role.as_ref(), channel.as_ref())?;
+
+    let permission_rule = PermissionRule { command, scope, target_id, allowed };
+    save_permission_rule(&ctx.data().database, guild_id.get(), &permission_rule)?;
+
+    send_ephemeral(ctx, format!(
+        "{} is now {} for {}",
+        permission_rule.command,
+        if allowed { "allowed" } else { "denied" },
+        target_name(guild_id, scope, target_id)
+    )).await
+}
+
+/*
+    The names that rules can be set for, chat and every top level command apart from this one
+*/
+fn permission_names(ctx: crate::Context<'_>) -> Vec<String> {
+    std::iter::once(CHAT_PERMISSION.to_owned())
+        .chain(ctx.framework().options().commands.iter().map(|t| t.name.clone()))
+        .filter(|t| t != PERMISSIONS_COMMAND)
+        .collect()
+}
+
+async fn autocomplete_command(ctx: crate::Context<'_>, partial: &str) -> Vec<String> {
+    permission_names(ctx).into_iter().filter(|t| t.starts_with(&partial.to_lowercase())).collect()
+}
+
+fn validate_command(ctx: crate::Context<'_>, command: &str) -> Result<String, Error> {
+    let command = command.trim().to_lowercase();
+    if command == PERMISSIONS_COMMAND {
+        return Err(BotError::UserInput("The permissions command can't be limited, it always needs the Manage Server permission".to_owned()));
+    }
+    let permission_names = permission_names(ctx);
+    if !permission_names.contains(&command) {
+        return Err(BotError::UserInput(format!("There is no command called {}, it can be one of: {}", command, permission_names.join(", "))));
+    }
+    Ok(command)
+}
+
+fn rule_target(role: Option<&Role>, channel: Option<&GuildChannel>) -> Result<(PermissionScope, u64), Error> {
+    match (role, channel) {
+        (Some(_), Some(_)) => Err(BotError::UserInput("Give either a role or a channel, not both".to_owned())),
+        (Some(role), None) => Ok((PermissionScope::Role, role.id.get())),
+        (None, Some(channel)) => Ok((PermissionScope::Channel, channel.id.get())),
+        (None, None) => Err(BotError::UserInput("Give a role or a channel, use the @everyone role for the whole server".to_owned())),
+    }
+}
+
+fn target_name(guild_id: GuildId, scope: PermissionScope, target_id: u64) -> String {
+    match scope {
+        PermissionScope::Role if target_id == guild_id.get() => "@everyone".to_owned(),
+        PermissionScope::Role => format!("<@&{}>", target_id),
+        PermissionScope::Channel => format!("<#{}>", target_id),
+    }
+}
+
+// The commands are guild_only so this only fails if Discord sends something unexpected
+fn command_guild_id(ctx: crate::Context<'_>) -> Result<GuildId, Error> {
+    ctx.guild_id().ok_or(BotError::UserInput("Permissions can only be managed in a server".to_owned()))
+}
+
+async fn send_ephemeral(ctx: crate::Context<'_>, content: String) -> Result<(), Error> {
+    ctx.send(CreateReply::default().content(content).ephemeral(true)).await?;
+    Ok(())
+}
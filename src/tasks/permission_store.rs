@@ -0,0 +1,116 @@
+use rusqlite::{params, Row};
+
+use crate::Error;
+
+use super::database::Database;
+
+/*
+    What a permission rule applies to, the target ID is the role or channel ID
+    The @everyone role has the same ID as the server
+*/
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PermissionScope {
+    Role,
+    Channel,
+}
+
+impl PermissionScope {
+    fn as_str(&self) -> &'static str {
+        match self {
+            PermissionScope::Role => "role",
+            PermissionScope::Channel => "channel",
+        }
+    }
+
+    fn parse(scope: &str) -> Option<PermissionScope> {
+        match scope {
+            "role" => Some(PermissionScope::Role),
+            "channel" => Some(PermissionScope::Channel),
+            _ => None,
+        }
+    }
+}
+
+/*
+    Allows or denies a command (or chat replies) for a role or channel in a server
+*/
+#[derive(Clone, Debug)]
+pub struct PermissionRule {
+    pub command: String,
+    pub scope: PermissionScope,
+    pub target_id: u64,
+    pub allowed: bool,
+}
+
+/*
+    Creates the rule or replaces the rule for the same command, role or channel
+*/
+pub fn save_permission_rule(database: &Database, guild_id: u64, permission_rule: &PermissionRule) -> Result<(), Error> {
+    database.run(|connection| connection.execute(
+        "INSERT OR REPLACE INTO command_permissions (guild_id, command, scope, target_id, allowed) VALUES (?1, ?2, ?3, ?4, ?5)",
+        params![
+            guild_id as i64,
+            permission_rule.command,
+            permission_rule.scope.as_str(),
+            permission_rule.target_id as i64,
+            permission_rule.allowed
+        ],
+    ))?;
+    Ok(())
+}
+
+/*
+    Returns false if there was no rule to remove
+*/
+pub fn delete_permission_rule(database: &Database, guild_id: u64, command: &str, scope: PermissionScope, target_id: u64) -> Result<bool, Error> {
+    let deleted_count = database.run(|connection| connection.execute(
+        "DELETE FROM command_permissions WHERE guild_id = ?1 AND command = ?2 AND scope = ?3 AND target_id = ?4",
+        params![guild_id as i64, command, scope.as_str(), target_id as i64],
+    ))?;
+    Ok(deleted_count > 0)
+}
+
+/*
+    Removes every rule for a command, returns how many were removed
+*/
+pub fn delete_command_rules(database: &Database, guild_id: u64, command: &str) -> Result<usize, Error> {
+    database.run(|connection| connection.execute(
+        "DELETE FROM command_permissions WHERE guild_id = ?1 AND command = ?2",
+        params![guild_id as i64, command],
+    ))
+}
+
+pub fn list_permission_rules(database: &Database, guild_id: u64) -> Result<Vec<PermissionRule>, Error> {
+    database.run(|connection| {
+        let mut statement = connection.prepare(
+            "SELECT command, scope, target_id, allowed FROM command_permissions WHERE guild_id = ?1 ORDER BY command, scope, target_id",
+        )?;
+        let rows = statement.query_map(params![guild_id as i64], read_permission_rule)?;
+        // Rows with a scope this version does not know about are skipped
+        Ok(rows.collect::<rusqlite::Result<Vec<Option<PermissionRule>>>>()?.into_iter().flatten().collect())
+    })
+}
+
+pub fn command_permission_rules(database: &Database, guild_id: u64, command: &str) -> Result<Vec<PermissionRule>, Error> {
+    database.run(|connection| {
+        let mut statement = connection.prepare(
+            "SELECT command, scope, target_id, allowed FROM command_permissions WHERE guild_id = ?1 AND command = ?2",
+        )?;
+        let rows = statement.query_map(params![guild_id as i64, command], read_permission_rule)?;
+        Ok(rows.collect::<rusqlite::Result<Vec<Option<PermissionRule>>>>()?.into_iter().flatten().collect())
+    })
+}
+
+fn read_permission_rule(row: &Row) -> rusqlite::Result<Option<PermissionRule>> {
+    let scope: String = row.get(1)?;
+    let Some(scope) = PermissionScope::parse(&scope) else {
+        return Ok(None);
+    };
+    let target_id: i64 = row.get(2)?;
+    Ok(Some(PermissionRule {
+        command: row.get(0)?,
+        scope,
+        target_id: target_id as u64,
+        allowed: row.get(3)?,
+    }))
+}
@@ -0,0 +1,127 @@
+use crate::{tasks::handle_errors::BotError, Data, Error};
+
+use super::{permission_store::{command_permission_rules, PermissionRule, PermissionScope}, quotas::QuotaUser};
+
+// The name used in permission rules for replies to mentions
+pub const CHAT_PERMISSION: &str = "chat";
+// The permissions command is never checked so an admin can always undo a rule that locks everyone out
+pub const PERMISSIONS_COMMAND: &str = "permissions";
+
+/*
+    Set as poise's command_check, this runs before every command
+    Subcommands use the rules of the command they belong to (so /quota set uses the rules for quota)
+*/
+pub async fn command_check(ctx: crate::Context<'_>) -> Result<bool, Error> {
+    let command_name = ctx.parent_commands().first().map_or(ctx.command().name.as_str(), |t| t.name.as_str());
+    if command_name == PERMISSIONS_COMMAND {
+        return Ok(true);
+    }
+    let quota_user = QuotaUser::from_context(ctx).await;
+    check_permission(ctx.data(), &quota_user, ctx.channel_id().get(), command_name)?;
+    Ok(true)
+}
+
+/*
+    Returns an error if the command (or chat replies) cannot be used by this user in this channel
+    Everything is allowed in DMs and in servers without any rules for the command
+*/
+pub fn check_permission(data: &Data, quota_user: &QuotaUser, channel_id: u64, command: &str) -> Result<(), Error> {
+    if quota_user.guild_id == 0 {
+        return Ok(());
+    }
+    let permission_rules = command_permission_rules(&data.database, quota_user.guild_id, command)?;
+    match denied_reason(&permission_rules, quota_user, channel_id) {
+        Some(reason) => Err(BotError::PermissionDenied(format!("{} {}", describe_command(command), reason))),
+        None => Ok(()),
+    }
+}
+
+/*
+    Works out if the rules for a command deny it, the channel and role rules both have to allow it
+        - Channels: a denied channel is blocked, if any channels are allowed then only those channels can be used
+        - Roles: a user with an allowed role can always use it, otherwise a denied role blocks it
+          and if any roles are allowed then only users with one of those roles can use it
+    Every member has the @everyone role (which has the server's ID), so denying @everyone and allowing a role limits it to that role
+*/
+fn denied_reason(permission_rules: &[PermissionRule], quota_user: &QuotaUser, channel_id: u64) -> Option<&'static str> {
+    let channel_rules: Vec<&PermissionRule> = permission_rules.iter().filter(|t| t.scope == PermissionScope::Channel).collect();
+    if channel_rules.iter().any(|t| !t.allowed && t.target_id == channel_id) {
+        return Some("is turned off in this channel");
+    }
+    let allowed_channels: Vec<u64> = channel_rules.iter().filter(|t| t.allowed).map(|t| t.target_id).collect();
+    if !allowed_channels.is_empty() && !allowed_channels.contains(&channel_id) {
+        return Some("can't be used in this channel");
+    }
+
+    let has_role = |role_id: u64| role_id == quota_user.guild_id || quota_user.role_ids.contains(&role_id);
+    let role_rules: Vec<&PermissionRule> = permission_rules.iter().filter(|t| t.scope == PermissionScope::Role).collect();
+    if role_rules.iter().any(|t| t.allowed && has_role(t.target_id)) {
+        return None;
+    }
+    if role_rules.iter().any(|t| !t.allowed && has_role(t.target_id)) {
+        return Some("is turned off for one of your roles");
+    }
+    if role_rules.iter().any(|t| t.allowed) {
+        return Some("needs a role you don't have");
+    }
+    None
+}
+
+fn describe_command(command: &str) -> String {
+    if command == CHAT_PERMISSION {
+        "Chatting with me".to_owned()
+    } else {
+        format!("The {} command", command)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GUILD_ID: u64 = 100;
+
+    fn rule(scope: PermissionScope, target_id: u64, allowed: bool) -> PermissionRule {
+        PermissionRule { command: "imagegen".to_owned(), scope, target_id, allowed }
+    }
+
+    fn user_with_roles(role_ids: &[u64]) -> QuotaUser {
+        QuotaUser { guild_id: GUILD_ID, user_id: 1, role_ids: role_ids.to_vec() }
+    }
+
+    #[test]
+    fn no_rules_allows_everything() {
+        assert_eq!(denied_reason(&[], &user_with_roles(&[]), 5), None);
+    }
+
+    #[test]
+    fn channel_rules_deny_and_limit_channels() {
+        let denied = [rule(PermissionScope::Channel, 5, false)];
+        assert!(denied_reason(&denied, &user_with_roles(&[]), 5).is_some());
+        assert_eq!(denied_reason(&denied, &user_with_roles(&[]), 6), None);
+
+        let allowed = [rule(PermissionScope::Channel, 5, true)];
+        assert_eq!(denied_reason(&allowed, &user_with_roles(&[]), 5), None);
+        assert!(denied_reason(&allowed, &user_with_roles(&[]), 6).is_some());
+    }
+
+    #[test]
+    fn allowed_role_overrides_denied_everyone() {
+        let rules = [rule(PermissionScope::Role, GUILD_ID, false), rule(PermissionScope::Role, 7, true)];
+        assert_eq!(denied_reason(&rules, &user_with_roles(&[7]), 5), None);
+        assert!(denied_reason(&rules, &user_with_roles(&[8]), 5).is_some());
+    }
+
+    #[test]
+    fn allowed_roles_limit_who_can_use_it() {
+        let rules = [rule(PermissionScope::Role, 7, true)];
+        assert_eq!(denied_reason(&rules, &user_with_roles(&[7, 8]), 5), None);
+        assert!(denied_reason(&rules, &user_with_roles(&[8]), 5).is_some());
+    }
+
+    #[test]
+    fn allowed_role_does_not_override_denied_channel() {
+        let rules = [rule(PermissionScope::Channel, 5, false), rule(PermissionScope::Role, 7, true)];
+        assert!(denied_reason(&rules, &user_with_roles(&[7]), 5).is_some());
+    }
+}
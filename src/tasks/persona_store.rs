@@ -0,0 +1,143 @@
+use rusqlite::{params, OptionalExtension, Row};
+
+use crate::Error;
+
+use super::database::Database;
+
+/*
+    A personality for the bot in a server
+    Anything left as None uses the value from text_generation in the config
+*/
+#[derive(Clone, Debug)]
+pub struct Persona {
+    pub guild_id: u64,
+    pub name: String,
+    pub system_prompt: String,
+    pub model: Option<String>,
+    pub temperature: Option<f32>,
+    pub max_tokens: Option<u16>,
+}
+
+/*
+    Where a persona is used, a channel of None is the default for the whole server
+*/
+#[derive(Clone, Debug)]
+pub struct PersonaAssignment {
+    pub channel_id: Option<u64>,
+    pub persona_name: String,
+}
+
+// Server wide assignments are stored with a channel ID of 0 as SQLite treats every NULL in a primary key as different
+const GUILD_DEFAULT_CHANNEL_ID: i64 = 0;
+
+/*
+    Creates the persona or replaces the persona with the same name in the server
+*/
+pub fn save_persona(database: &Database, persona: &Persona) -> Result<(), Error> {
+    database.run(|connection| connection.execute(
+        "INSERT OR REPLACE INTO personas (guild_id, name, system_prompt, model, temperature, max_tokens)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+        params![
+            persona.guild_id as i64,
+            persona.name,
+            persona.system_prompt,
+            persona.model,
+            persona.temperature.map(|t| t as f64),
+            persona.max_tokens.map(|t| t as i64)
+        ],
+    ))?;
+    Ok(())
+}
+
+pub fn get_persona(database: &Database, guild_id: u64, name: &str) -> Result<Option<Persona>, Error> {
+    database.run(|connection| connection.query_row(
+        "SELECT guild_id, name, system_prompt, model, temperature, max_tokens FROM personas WHERE guild_id = ?1 AND name = ?2",
+        params![guild_id as i64, name],
+        read_persona,
+    ).optional())
+}
+
+pub fn list_personas(database: &Database, guild_id: u64) -> Result<Vec<Persona>, Error> {
+    database.run(|connection| {
+        let mut statement = connection.prepare(
+            "SELECT guild_id, name, system_prompt, model, temperature, max_tokens FROM personas WHERE guild_id = ?1 ORDER BY name",
+        )?;
+        let rows = statement.query_map(params![guild_id as i64], read_persona)?;
+        rows.collect()
+    })
+}
+
+/*
+    Removes the persona along with everywhere it is assigned, returns false if there was no persona with that name
+*/
+pub fn delete_persona(database: &Database, guild_id: u64, name: &str) -> Result<bool, Error> {
+    database.run(|connection| {
+        connection.execute("DELETE FROM persona_assignments WHERE guild_id = ?1 AND persona_name = ?2", params![guild_id as i64, name])?;
+        let deleted_count = connection.execute("DELETE FROM personas WHERE guild_id = ?1 AND name = ?2", params![guild_id as i64, name])?;
+        Ok(deleted_count > 0)
+    })
+}
+
+pub fn assign_persona(database: &Database, guild_id: u64, channel_id: Option<u64>, name: &str) -> Result<(), Error> {
+    database.run(|connection| connection.execute(
+        "INSERT OR REPLACE INTO persona_assignments (guild_id, channel_id, persona_name) VALUES (?1, ?2, ?3)",
+        params![guild_id as i64, channel_id.map_or(GUILD_DEFAULT_CHANNEL_ID, |t| t as i64), name],
+    ))?;
+    Ok(())
+}
+
+/*
+    Returns false if nothing was assigned there
+*/
+pub fn unassign_persona(database: &Database, guild_id: u64, channel_id: Option<u64>) -> Result<bool, Error> {
+    let deleted_count = database.run(|connection| connection.execute(
+        "DELETE FROM persona_assignments WHERE guild_id = ?1 AND channel_id = ?2",
+        params![guild_id as i64, channel_id.map_or(GUILD_DEFAULT_CHANNEL_ID, |t| t as i64)],
+    ))?;
+    Ok(deleted_count > 0)
+}
+
+pub fn list_assignments(database: &Database, guild_id: u64) -> Result<Vec<PersonaAssignment>, Error> {
+    database.run(|connection| {
+        let mut statement = connection.prepare(
+            "SELECT channel_id, persona_name FROM persona_assignments WHERE guild_id = ?1 ORDER BY channel_id",
+        )?;
+        let rows = statement.query_map(params![guild_id as i64], |row| {
+            let channel_id: i64 = row.get(0)?;
+            Ok(PersonaAssignment {
+                channel_id: if channel_id == GUILD_DEFAULT_CHANNEL_ID { None } else { Some(channel_id as u64) },
+                persona_name: row.get(1)?,
+            })
+        })?;
+        rows.collect()
+    })
+}
+
+/*
+    The persona to use for a message, a persona assigned to the channel is used over the server default
+*/
+pub fn active_persona(database: &Database, guild_id: u64, channel_id: u64) -> Result<Option<Persona>, Error> {
+    database.run(|connection| connection.query_row(
+        "SELECT personas.guild_id, personas.name, personas.system_prompt, personas.model, personas.temperature, personas.max_tokens
+            FROM persona_assignments
+            JOIN personas ON personas.guild_id = persona_assignments.guild_id AND personas.name = persona_assignments.persona_name
+            WHERE persona_assignments.guild_id = ?1 AND persona_assignments.channel_id IN (?2, ?3)
+            ORDER BY persona_assignments.channel_id = ?3 ASC LIMIT 1",
+        params![guild_id as i64, channel_id as i64, GUILD_DEFAULT_CHANNEL_ID],
+        read_persona,
+    ).optional())
+}
+
+fn read_persona(row: &Row) -> rusqlite::Result<Persona> {
+    let guild_id: i64 = row.get(0)?;
+    let temperature: Option<f64> = row.get(4)?;
+    let max_tokens: Option<i64> = row.get(5)?;
+    Ok(Persona {
+        guild_id: guild_id as u64,
+        name: row.get(1)?,
+        system_prompt: row.get(2)?,
+        model: row.get(3)?,
+        temperature: temperature.map(|t| t as f32),
+        max_tokens: max_tokens.map(|t| t as u16),
+    })
+}
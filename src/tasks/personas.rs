@@ -0,0 +1,199 @@
+use poise::CreateReply;
+use serenity::all::{GuildChannel, GuildId};
+
+use crate::{tasks::handle_errors::BotError, Error};
+
+use super::{message_splitter::split_message, persona_store::{assign_persona, delete_persona, get_persona, list_assignments, list_personas, save_persona, unassign_persona, Persona}};
+
+// How much of a system prompt is shown when listing personas
+const PROMPT_PREVIEW_LENGTH: usize = 100;
+
+/*
+    Persona management, this needs the Manage Server permission
+    Discord hides the command from anyone without the permission and poise checks it again when the command is run
+*/
+#[poise::command(
+    slash_command,
+    guild_only,
+    subcommands("persona_create", "persona_delete", "persona_list", "persona_assign", "persona_unassign"),
+    subcommand_required,
+    default_member_permissions = "MANAGE_GUILD",
+    required_permissions = "MANAGE_GUILD"
+)]
+pub async fn persona(_ctx: crate::Context<'_>) -> Result<(), Error> {
+    Ok(())
+}
+
+/// Create a persona, or replace the persona with the same name
+#[poise::command(slash_command, guild_only, rename = "create", required_permissions = "MANAGE_GUILD")]
+async fn persona_create(
+    ctx: crate::Context<'_>,
+    #[description = "Name of the persona"]
+    #[max_length = 50]
+    name: String,
+    #[description = "The system prompt, this sets the personality of the bot"]
+    system_prompt: String,
+    #[description = "Model to use (default: the model in the bot's config)"]
+    model: Option<String>,
+    #[description = "Temperature, higher values are more random (default: the bot's config)"]
+    #[min = 0.0]
+    #[max = 2.0]
+    temperature: Option<f32>,
+    #[description = "The longest a reply can be in tokens (default: the bot's config)"]
+    #[min = 1]
+    #[max = 16384]
+    max_tokens: Option<u16>
+) -> Result<(), Error> {
+    let guild_id = command_guild_id(ctx)?;
+    let name = name.trim().to_owned();
+    if name.is_empty() {
+        return Err(BotError::UserInput("The persona name cannot be empty".to_owned()));
+    }
+
+    let persona = Persona {
+        guild_id: guild_id.get(),
+        name: name.clone(),
+        system_prompt,
+        model: model.map(|t| t.trim().to_owned()).filter(|t| !t.is_empty()),
+        temperature,
+        max_tokens,
+    };
+    save_persona(&ctx.data().database, &persona)?;
+
+    send_ephemeral(ctx, format!("The persona **{}** has been saved, use `/persona assign` to use it in this server or a channel", name)).await
+}
+
+/// Delete a persona, this also removes it from everywhere it is assigned
+#[poise::command(slash_command, guild_only, rename = "delete", required_permissions = "MANAGE_GUILD")]
+async fn persona_delete(
+    ctx: crate::Context<'_>,
+    #[description = "Name of the persona"]
+    #[autocomplete = "autocomplete_persona_name"]
+    name: String
+) -> Result<(), Error> {
+    let guild_id = command_guild_id(ctx)?;
+    let deleted = delete_persona(&ctx.data().database, guild_id.get(), &name)?;
+
+    if deleted {
+        send_ephemeral(ctx, format!("The persona **{}** has been deleted", name)).await
+    } else {
+        send_ephemeral(ctx, format!("There is no persona called **{}** in this server", name)).await
+    }
+}
+
+/// List the personas in this server and where they are used
+#[poise::command(slash_command, guild_only, rename = "list", required_permissions = "MANAGE_GUILD")]
+async fn persona_list(ctx: crate::Context<'_>) -> Result<(), Error> {
+    let guild_id = command_guild_id(ctx)?;
+    let database = &ctx.data().database;
+    let personas = list_personas(database, guild_id.get())?;
+    let assignments = list_assignments(database, guild_id.get())?;
+
+    if personas.is_empty() {
+        return send_ephemeral(ctx, "There are no personas in this server, use `/persona create` to make one".to_owned()).await;
+    }
+
+    let text_config = &ctx.data().config.text_generation;
+    let mut persona_list = String::new();
+    for persona in personas {
+        let used_in: Vec<String> = assignments.iter()
+            .filter(|assignment| assignment.persona_name == persona.name)
+            .map(|assignment| match assignment.channel_id {
+                Some(channel_id) => format!("<#{}>", channel_id),
+                None => "server default".to_owned(),
+            })
+            .collect();
+        let mut prompt_preview: String = persona.system_prompt.chars().take(PROMPT_PREVIEW_LENGTH).collect();
+        if persona.system_prompt.chars().count() > PROMPT_PREVIEW_LENGTH {
+            prompt_preview.push_str("...");
+        }
+
+        persona_list.push_str(&format!(
+            "**{}**\n> Model: {}\n> Temperature: {}\n> Max tokens: {}\n> Used in: {}\n> Prompt: {}\n\n",
+            persona.name,
+            persona.model.unwrap_or_else(|| format!("{} (default)", text_config.model)),
+            persona.temperature.map_or_else(|| format!("{} (default)", text_config.temperature), |t| t.to_string()),
+            persona.max_tokens.map_or_else(|| format!("{} (default)", text_config.max_tokens), |t| t.to_string()),
+            if used_in.is_empty() { "nowhere".to_owned() } else { used_in.join(", ") },
+            prompt_preview.replace('\n', " ")
+        ));
+    }
+
+    for persona_list_chunk in split_message(&persona_list, 1900) {
+        send_ephemeral(ctx, persona_list_chunk).await?;
+    }
+    Ok(())
+}
+
+/// Use a persona in a channel, or in the whole server if no channel is given
+#[poise::command(slash_command, guild_only, rename = "assign", required_permissions = "MANAGE_GUILD")]
+async fn persona_assign(
+    ctx: crate::Context<'_>,
+    #[description = "Name of the persona"]
+    #[autocomplete = "autocomplete_persona_name"]
+    name: String,
+    #[description = "Channel to use the persona in (default: the whole server)"]
+    #[channel_types("Text", "PublicThread", "PrivateThread", "Forum", "Voice")]
+    channel: Option<GuildChannel>
+) -> Result<(), Error> {
+    let guild_id = command_guild_id(ctx)?;
+    let database = &ctx.data().database;
+    if get_persona(database, guild_id.get(), &name)?.is_none() {
+        return Err(BotError::UserInput(format!("There is no persona called {} in this server", name)));
+    }
+
+    let channel_id = channel.map(|t| t.id.get());
+    assign_persona(database, guild_id.get(), channel_id, &name)?;
+
+    send_ephemeral(ctx, format!("The persona **{}** will now be used in {}", name, assignment_location(channel_id))).await
+}
+
+/// Stop using a persona in a channel, or remove the server default if no channel is given
+#[poise::command(slash_command, guild_only, rename = "unassign", required_permissions = "MANAGE_GUILD")]
+async fn persona_unassign(
+    ctx: crate::Context<'_>,
+    #[description = "Channel to stop using a persona in (default: the whole server)"]
+    #[channel_types("Text", "PublicThread", "PrivateThread", "Forum", "Voice")]
+    channel: Option<GuildChannel>
+) -> Result<(), Error> {
+    let guild_id = command_guild_id(ctx)?;
+    let channel_id = channel.map(|t| t.id.get());
+    let removed = unassign_persona(&ctx.data().database, guild_id.get(), channel_id)?;
+
+    if removed {
+        send_ephemeral(ctx, format!("No persona is assigned to {} now", assignment_location(channel_id))).await
+    } else {
+        send_ephemeral(ctx, format!("There was no persona assigned to {}", assignment_location(channel_id))).await
+    }
+}
+
+async fn autocomplete_persona_name(ctx: crate::Context<'_>, partial: &str) -> Vec<String> {
+    let Some(guild_id) = ctx.guild_id() else {
+        return Vec::new();
+    };
+    let partial = partial.to_lowercase();
+    list_personas(&ctx.data().database, guild_id.get())
+        .unwrap_or_default()
+        .into_iter()
+        .map(|persona| persona.name)
+        .filter(|name| name.to_lowercase().contains(&partial))
+        .take(25)
+        .collect()
+}
+
+// The commands are guild_only so this only fails if Discord sends something unexpected
+fn command_guild_id(ctx: crate::Context<'_>) -> Result<GuildId, Error> {
+    ctx.guild_id().ok_or(BotError::UserInput("Personas can only be used in a server".to_owned()))
+}
+
+fn assignment_location(channel_id: Option<u64>) -> String {
+    match channel_id {
+        Some(channel_id) => format!("<#{}>", channel_id),
+        None => "this server (unless a channel has its own persona)".to_owned(),
+    }
+}
+
+async fn send_ephemeral(ctx: crate::Context<'_>, content: String) -> Result<(), Error> {
+    ctx.send(CreateReply::default().content(content).ephemeral(true)).await?;
+    Ok(())
+}
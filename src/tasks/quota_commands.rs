@@ -0,0 +1,204 @@
+use poise::{ChoiceParameter, CreateReply};
+use serenity::all::{GuildId, Role, User};
+
+use crate::{tasks::handle_errors::BotError, Error};
+
+use super::{message_splitter::split_message, quota_store::{clear_quota_limit, delete_quota_override, get_daily_usage, list_quota_overrides, reset_daily_usage, save_quota_override, QuotaLimit, QuotaOverride, QuotaScope}, quotas::{current_day, effective_limits, format_wait, seconds_until_reset, QuotaUser}};
+
+/*
+    Quota management, this needs the Manage Server permission
+    Limits set here replace the ones in the config for this server, a role or a user
+*/
+#[poise::command(
+    slash_command,
+    guild_only,
+    subcommands("quota_set", "quota_clear", "quota_show", "quota_list", "quota_reset"),
+    subcommand_required,
+    default_member_permissions = "MANAGE_GUILD",
+    required_permissions = "MANAGE_GUILD"
+)]
+pub async fn quota(_ctx: crate::Context<'_>) -> Result<(), Error> {
+    Ok(())
+}
+
+/// Set limits for the server, a role or a user, limits left out are inherited
+#[poise::command(slash_command, guild_only, rename = "set", required_permissions = "MANAGE_GUILD")]
+#[allow(clippy::too_many_arguments)]
+async fn quota_set(
+    ctx: crate::Context<'_>,
+    #[description = "User to set the limits for (default: the whole server)"]
+    user: Option<User>,
+    #[description = "Role to set the limits for (default: the whole server)"]
+    role: Option<Role>,
+    #[description = "Mentions and commands per minute, 0 is unlimited"]
+    requests_per_minute: Option<u32>,
+    #[description = "Images per day, 0 is unlimited"]
+    daily_images: Option<u32>,
+    #[description = "Text reply tokens per day, 0 is unlimited"]
+    daily_tokens: Option<u32>,
+    #[description = "TTS characters per day, 0 is unlimited"]
+    daily_tts_characters: Option<u32>
+) -> Result<(), Error> {
+    let guild_id = command_guild_id(ctx)?;
+    let (scope, target_id) = quota_target(user.as_ref(), role.as_ref())?;
+    if requests_per_minute.is_none() && daily_images.is_none() && daily_tokens.is_none() && daily_tts_characters.is_none() {
+        return Err(BotError::UserInput("Give at least one limit to set".to_owned()));
+    }
+
+    let quota_override = QuotaOverride {
+        scope,
+        target_id,
+        requests_per_minute: requests_per_minute.map(u64::from),
+        daily_images: daily_images.map(u64::from),
+        daily_tokens: daily_tokens.map(u64::from),
+        daily_tts_characters: daily_tts_characters.map(u64::from),
+    };
+    let saved_override = save_quota_override(&ctx.data().database, guild_id.get(), &quota_override)?;
+
+    send_ephemeral(ctx, format!("The limits for {} are now:\n{}", target_name(scope, target_id), format_override(&saved_override))).await
+}
+
+/// Remove the limits set for the server, a role or a user, removed limits are inherited again
+#[poise::command(slash_command, guild_only, rename = "clear", required_permissions = "MANAGE_GUILD")]
+async fn quota_clear(
+    ctx: crate::Context<'_>,
+    #[description = "User to remove the limits for (default: the whole server)"]
+    user: Option<User>,
+    #[description = "Role to remove the limits for (default: the whole server)"]
+    role: Option<Role>,
+    #[description = "Only remove this limit (default: all of them)"]
+    limit: Option<QuotaLimit>
+) -> Result<(), Error> {
+    let guild_id = command_guild_id(ctx)?;
+    let (scope, target_id) = quota_target(user.as_ref(), role.as_ref())?;
+    if let Some(limit) = limit {
+        let cleared = clear_quota_limit(&ctx.data().database, guild_id.get(), scope, target_id, limit)?;
+        return match cleared {
+            true => send_ephemeral(ctx, format!("The {} limit for {} has been removed", limit.name().to_lowercase(), target_name(scope, target_id))).await,
+            false => send_ephemeral(ctx, format!("There was no {} limit set for {}", limit.name().to_lowercase(), target_name(scope, target_id))).await,
+        };
+    }
+    let deleted = delete_quota_override(&ctx.data().database, guild_id.get(), scope, target_id)?;
+
+    if deleted {
+        send_ephemeral(ctx, format!("The limits for {} have been removed", target_name(scope, target_id))).await
+    } else {
+        send_ephemeral(ctx, format!("There were no limits set for {}", target_name(scope, target_id))).await
+    }
+}
+
+/// Show the limits that apply to a user and how much they have used today
+#[poise::command(slash_command, guild_only, rename = "show", required_permissions = "MANAGE_GUILD")]
+async fn quota_show(
+    ctx: crate::Context<'_>,
+    #[description = "User to show (default: you)"]
+    user: Option<User>
+) -> Result<(), Error> {
+    let guild_id = command_guild_id(ctx)?;
+    let user = user.unwrap_or_else(|| ctx.author().clone());
+    // Users who have left the server only get the server and user limits
+    let role_ids = match guild_id.member(ctx, user.id).await {
+        Ok(member) => member.roles.iter().map(|t| t.get()).collect(),
+        Err(_) => Vec::new(),
+    };
+    let quota_user = QuotaUser { guild_id: guild_id.get(), user_id: user.id.get(), role_ids };
+
+    let limits = effective_limits(ctx.data(), &quota_user)?;
+    let usage = get_daily_usage(&ctx.data().database, guild_id.get(), user.id.get(), current_day())?;
+
+    send_ephemeral(ctx, format!(
+        "Quotas for <@{}>, the daily quotas reset in {}\n> Requests per minute: {}\n> Images today: {} of {}\n> Tokens today: {} of {}\n> TTS characters today: {} of {}",
+        user.id,
+        format_wait(seconds_until_reset()),
+        format_limit(limits.requests_per_minute),
+        usage.images,
+        format_limit(limits.daily_images),
+        usage.tokens,
+        format_limit(limits.daily_tokens),
+        usage.tts_characters,
+        format_limit(limits.daily_tts_characters)
+    )).await
+}
+
+/// List the limits set in this server
+#[poise::command(slash_command, guild_only, rename = "list", required_permissions = "MANAGE_GUILD")]
+async fn quota_list(ctx: crate::Context<'_>) -> Result<(), Error> {
+    let guild_id = command_guild_id(ctx)?;
+    let quota_overrides = list_quota_overrides(&ctx.data().database, guild_id.get())?;
+
+    let quota_config = &ctx.data().config.quotas;
+    let mut quota_list = format!(
+        "**Default limits (from the bot's config)**\n> Requests per minute: {}\n> Images per day: {}\n> Tokens per day: {}\n> TTS characters per day: {}\n\n",
+        format_limit(quota_config.requests_per_minute),
+        format_limit(quota_config.daily_images),
+        format_limit(quota_config.daily_tokens),
+        format_limit(quota_config.daily_tts_characters)
+    );
+    if quota_overrides.is_empty() {
+        quota_list.push_str("No limits have been set in this server, use `/quota set` to add some");
+    }
+    for quota_override in quota_overrides {
+        quota_list.push_str(&format!("**{}**\n{}\n\n", target_name(quota_override.scope, quota_override.target_id), format_override(&quota_override)));
+    }
+
+    for quota_list_chunk in split_message(&quota_list, 1900) {
+        send_ephemeral(ctx, quota_list_chunk).await?;
+    }
+    Ok(())
+}
+
+/// Reset a user's usage for today, this also clears their requests from the last minute
+#[poise::command(slash_command, guild_only, rename = "reset", required_permissions = "MANAGE_GUILD")]
+async fn quota_reset(
+    ctx: crate::Context<'_>,
+    #[description = "User to reset"]
+    user: User
+) -> Result<(), Error> {
+    let guild_id = command_guild_id(ctx)?;
+    reset_daily_usage(&ctx.data().database, guild_id.get(), user.id.get(), current_day())?;
+    ctx.data().rate_limiter.reset(guild_id.get(), user.id.get());
+
+    send_ephemeral(ctx, format!("The usage for <@{}> has been reset for today", user.id)).await
+}
+
+fn quota_target(user: Option<&User>, role: Option<&Role>) -> Result<(QuotaScope, u64), Error> {
+    match (user, role) {
+        (Some(_), Some(_)) => Err(BotError::UserInput("Give either a user or a role, not both".to_owned())),
+        (Some(user), None) => Ok((QuotaScope::User, user.id.get())),
+        (None, Some(role)) => Ok((QuotaScope::Role, role.id.get())),
+        (None, None) => Ok((QuotaScope::Guild, 0)),
+    }
+}
+
+fn target_name(scope: QuotaScope, target_id: u64) -> String {
+    match scope {
+        QuotaScope::Guild => "this server".to_owned(),
+        QuotaScope::Role => format!("<@&{}>", target_id),
+        QuotaScope::User => format!("<@{}>", target_id),
+    }
+}
+
+fn format_override(quota_override: &QuotaOverride) -> String {
+    let format_override_limit = |limit: Option<u64>| limit.map_or_else(|| "not set".to_owned(), format_limit);
+    format!(
+        "> Requests per minute: {}\n> Images per day: {}\n> Tokens per day: {}\n> TTS characters per day: {}",
+        format_override_limit(quota_override.requests_per_minute),
+        format_override_limit(quota_override.daily_images),
+        format_override_limit(quota_override.daily_tokens),
+        format_override_limit(quota_override.daily_tts_characters)
+    )
+}
+
+fn format_limit(limit: u64) -> String {
+    if limit == 0 { "unlimited".to_owned() } else { limit.to_string() }
+}
+
+// The commands are guild_only so this only fails if Discord sends something unexpected
+fn command_guild_id(ctx: crate::Context<'_>) -> Result<GuildId, Error> {
+    ctx.guild_id().ok_or(BotError::UserInput("Quotas can only be managed in a server".to_owned()))
+}
+
+async fn send_ephemeral(ctx: crate::Context<'_>, content: String) -> Result<(), Error> {
+    ctx.send(CreateReply::default().content(content).ephemeral(true)).await?;
+    Ok(())
+}
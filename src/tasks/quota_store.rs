@@ -0,0 +1,288 @@
+use rusqlite::{params, OptionalExtension, Row};
+
+use crate::Error;
+
+use super::database::Database;
+
+/*
+    What a quota override applies to, the target ID is the role or user ID (0 for the whole server)
+*/
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum QuotaScope {
+    Guild,
+    Role,
+    User,
+}
+
+impl QuotaScope {
+    fn as_str(&self) -> &'static str {
+        match self {
+            QuotaScope::Guild => "guild",
+            QuotaScope::Role => "role",
+            QuotaScope::User => "user",
+        }
+    }
+
+    fn parse(scope: &str) -> Option<QuotaScope> {
+        match scope {
+            "guild" => Some(QuotaScope::Guild),
+            "role" => Some(QuotaScope::Role),
+            "user" => Some(QuotaScope::User),
+            _ => None,
+        }
+    }
+}
+
+/*
+    Limits set by a server admin, a limit of None uses the limit from the level above (user, role, server then config)
+    and a limit of 0 is unlimited
+*/
+#[derive(Clone, Debug)]
+pub struct QuotaOverride {
+    pub scope: QuotaScope,
+    pub target_id: u64,
+    pub requests_per_minute: Option<u64>,
+    pub daily_images: Option<u64>,
+    pub daily_tokens: Option<u64>,
+    pub daily_tts_characters: Option<u64>,
+}
+
+/*
+    How much a user has used in a server on one day
+*/
+#[derive(Clone, Copy, Debug, Default)]
+pub struct DailyUsage {
+    pub images: u64,
+    pub tokens: u64,
+    pub tts_characters: u64,
+}
+
+// The kinds of daily usage that have a quota, each is a column in quota_usage
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum QuotaKind {
+    Images,
+    Tokens,
+    TtsCharacters,
+}
+
+impl QuotaKind {
+    fn as_str(&self) -> &'static str {
+        match self {
+            QuotaKind::Images => "images",
+            QuotaKind::Tokens => "tokens",
+            QuotaKind::TtsCharacters => "tts_characters",
+        }
+    }
+}
+
+/*
+    The limits an override can set, each is a column in quota_overrides
+*/
+#[derive(Clone, Copy, Debug, PartialEq, poise::ChoiceParameter)]
+pub enum QuotaLimit {
+    #[name = "Requests per minute"]
+    RequestsPerMinute,
+    #[name = "Images per day"]
+    DailyImages,
+    #[name = "Tokens per day"]
+    DailyTokens,
+    #[name = "TTS characters per day"]
+    DailyTtsCharacters,
+}
+
+impl QuotaLimit {
+    fn column_name(&self) -> &'static str {
+        match self {
+            QuotaLimit::RequestsPerMinute => "requests_per_minute",
+            QuotaLimit::DailyImages => "daily_images",
+            QuotaLimit::DailyTokens => "daily_tokens",
+            QuotaLimit::DailyTtsCharacters => "daily_tts_characters",
+        }
+    }
+}
+
+/*
+    Creates the override or adds to the override for the same server, role or user
+    Limits that are None keep what was set before, clear_quota_limit removes a single limit
+    Returns the override as it is now saved
+*/
+pub fn save_quota_override(database: &Database, guild_id: u64, quota_override: &QuotaOverride) -> Result<QuotaOverride, Error> {
+    database.run(|connection| connection.execute(
+        "INSERT INTO quota_overrides (guild_id, scope, target_id, requests_per_minute, daily_images, daily_tokens, daily_tts_characters)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)
+            ON CONFLICT (guild_id, scope, target_id) DO UPDATE SET
+                requests_per_minute = COALESCE(excluded.requests_per_minute, requests_per_minute),
+                daily_images = COALESCE(excluded.daily_images, daily_images),
+                daily_tokens = COALESCE(excluded.daily_tokens, daily_tokens),
+                daily_tts_characters = COALESCE(excluded.daily_tts_characters, daily_tts_characters)",
+        params![
+            guild_id as i64,
+            quota_override.scope.as_str(),
+            quota_override.target_id as i64,
+            quota_override.requests_per_minute.map(|t| t as i64),
+            quota_override.daily_images.map(|t| t as i64),
+            quota_override.daily_tokens.map(|t| t as i64),
+            quota_override.daily_tts_characters.map(|t| t as i64)
+        ],
+    ))?;
+    get_quota_override(database, guild_id, quota_override.scope, quota_override.target_id)?
+        .ok_or_else(|| "The quota override was not saved".into())
+}
+
+pub fn get_quota_override(database: &Database, guild_id: u64, scope: QuotaScope, target_id: u64) -> Result<Option<QuotaOverride>, Error> {
+    let quota_override = database.run(|connection| connection.query_row(
+        "SELECT scope, target_id, requests_per_minute, daily_images, daily_tokens, daily_tts_characters
+            FROM quota_overrides WHERE guild_id = ?1 AND scope = ?2 AND target_id = ?3",
+        params![guild_id as i64, scope.as_str(), target_id as i64],
+        read_quota_override,
+    ).optional())?;
+    Ok(quota_override.flatten())
+}
+
+/*
+    Removes one limit from an override so it is inherited again, the override is removed once it has no limits left
+    Returns false if the limit was not set
+*/
+pub fn clear_quota_limit(database: &Database, guild_id: u64, scope: QuotaScope, target_id: u64, limit: QuotaLimit) -> Result<bool, Error> {
+    let column_name = limit.column_name();
+    database.run(|connection| {
+        let cleared_count = connection.execute(
+            &format!("UPDATE quota_overrides SET {column_name} = NULL WHERE guild_id = ?1 AND scope = ?2 AND target_id = ?3 AND {column_name} IS NOT NULL"),
+            params![guild_id as i64, scope.as_str(), target_id as i64],
+        )?;
+        connection.execute(
+            "DELETE FROM quota_overrides WHERE guild_id = ?1 AND scope = ?2 AND target_id = ?3
+                AND requests_per_minute IS NULL AND daily_images IS NULL AND daily_tokens IS NULL AND daily_tts_characters IS NULL",
+            params![guild_id as i64, scope.as_str(), target_id as i64],
+        )?;
+        Ok(cleared_count > 0)
+    })
+}
+
+/*
+    Returns false if there was no override to remove
+*/
+pub fn delete_quota_override(database: &Database, guild_id: u64, scope: QuotaScope, target_id: u64) -> Result<bool, Error> {
+    let deleted_count = database.run(|connection| connection.execute(
+        "DELETE FROM quota_overrides WHERE guild_id = ?1 AND scope = ?2 AND target_id = ?3",
+        params![guild_id as i64, scope.as_str(), target_id as i64],
+    ))?;
+    Ok(deleted_count > 0)
+}
+
+pub fn list_quota_overrides(database: &Database, guild_id: u64) -> Result<Vec<QuotaOverride>, Error> {
+    database.run(|connection| {
+        let mut statement = connection.prepare(
+            "SELECT scope, target_id, requests_per_minute, daily_images, daily_tokens, daily_tts_characters
+                FROM quota_overrides WHERE guild_id = ?1 ORDER BY scope, target_id",
+        )?;
+        let rows = statement.query_map(params![guild_id as i64], read_quota_override)?;
+        // Rows with a scope this version does not know about are skipped
+        Ok(rows.collect::<rusqlite::Result<Vec<Option<QuotaOverride>>>>()?.into_iter().flatten().collect())
+    })
+}
+
+pub fn get_daily_usage(database: &Database, guild_id: u64, user_id: u64, day: i64) -> Result<DailyUsage, Error> {
+    let usage = database.run(|connection| connection.query_row(
+        "SELECT images, tokens, tts_characters FROM quota_usage WHERE guild_id = ?1 AND user_id = ?2 AND day = ?3",
+        params![guild_id as i64, user_id as i64, day],
+        |row| {
+            let images: i64 = row.get(0)?;
+            let tokens: i64 = row.get(1)?;
+            let tts_characters: i64 = row.get(2)?;
+            Ok(DailyUsage { images: images as u64, tokens: tokens as u64, tts_characters: tts_characters as u64 })
+        },
+    ).optional())?;
+    Ok(usage.unwrap_or_default())
+}
+
+pub fn add_daily_usage(database: &Database, guild_id: u64, user_id: u64, day: i64, kind: QuotaKind, amount: u64) -> Result<(), Error> {
+    let column_name = kind.as_str();
+    database.run(|connection| connection.execute(
+        &format!(
+            "INSERT INTO quota_usage (guild_id, user_id, day, {column_name}) VALUES (?1, ?2, ?3, ?4)
+                ON CONFLICT (guild_id, user_id, day) DO UPDATE SET {column_name} = {column_name} + excluded.{column_name}"
+        ),
+        params![guild_id as i64, user_id as i64, day, amount as i64],
+    ))?;
+    Ok(())
+}
+
+/*
+    Returns false if the user had not used anything that day
+*/
+pub fn reset_daily_usage(database: &Database, guild_id: u64, user_id: u64, day: i64) -> Result<bool, Error> {
+    let deleted_count = database.run(|connection| connection.execute(
+        "DELETE FROM quota_usage WHERE guild_id = ?1 AND user_id = ?2 AND day = ?3",
+        params![guild_id as i64, user_id as i64, day],
+    ))?;
+    Ok(deleted_count > 0)
+}
+
+/*
+    Removes the usage from before the given day, only the current day is used for quotas
+*/
+pub fn prune_daily_usage(database: &Database, before_day: i64) -> Result<usize, Error> {
+    database.run(|connection| connection.execute("DELETE FROM quota_usage WHERE day < ?1", params![before_day]))
+}
+
+fn read_quota_override(row: &Row) -> rusqlite::Result<Option<QuotaOverride>> {
+    let scope: String = row.get(0)?;
+    let Some(scope) = QuotaScope::parse(&scope) else {
+        return Ok(None);
+    };
+    let target_id: i64 = row.get(1)?;
+    let read_limit = |index: usize| -> rusqlite::Result<Option<u64>> {
+        let limit: Option<i64> = row.get(index)?;
+        Ok(limit.map(|t| t as u64))
+    };
+    Ok(Some(QuotaOverride {
+        scope,
+        target_id: target_id as u64,
+        requests_per_minute: read_limit(2)?,
+        daily_images: read_limit(3)?,
+        daily_tokens: read_limit(4)?,
+        daily_tts_characters: read_limit(5)?,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::*;
+
+    const GUILD_ID: u64 = 100;
+
+    fn user_override(daily_images: Option<u64>, daily_tokens: Option<u64>) -> QuotaOverride {
+        QuotaOverride { scope: QuotaScope::User, target_id: 1, requests_per_minute: None, daily_images, daily_tokens, daily_tts_characters: None }
+    }
+
+    #[test]
+    fn setting_a_limit_keeps_the_others() {
+        let database = Database::open(Path::new(":memory:")).unwrap();
+        save_quota_override(&database, GUILD_ID, &user_override(None, Some(5000))).unwrap();
+        let saved_override = save_quota_override(&database, GUILD_ID, &user_override(Some(50), None)).unwrap();
+        assert_eq!(saved_override.daily_images, Some(50));
+        assert_eq!(saved_override.daily_tokens, Some(5000));
+
+        // 0 is unlimited, it is a limit that replaces the one before
+        let saved_override = save_quota_override(&database, GUILD_ID, &user_override(None, Some(0))).unwrap();
+        assert_eq!(saved_override.daily_tokens, Some(0));
+    }
+
+    #[test]
+    fn clearing_a_limit_inherits_it_again() {
+        let database = Database::open(Path::new(":memory:")).unwrap();
+        save_quota_override(&database, GUILD_ID, &user_override(Some(50), Some(5000))).unwrap();
+
+        assert!(clear_quota_limit(&database, GUILD_ID, QuotaScope::User, 1, QuotaLimit::DailyTokens).unwrap());
+        assert!(!clear_quota_limit(&database, GUILD_ID, QuotaScope::User, 1, QuotaLimit::DailyTokens).unwrap());
+        let saved_override = get_quota_override(&database, GUILD_ID, QuotaScope::User, 1).unwrap().unwrap();
+        assert_eq!((saved_override.daily_images, saved_override.daily_tokens), (Some(50), None));
+
+        // The override goes once its last limit is cleared
+        assert!(clear_quota_limit(&database, GUILD_ID, QuotaScope::User, 1, QuotaLimit::DailyImages).unwrap());
+        assert!(get_quota_override(&database, GUILD_ID, QuotaScope::User, 1).unwrap().is_none());
+    }
+}
@@ -0,0 +1,325 @@
+use std::{collections::{HashMap, VecDeque}, sync::Mutex, time::{Duration, Instant}};
+
+use serenity::all::{ComponentInteraction, Message, Timestamp};
+use tracing::warn;
+
+use crate::{tasks::handle_errors::BotError, Data, Error};
+
+use super::{config::QuotaConfig, quota_store::{add_daily_usage, get_daily_usage, list_quota_overrides, QuotaKind, QuotaOverride, QuotaScope}};
+
+const RATE_LIMIT_WINDOW: Duration = Duration::from_secs(60);
+const SECONDS_PER_DAY: i64 = 24 * 60 * 60;
+
+/*
+    Who a request is for, quotas are per user in each server and roles can have their own limits
+    DMs use a guild ID of 0 so they only get the limits from the config
+*/
+pub struct QuotaUser {
+    pub guild_id: u64,
+    pub user_id: u64,
+    pub role_ids: Vec<u64>,
+}
+
+impl QuotaUser {
+    pub fn from_message(msg: &Message) -> QuotaUser {
+        QuotaUser {
+            guild_id: msg.guild_id.map_or(0, |t| t.get()),
+            user_id: msg.author.id.get(),
+            role_ids: msg.member.as_ref().map(|member| member.roles.iter().map(|t| t.get()).collect()).unwrap_or_default(),
+        }
+    }
+
+    pub fn from_interaction(interaction: &ComponentInteraction) -> QuotaUser {
+        QuotaUser {
+            guild_id: interaction.guild_id.map_or(0, |t| t.get()),
+            user_id: interaction.user.id.get(),
+            role_ids: interaction.member.as_ref().map(|member| member.roles.iter().map(|t| t.get()).collect()).unwrap_or_default(),
+        }
+    }
+
+    pub async fn from_context(ctx: crate::Context<'_>) -> QuotaUser {
+        let role_ids = match ctx.author_member().await {
+            Some(member) => member.roles.iter().map(|t| t.get()).collect(),
+            None => Vec::new(),
+        };
+        QuotaUser {
+            guild_id: ctx.guild_id().map_or(0, |t| t.get()),
+            user_id: ctx.author().id.get(),
+            role_ids,
+        }
+    }
+}
+
+/*
+    The limits that apply to a user, 0 is unlimited
+*/
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct QuotaLimits {
+    pub requests_per_minute: u64,
+    pub daily_images: u64,
+    pub daily_tokens: u64,
+    pub daily_tts_characters: u64,
+}
+
+impl From<&QuotaConfig> for QuotaLimits {
+    fn from(quota_config: &QuotaConfig) -> Self {
+        QuotaLimits {
+            requests_per_minute: quota_config.requests_per_minute,
+            daily_images: quota_config.daily_images,
+            daily_tokens: quota_config.daily_tokens,
+            daily_tts_characters: quota_config.daily_tts_characters,
+        }
+    }
+}
+
+impl QuotaLimits {
+    pub fn daily_limit(&self, kind: QuotaKind) -> u64 {
+        match kind {
+            QuotaKind::Images => self.daily_images,
+            QuotaKind::Tokens => self.daily_tokens,
+            QuotaKind::TtsCharacters => self.daily_tts_characters,
+        }
+    }
+
+    /*
+        Applies the overrides from one level, the user and server have one override each but a user can have many roles
+        When roles disagree the most generous limit is used so giving someone a role never lowers their limits
+    */
+    fn apply(&mut self, overrides: &[&QuotaOverride]) {
+        apply_limit(&mut self.requests_per_minute, overrides.iter().filter_map(|t| t.requests_per_minute));
+        apply_limit(&mut self.daily_images, overrides.iter().filter_map(|t| t.daily_images));
+        apply_limit(&mut self.daily_tokens, overrides.iter().filter_map(|t| t.daily_tokens));
+        apply_limit(&mut self.daily_tts_characters, overrides.iter().filter_map(|t| t.daily_tts_characters));
+    }
+}
+
+fn apply_limit(limit: &mut u64, override_limits: impl Iterator<Item = u64>) {
+    let most_generous = override_limits.reduce(|a, b| if a == 0 || b == 0 { 0 } else { a.max(b) });
+    if let Some(t) = most_generous {
+        *limit = t;
+    }
+}
+
+/*
+    Works out the limits for a user, each level replaces the one before it
+        - The config
+        - An override for the server
+        - Overrides for any of the user's roles
+        - An override for the user
+*/
+pub fn effective_limits(data: &Data, quota_user: &QuotaUser) -> Result<QuotaLimits, Error> {
+    if quota_user.guild_id == 0 {
+        return Ok(QuotaLimits::from(&data.config.quotas));
+    }
+    let overrides = list_quota_overrides(&data.database, quota_user.guild_id)?;
+    Ok(limits_with_overrides(&data.config.quotas, &overrides, quota_user))
+}
+
+fn limits_with_overrides(quota_config: &QuotaConfig, overrides: &[QuotaOverride], quota_user: &QuotaUser) -> QuotaLimits {
+    let mut limits = QuotaLimits::from(quota_config);
+    let overrides_for = |scope: QuotaScope, target_ids: &[u64]| -> Vec<&QuotaOverride> {
+        overrides.iter().filter(|t| t.scope == scope && target_ids.contains(&t.target_id)).collect()
+    };
+    limits.apply(&overrides_for(QuotaScope::Guild, &[0]));
+    limits.apply(&overrides_for(QuotaScope::Role, &quota_user.role_ids));
+    limits.apply(&overrides_for(QuotaScope::User, &[quota_user.user_id]));
+    limits
+}
+
+/*
+    Keeps the time of each user's recent requests to enforce requests_per_minute
+    This is kept in memory, so restarting the bot clears it
+*/
+#[derive(Default)]
+pub struct RateLimiter {
+    recent_requests: Mutex<HashMap<(u64, u64), VecDeque<Instant>>>,
+}
+
+impl RateLimiter {
+    /*
+        Counts the request if the user is under the limit, otherwise returns how long until they can make another
+    */
+    fn try_request(&self, quota_user: &QuotaUser, limit: u64) -> Result<(), Duration> {
+        self.try_request_at(quota_user, limit, Instant::now())
+    }
+
+    fn try_request_at(&self, quota_user: &QuotaUser, limit: u64, now: Instant) -> Result<(), Duration> {
+        let Ok(mut recent_requests) = self.recent_requests.lock() else {
+            // A poisoned lock only means another request panicked while holding it, the limit is skipped rather than blocking everyone
+            return Ok(());
+        };
+
+        // Users who have not made a request in the last minute are dropped so the map does not keep growing
+        if recent_requests.len() > 1000 {
+            recent_requests.retain(|_, times| times.back().is_some_and(|t| now.duration_since(*t) < RATE_LIMIT_WINDOW));
+        }
+
+        let request_times = recent_requests.entry((quota_user.guild_id, quota_user.user_id)).or_default();
+        while request_times.front().is_some_and(|t| now.duration_since(*t) >= RATE_LIMIT_WINDOW) {
+            request_times.pop_front();
+        }
+        if request_times.len() as u64 >= limit {
+            let oldest = request_times.front().copied().unwrap_or(now);
+            return Err(RATE_LIMIT_WINDOW.saturating_sub(now.duration_since(oldest)));
+        }
+        request_times.push_back(now);
+        Ok(())
+    }
+
+    pub fn reset(&self, guild_id: u64, user_id: u64) {
+        if let Ok(mut recent_requests) = self.recent_requests.lock() {
+            recent_requests.remove(&(guild_id, user_id));
+        }
+    }
+}
+
+/*
+    Called at the start of every request that uses a backend, this checks (and counts) the request against requests_per_minute
+    The limits are returned so any daily quotas can be checked without looking them up again
+*/
+pub fn start_request(data: &Data, quota_user: &QuotaUser) -> Result<QuotaLimits, Error> {
+    let limits = effective_limits(data, quota_user)?;
+    if limits.requests_per_minute > 0 {
+        data.rate_limiter.try_request(quota_user, limits.requests_per_minute)
+            .map_err(|wait| BotError::QuotaExceeded(format!(
+                "You've hit your limit of {} requests per minute, try again in {}",
+                limits.requests_per_minute,
+                format_wait(wait.as_secs().max(1))
+            )))?;
+    }
+    Ok(limits)
+}
+
+/*
+    Checks that the user has enough of their daily quota left for the amount they are about to use
+    When the amount is not known beforehand (tokens for a reply) an amount of 0 only checks that some is left
+*/
+pub fn check_daily_quota(data: &Data, quota_user: &QuotaUser, limits: &QuotaLimits, kind: QuotaKind, amount: u64) -> Result<(), Error> {
+    let limit = limits.daily_limit(kind);
+    if limit == 0 {
+        return Ok(());
+    }
+
+    let usage = get_daily_usage(&data.database, quota_user.guild_id, quota_user.user_id, current_day())?;
+    let used = match kind {
+        QuotaKind::Images => usage.images,
+        QuotaKind::Tokens => usage.tokens,
+        QuotaKind::TtsCharacters => usage.tts_characters,
+    };
+    if used + amount.max(1) <= limit {
+        return Ok(());
+    }
+
+    let remaining = limit.saturating_sub(used);
+    let reset_in = format_wait(seconds_until_reset());
+    Err(BotError::QuotaExceeded(match kind {
+        QuotaKind::Images if remaining > 0 => format!("This would make {} images but you only have {} of your {} daily images left, it resets in {}", amount, remaining, limit, reset_in),
+        QuotaKind::Images => format!("You've hit your limit of {} images per day, it resets in {}", limit, reset_in),
+        QuotaKind::Tokens if remaining > 0 => format!("This conversation is about {} tokens but you only have {} of your {} daily tokens left, it resets in {}", amount, remaining, limit, reset_in),
+        QuotaKind::Tokens => format!("You've hit your limit of {} tokens per day, it resets in {}", limit, reset_in),
+        QuotaKind::TtsCharacters if remaining > 0 => format!("This text is {} characters but you only have {} of your {} daily TTS characters left, it resets in {}", amount, remaining, limit, reset_in),
+        QuotaKind::TtsCharacters => format!("You've hit your limit of {} TTS characters per day, it resets in {}", limit, reset_in),
+    }))
+}
+
+/*
+    Adds to the user's usage for today once a request has finished
+    This is always recorded (even without a limit) so a limit added later applies to what was already used that day
+*/
+pub fn record_daily_usage(data: &Data, quota_user: &QuotaUser, kind: QuotaKind, amount: u64) {
+    if amount == 0 {
+        return;
+    }
+    if let Err(e) = add_daily_usage(&data.database, quota_user.guild_id, quota_user.user_id, current_day(), kind, amount) {
+        warn!("Unable to record quota usage: {}", e);
+    }
+}
+
+// Days since the Unix epoch, daily quotas reset at midnight UTC
+pub fn current_day() -> i64 {
+    Timestamp::now().unix_timestamp().div_euclid(SECONDS_PER_DAY)
+}
+
+pub fn seconds_until_reset() -> u64 {
+    (SECONDS_PER_DAY - Timestamp::now().unix_timestamp().rem_euclid(SECONDS_PER_DAY)) as u64
+}
+
+/*
+    A short human readable wait, such as 45s, 12m 5s or 3h 20m
+*/
+pub fn format_wait(seconds: u64) -> String {
+    let hours = seconds / 3600;
+    let minutes = (seconds % 3600) / 60;
+    let seconds = seconds % 60;
+    if hours > 0 {
+        format!("{}h {}m", hours, minutes)
+    } else if minutes > 0 {
+        format!("{}m {}s", minutes, seconds)
+    } else {
+        format!("{}s", seconds)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GUILD_ID: u64 = 100;
+
+    fn quota_config() -> QuotaConfig {
+        QuotaConfig { requests_per_minute: 5, daily_images: 10, daily_tokens: 1000, daily_tts_characters: 500 }
+    }
+
+    fn images_override(scope: QuotaScope, target_id: u64, daily_images: u64) -> QuotaOverride {
+        QuotaOverride { scope, target_id, requests_per_minute: None, daily_images: Some(daily_images), daily_tokens: None, daily_tts_characters: None }
+    }
+
+    fn user_with_roles(role_ids: &[u64]) -> QuotaUser {
+        QuotaUser { guild_id: GUILD_ID, user_id: 1, role_ids: role_ids.to_vec() }
+    }
+
+    #[test]
+    fn each_level_replaces_the_one_before() {
+        let guild_override = images_override(QuotaScope::Guild, 0, 20);
+        let role_override = images_override(QuotaScope::Role, 7, 30);
+        let user_override = images_override(QuotaScope::User, 1, 5);
+
+        let limits = limits_with_overrides(&quota_config(), &[], &user_with_roles(&[7]));
+        assert_eq!(limits, QuotaLimits::from(&quota_config()));
+        let limits = limits_with_overrides(&quota_config(), std::slice::from_ref(&guild_override), &user_with_roles(&[7]));
+        assert_eq!(limits.daily_images, 20);
+        let limits = limits_with_overrides(&quota_config(), &[guild_override.clone(), role_override.clone()], &user_with_roles(&[7]));
+        assert_eq!(limits.daily_images, 30);
+        // A user override can lower a limit, and limits that are not set come from the level above
+        let limits = limits_with_overrides(&quota_config(), &[guild_override, role_override, user_override], &user_with_roles(&[7]));
+        assert_eq!(limits.daily_images, 5);
+        assert_eq!(limits.daily_tokens, 1000);
+        // Overrides for other roles and users are ignored
+        let limits = limits_with_overrides(&quota_config(), &[images_override(QuotaScope::Role, 8, 30), images_override(QuotaScope::User, 2, 1)], &user_with_roles(&[7]));
+        assert_eq!(limits.daily_images, 10);
+    }
+
+    #[test]
+    fn the_most_generous_role_wins() {
+        let overrides = [images_override(QuotaScope::Role, 7, 30), images_override(QuotaScope::Role, 8, 50), images_override(QuotaScope::Role, 9, 0)];
+        assert_eq!(limits_with_overrides(&quota_config(), &overrides, &user_with_roles(&[7, 8])).daily_images, 50);
+        // 0 is unlimited so it beats any number
+        assert_eq!(limits_with_overrides(&quota_config(), &overrides, &user_with_roles(&[7, 8, 9])).daily_images, 0);
+    }
+
+    #[test]
+    fn rate_limit_waits_for_the_oldest_request() {
+        let rate_limiter = RateLimiter::default();
+        let quota_user = user_with_roles(&[]);
+        let start = Instant::now();
+        assert_eq!(rate_limiter.try_request_at(&quota_user, 2, start), Ok(()));
+        assert_eq!(rate_limiter.try_request_at(&quota_user, 2, start + Duration::from_secs(10)), Ok(()));
+        assert_eq!(rate_limiter.try_request_at(&quota_user, 2, start + Duration::from_secs(20)), Err(Duration::from_secs(40)));
+        // Other users have their own limit
+        assert_eq!(rate_limiter.try_request_at(&QuotaUser { user_id: 2, ..user_with_roles(&[]) }, 2, start + Duration::from_secs(20)), Ok(()));
+
+        // The first request leaves the window after a minute
+        assert_eq!(rate_limiter.try_request_at(&quota_user, 2, start + Duration::from_secs(60)), Ok(()));
+        assert_eq!(rate_limiter.try_request_at(&quota_user, 2, start + Duration::from_secs(61)), Err(Duration::from_secs(9)));
+    }
+}
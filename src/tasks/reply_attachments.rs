@@ -0,0 +1,162 @@
+use serenity::all::CreateAttachment;
+
+// Discord allows 10 attachments on a message, one is always used for the full reply
+const MAX_CODE_ATTACHMENTS: usize = 9;
+// The length of the preview of the reply that is put in the message itself
+const SUMMARY_CHARACTER_LIMIT: usize = 300;
+
+/*
+    Whether a reply that needs this many messages should be sent as an attachment instead, a threshold of 0 never uses attachments
+*/
+pub fn needs_attachment(message_count: usize, attachment_threshold: usize) -> bool {
+    attachment_threshold > 0 && message_count > attachment_threshold
+}
+
+/*
+    Creates the attachments for a reply that is too long to send as messages
+    The full reply is attached as a markdown file and every code block is also attached on its own
+    with a file extension based on the language of the code block
+*/
+pub fn build_reply_attachments(response_text: &str) -> Vec<CreateAttachment> {
+    let mut attachments = vec![CreateAttachment::bytes(response_text.as_bytes().to_vec(), "reply.md")];
+
+    for (index, (language, code)) in extract_code_blocks(response_text).into_iter().take(MAX_CODE_ATTACHMENTS).enumerate() {
+        attachments.push(CreateAttachment::bytes(code.into_bytes(), format!("code_{}.{}", index + 1, language_extension(&language))));
+    }
+
+    attachments
+}
+
+/*
+    The message sent with the attachments, this is the start of the reply (up to the first code block)
+    cut down to a short preview
+*/
+pub fn build_reply_summary(response_text: &str) -> String {
+    let before_code = response_text.split("```").next().unwrap_or_default().trim();
+    let first_paragraph = before_code.split("\n\n").next().unwrap_or_default().trim();
+
+    let mut summary: String = first_paragraph.chars().take(SUMMARY_CHARACTER_LIMIT).collect();
+    if first_paragraph.chars().count() > SUMMARY_CHARACTER_LIMIT {
+        summary.push_str("...");
+    }
+
+    if summary.is_empty() {
+        "The reply was too long to send as messages so it has been attached as a file".to_owned()
+    } else {
+        format!("{}\n\n(The full reply was too long to send as messages so it has been attached as a file)", summary)
+    }
+}
+
+/*
+    Returns the language tag and content of every complete code block in the text
+*/
+fn extract_code_blocks(text: &str) -> Vec<(String, String)> {
+    let mut code_blocks: Vec<(String, String)> = Vec::new();
+    let mut current_block: Option<(String, Vec<&str>)> = None;
+
+    for line in text.lines() {
+        let trimmed_line = line.trim_start();
+        if !trimmed_line.starts_with("```") {
+            if let Some((_, code_lines)) = current_block.as_mut() {
+                code_lines.push(line);
+            }
+            continue;
+        }
+        match current_block.take() {
+            Some((language, code_lines)) => code_blocks.push((language, code_lines.join("\n") + "\n")),
+            // Only the first word of the info string is the language (```python title="main.py")
+            None => {
+                let language = trimmed_line.trim_start_matches('`').split_whitespace().next().unwrap_or_default().to_lowercase();
+                current_block = Some((language, Vec::new()));
+            },
+        }
+    }
+
+    code_blocks
+}
+
+fn language_extension(language: &str) -> &str {
+    match language {
+        "rust" | "rs" => "rs",
+        "python" | "py" => "py",
+        "javascript" | "js" | "jsx" => "js",
+        "typescript" | "ts" | "tsx" => "ts",
+        "c" | "h" => "c",
+        "cpp" | "c++" | "cc" | "hpp" => "cpp",
+        "csharp" | "cs" | "c#" => "cs",
+        "fsharp" | "fs" | "f#" => "fs",
+        "java" => "java",
+        "kotlin" | "kt" => "kt",
+        "go" | "golang" => "go",
+        "swift" => "swift",
+        "ruby" | "rb" => "rb",
+        "php" => "php",
+        "lua" => "lua",
+        "perl" | "pl" => "pl",
+        "r" => "r",
+        "bash" | "sh" | "shell" | "zsh" | "console" => "sh",
+        "powershell" | "ps1" | "pwsh" => "ps1",
+        "bat" | "batch" | "cmd" => "bat",
+        "sql" => "sql",
+        "html" => "html",
+        "css" => "css",
+        "scss" => "scss",
+        "xml" => "xml",
+        "json" => "json",
+        "yaml" | "yml" => "yaml",
+        "toml" => "toml",
+        "ini" => "ini",
+        "markdown" | "md" => "md",
+        "dockerfile" | "docker" => "dockerfile",
+        "makefile" | "make" => "mk",
+        "lisp" => "lisp",
+        "clojure" | "clj" => "clj",
+        "haskell" | "hs" => "hs",
+        "diff" | "patch" => "diff",
+        _ => "txt",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{extract_code_blocks, language_extension, needs_attachment};
+
+    #[test]
+    fn extracts_complete_code_blocks_with_their_language() {
+        let text = "Here:\n```Rust\nfn main() {}\n```\nAnd some text\n```\nplain\n```";
+        assert_eq!(extract_code_blocks(text), vec![
+            ("rust".to_owned(), "fn main() {}\n".to_owned()),
+            (String::new(), "plain\n".to_owned()),
+        ]);
+    }
+
+    #[test]
+    fn unterminated_code_blocks_are_left_out() {
+        let text = "```python\nprint(1)\n```\n```js\nconsole.log(1)";
+        assert_eq!(extract_code_blocks(text), vec![("python".to_owned(), "print(1)\n".to_owned())]);
+    }
+
+    #[test]
+    fn only_the_first_word_of_the_info_string_is_the_language() {
+        let text = "```python title=\"main.py\" linenums\nprint(1)\n```";
+        let code_blocks = extract_code_blocks(text);
+        assert_eq!(code_blocks[0].0, "python");
+        assert_eq!(language_extension(&code_blocks[0].0), "py");
+    }
+
+    #[test]
+    fn languages_map_to_extensions() {
+        assert_eq!(language_extension("lisp"), "lisp");
+        assert_eq!(language_extension("clojure"), "clj");
+        assert_eq!(language_extension("c++"), "cpp");
+        assert_eq!(language_extension(""), "txt");
+        assert_eq!(language_extension("brainfuck"), "txt");
+    }
+
+    #[test]
+    fn replies_over_the_threshold_become_attachments() {
+        assert!(!needs_attachment(3, 3));
+        assert!(needs_attachment(4, 3));
+        assert!(!needs_attachment(100, 0));
+    }
+}
@@ -0,0 +1,402 @@
+use std::{collections::HashMap, future::Future, sync::{Arc, Mutex}, time::{Duration, Instant}};
+
+use reqwest::{header::{HeaderValue, ACCEPT, AUTHORIZATION}, Method};
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::Value;
+use tokio::{sync::oneshot, time::sleep};
+use tracing::{debug, field::Empty, info, instrument, warn, Span};
+
+use crate::{tasks::handle_errors::BotError, Error};
+
+use super::config::RunpodConfig;
+
+// How long a single request to Runpod can take, runsync holds the request open while the job runs (up to about 90 seconds)
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+const RUNSYNC_TIMEOUT: Duration = Duration::from_secs(120);
+
+/*
+    The status of a serverless job, anything other than IN_QUEUE and IN_PROGRESS means the job has finished
+*/
+#[derive(serde::Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum JobStatus {
+    InQueue,
+    InProgress,
+    Completed,
+    Failed,
+    Cancelled,
+    TimedOut,
+    // Any status added to Runpod after this was written
+    #[serde(other)]
+    Unknown,
+}
+
+impl JobStatus {
+    fn is_finished(&self) -> bool {
+        !matches!(self, JobStatus::InQueue | JobStatus::InProgress)
+    }
+}
+
+/*
+    The response from /run, /runsync and /status
+    The output is kept as JSON until the job has completed as failed jobs can return anything
+*/
+#[derive(serde::Deserialize, Debug)]
+struct JobResponse {
+    id: String,
+    status: JobStatus,
+    output: Option<Value>,
+    error: Option<Value>,
+}
+
+/*
+    A body that can be sent to /run and /runsync, the client sets the webhook when Runpod webhooks are turned on
+*/
+pub trait JobRequest: Serialize {
+    fn set_webhook(&mut self, webhook: String);
+}
+
+/*
+    The jobs waiting for Runpod to send them to the webhook, this is shared between the Runpod client and the HTTP server
+    Each job is given a random ID in its webhook URL, so only Runpod (which is the only one given the URL) can finish it
+*/
+pub struct RunpodWebhooks {
+    public_url: String,
+    waiting_jobs: Mutex<HashMap<String, oneshot::Sender<JobResponse>>>,
+}
+
+impl RunpodWebhooks {
+    pub fn new(public_url: &str) -> RunpodWebhooks {
+        RunpodWebhooks { public_url: public_url.trim_end_matches('/').to_owned(), waiting_jobs: Mutex::default() }
+    }
+
+    /*
+        Called by the HTTP server with the body Runpod sent to a webhook URL
+        Returns false if no job is waiting on the URL (it has already finished or been cancelled)
+    */
+    pub fn complete(&self, webhook_id: &str, body: &str) -> Result<bool, Error> {
+        let job: JobResponse = serde_json::from_str(body)
+            .map_err(|e| BotError::Runpod(format!("The webhook body is not a Runpod job: {}", e)))?;
+        let Some(sender) = self.waiting_jobs.lock().ok().and_then(|mut t| t.remove(webhook_id)) else {
+            return Ok(false);
+        };
+        debug!(job_id = job.id, status = ?job.status, "Runpod sent a job to the webhook");
+        // The job has only just stopped waiting if this fails, the status checks are used instead
+        Ok(sender.send(job).is_ok())
+    }
+
+    fn register(&self) -> WebhookRegistration<'_> {
+        let webhook_id = format!("{:032x}", rand::random::<u128>());
+        let (sender, receiver) = oneshot::channel();
+        if let Ok(mut waiting_jobs) = self.waiting_jobs.lock() {
+            waiting_jobs.insert(webhook_id.clone(), sender);
+        }
+        WebhookRegistration { webhooks: self, webhook_id, receiver }
+    }
+}
+
+/*
+    A job waiting on its webhook, the URL stops working once this is dropped
+*/
+struct WebhookRegistration<'a> {
+    webhooks: &'a RunpodWebhooks,
+    webhook_id: String,
+    receiver: oneshot::Receiver<JobResponse>,
+}
+
+impl WebhookRegistration<'_> {
+    fn url(&self) -> String {
+        format!("{}/runpod/webhook/{}", self.webhooks.public_url, self.webhook_id)
+    }
+}
+
+impl Drop for WebhookRegistration<'_> {
+    fn drop(&mut self) {
+        if let Ok(mut waiting_jobs) = self.webhooks.waiting_jobs.lock() {
+            waiting_jobs.remove(&self.webhook_id);
+        }
+    }
+}
+
+/*
+    A client for Runpod serverless endpoints, this is shared through the poise Data so connections are reused
+    If webhooks are given, Runpod sends finished jobs to the HTTP server and the status is only checked as a fallback
+*/
+pub struct RunpodClient {
+    http_client: reqwest::Client,
+    runpod_config: RunpodConfig,
+    webhooks: Option<Arc<RunpodWebhooks>>,
+}
+
+impl RunpodClient {
+    pub fn new(runpod_config: &RunpodConfig, webhooks: Option<Arc<RunpodWebhooks>>) -> RunpodClient {
+        RunpodClient { http_client: reqwest::Client::new(), runpod_config: runpod_config.clone(), webhooks }
+    }
+
+    /*
+        Runs a job on a serverless endpoint and waits for its output
+        The request is the whole body sent to Runpod (the input and any webhook), the output is parsed from the completed job
+        With runsync Runpod holds the request open while the job runs, which saves polling for endpoints that finish quickly
+        If the job is still running when runsync returns, it is polled the same as a job started with run
+        The job is cancelled if it takes longer than runpod.max_wait_seconds or if `cancelled` finishes first (such as a cancel button being pressed)
+    */
+    #[instrument(skip_all, fields(endpoint = endpoint_id, job_id = Empty))]
+    pub async fn run<R: JobRequest, O: DeserializeOwned>(
+        &self,
+        endpoint_id: &str,
+        mut job_request: R,
+        use_runsync: bool,
+        cancelled: impl Future<Output = ()>
+    ) -> Result<O, Error> {
+        if self.runpod_config.api_key.is_empty() {
+            return Err(BotError::Config("No Runpod API key has been set".to_owned()));
+        }
+        let started = Instant::now();
+        let mut webhook_registration = self.webhooks.as_ref().map(|t| t.register());
+        if let Some(registration) = &webhook_registration {
+            job_request.set_webhook(registration.url());
+        }
+        let (path, timeout) = if use_runsync { ("runsync", RUNSYNC_TIMEOUT) } else { ("run", REQUEST_TIMEOUT) };
+        let job = self.request(Method::POST, &format!("{}/{}", endpoint_id, path), Some(&job_request), timeout).await?;
+        Span::current().record("job_id", job.id.as_str());
+        info!(status = ?job.status, "Started a Runpod job");
+
+        let job_id = job.id.clone();
+        tokio::select! {
+            job_result = self.wait_for_job(endpoint_id, job, started, webhook_registration.as_mut().map(|t| &mut t.receiver)) => job_output(job_result?),
+            _ = cancelled => {
+                info!("The Runpod job was cancelled by the user");
+                self.cancel_job(endpoint_id, &job_id).await;
+                Err(BotError::Cancelled)
+            },
+        }
+    }
+
+    /*
+        Checks the status of the job until it finishes, waiting longer between each check
+        A failed status check is logged and tried again, only the max wait stops the job
+        With a webhook the job normally finishes when Runpod sends it, the status is only checked every max_poll_interval_ms in case the webhook is missed
+    */
+    async fn wait_for_job(
+        &self,
+        endpoint_id: &str,
+        mut job: JobResponse,
+        started: Instant,
+        mut webhook: Option<&mut oneshot::Receiver<JobResponse>>
+    ) -> Result<JobResponse, Error> {
+        let max_wait = Duration::from_secs(self.runpod_config.max_wait_seconds);
+        let max_poll_interval = Duration::from_millis(self.runpod_config.max_poll_interval_ms);
+        let mut poll_interval = if webhook.is_some() { max_poll_interval } else { Duration::from_millis(self.runpod_config.poll_interval_ms) };
+
+        while !job.status.is_finished() {
+            let elapsed = started.elapsed();
+            if elapsed >= max_wait {
+                self.cancel_job(endpoint_id, &job.id).await;
+                return Err(BotError::Runpod(format!("The job {} did not finish within {} seconds and has been cancelled", job.id, max_wait.as_secs())));
+            }
+            debug!(status = ?job.status, "Waiting for the Runpod job");
+            let wait = poll_interval.min(max_wait - elapsed);
+            let webhook_job = match webhook.as_mut() {
+                Some(receiver) => tokio::select! {
+                    webhook_job = receiver => Some(webhook_job),
+                    _ = sleep(wait) => None,
+                },
+                None => {
+                    sleep(wait).await;
+                    None
+                },
+            };
+            // The receiver can only be used once, any later checks are status checks
+            if let Some(webhook_job) = webhook_job {
+                webhook = None;
+                if let Ok(t) = webhook_job {
+                    job = t;
+                    continue;
+                }
+            }
+            poll_interval = (poll_interval * 2).min(max_poll_interval);
+
+            match self.request(Method::GET, &format!("{}/status/{}", endpoint_id, job.id), None::<&()>, REQUEST_TIMEOUT).await {
+                Ok(t) => job = t,
+                Err(e) => warn!("Unable to get the status of the Runpod job, trying again: {}", e),
+            }
+        }
+
+        info!(status = ?job.status, "The Runpod job has finished");
+        Ok(job)
+    }
+
+    /*
+        Cancelling is best effort, the job has already failed (or been abandoned) from the bot's point of view
+    */
+    async fn cancel_job(&self, endpoint_id: &str, job_id: &str) {
+        match self.request(Method::POST, &format!("{}/cancel/{}", endpoint_id, job_id), None::<&()>, REQUEST_TIMEOUT).await {
+            Ok(_) => info!(job_id, "Cancelled the Runpod job"),
+            Err(e) => warn!(job_id, "Unable to cancel the Runpod job: {}", e),
+        }
+    }
+
+    async fn request<B: Serialize>(&self, method: Method, path: &str, body: Option<&B>, timeout: Duration) -> Result<JobResponse, Error> {
+        let api_key = HeaderValue::from_str(&self.runpod_config.api_key)
+            .map_err(|_| BotError::Config("The Runpod API key is not a valid header value".to_owned()))?;
+        let mut request = self.http_client.request(method, format!("{}/{}", self.runpod_config.base_url.trim_end_matches('/'), path))
+            .header(ACCEPT, "application/json")
+            .header(AUTHORIZATION, api_key)
+            .timeout(timeout);
+        if let Some(body) = body {
+            request = request.json(body);
+        }
+
+        let response = request.send().await
+            .map_err(|e| BotError::Runpod(format!("Unable to call /{}: {}", path, e)))?;
+        let status_code = response.status();
+        if !status_code.is_success() {
+            let response_text = response.text().await.unwrap_or_default();
+            return Err(BotError::Runpod(format!("/{} returned {}: {}", path, status_code, response_text)));
+        }
+        response.json().await
+            .map_err(|e| BotError::Runpod(format!("Unable to read the response from /{}: {}", path, e)))
+    }
+}
+
+/*
+    The output of a finished job, any status other than COMPLETED is an error with Runpod's error message if it gave one
+*/
+fn job_output<O: DeserializeOwned>(job: JobResponse) -> Result<O, Error> {
+    match job.status {
+        JobStatus::Completed => {
+            let output = job.output.ok_or(BotError::Runpod(format!("The job {} completed without any output", job.id)))?;
+            serde_json::from_value(output)
+                .map_err(|e| BotError::Runpod(format!("The output of the job {} is not in the expected format: {}", job.id, e)))
+        },
+        JobStatus::Failed => Err(BotError::Runpod(format!("The job {} failed: {}", job.id, describe_job_error(job.error.as_ref())))),
+        JobStatus::TimedOut => Err(BotError::Runpod(format!("The job {} timed out on Runpod: {}", job.id, describe_job_error(job.error.as_ref())))),
+        JobStatus::Cancelled => Err(BotError::Runpod(format!("The job {} was cancelled on Runpod", job.id))),
+        status => Err(BotError::Runpod(format!("The job {} finished with the unexpected status {:?}", job.id, status))),
+    }
+}
+
+/*
+    Workers built on the Runpod SDK give the error as a JSON string with error_type, error_message and error_traceback
+    Only the message is used if there is one, otherwise the error is shown as it was given
+*/
+fn describe_job_error(error: Option<&Value>) -> String {
+    let error = match error {
+        Some(Value::String(t)) => serde_json::from_str::<Value>(t).unwrap_or_else(|_| Value::String(t.clone())),
+        Some(t) => t.clone(),
+        None => return "no error was given".to_owned(),
+    };
+    match error.get("error_message").and_then(|t| t.as_str()) {
+        Some(error_message) => error_message.to_owned(),
+        None => error.as_str().map_or_else(|| error.to_string(), |t| t.to_owned()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::future::pending;
+
+    use axum::{routing::{get, post}, Router};
+    use serde_json::json;
+    use tokio::net::TcpListener;
+
+    use crate::tasks::http_server::build_router;
+
+    use super::*;
+
+    fn finished_job(status: JobStatus, output: Option<Value>, error: Option<Value>) -> JobResponse {
+        JobResponse { id: "job-1".to_owned(), status, output, error }
+    }
+
+    #[test]
+    fn completed_job_returns_output() {
+        let output: Vec<u32> = job_output(finished_job(JobStatus::Completed, Some(json!([1, 2])), None)).unwrap();
+        assert_eq!(output, vec![1, 2]);
+    }
+
+    #[test]
+    fn failed_job_uses_the_error_message() {
+        let worker_error = json!({ "error_type": "ValueError", "error_message": "Prompt is too long", "error_traceback": "..." }).to_string();
+        let job_error = job_output::<Value>(finished_job(JobStatus::Failed, None, Some(Value::String(worker_error)))).unwrap_err();
+        assert_eq!(job_error.to_string(), "Runpod error: The job job-1 failed: Prompt is too long");
+
+        let job_error = job_output::<Value>(finished_job(JobStatus::TimedOut, None, Some(json!("Execution timeout")))).unwrap_err();
+        assert_eq!(job_error.to_string(), "Runpod error: The job job-1 timed out on Runpod: Execution timeout");
+    }
+
+    #[test]
+    fn unknown_statuses_are_parsed() {
+        let job: JobResponse = serde_json::from_value(json!({ "id": "job-1", "status": "SOMETHING_NEW" })).unwrap();
+        assert_eq!(job.status, JobStatus::Unknown);
+        assert!(job.status.is_finished());
+    }
+
+    #[derive(serde::Serialize)]
+    struct TestRequest {
+        input: Value,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        webhook: Option<String>,
+    }
+
+    impl JobRequest for TestRequest {
+        fn set_webhook(&mut self, webhook: String) {
+            self.webhook = Some(webhook);
+        }
+    }
+
+    // A listener on a free local port with its URL, the URL is needed for the webhooks before the bot's router can be built
+    async fn local_listener() -> (String, TcpListener) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        (format!("http://{}", listener.local_addr().unwrap()), listener)
+    }
+
+    fn serve(listener: TcpListener, router: Router) {
+        tokio::spawn(async move { axum::serve(listener, router).await.unwrap() });
+    }
+
+    fn test_config(base_url: String, poll_interval_ms: u64) -> RunpodConfig {
+        RunpodConfig { api_key: "test".to_owned(), base_url, max_wait_seconds: 10, poll_interval_ms, max_poll_interval_ms: poll_interval_ms, ..Default::default() }
+    }
+
+    #[tokio::test]
+    async fn webhook_finishes_the_job() {
+        let (bot_url, bot_listener) = local_listener().await;
+        let runpod_webhooks = Arc::new(RunpodWebhooks::new(&bot_url));
+        serve(bot_listener, build_router(false, Some(runpod_webhooks.clone())));
+
+        // The mock only finishes the job through the webhook, the status always says it is queued
+        let (runpod_url, runpod_listener) = local_listener().await;
+        serve(runpod_listener, Router::new()
+            .route("/endpoint/run", post(|body: String| async move {
+                let webhook = serde_json::from_str::<Value>(&body).unwrap()["webhook"].as_str().unwrap().to_owned();
+                tokio::spawn(async move {
+                    let finished_job = json!({ "id": "job-1", "status": "COMPLETED", "output": { "images": ["abc"] } });
+                    reqwest::Client::new().post(webhook).body(finished_job.to_string()).send().await.unwrap().error_for_status().unwrap();
+                });
+                json!({ "id": "job-1", "status": "IN_QUEUE" }).to_string()
+            }))
+            .route("/endpoint/status/job-1", get(|| async { json!({ "id": "job-1", "status": "IN_QUEUE" }).to_string() }))
+        );
+
+        let runpod_client = RunpodClient::new(&test_config(runpod_url, 60000), Some(runpod_webhooks.clone()));
+        let output: Value = runpod_client.run("endpoint", TestRequest { input: json!({}), webhook: None }, false, pending()).await.unwrap();
+        assert_eq!(output, json!({ "images": ["abc"] }));
+        assert!(runpod_webhooks.waiting_jobs.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn status_is_checked_without_a_webhook() {
+        let (runpod_url, runpod_listener) = local_listener().await;
+        serve(runpod_listener, Router::new()
+            .route("/endpoint/run", post(|body: String| async move {
+                assert!(serde_json::from_str::<Value>(&body).unwrap().get("webhook").is_none());
+                json!({ "id": "job-1", "status": "IN_QUEUE" }).to_string()
+            }))
+            .route("/endpoint/status/job-1", get(|| async { json!({ "id": "job-1", "status": "COMPLETED", "output": [1, 2] }).to_string() }))
+        );
+
+        let runpod_client = RunpodClient::new(&test_config(runpod_url, 10), None);
+        let output: Vec<u32> = runpod_client.run("endpoint", TestRequest { input: json!({}), webhook: None }, false, pending()).await.unwrap();
+        assert_eq!(output, vec![1, 2]);
+    }
+}
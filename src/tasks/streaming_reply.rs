@@ -0,0 +1,232 @@
+use std::time::{Duration, Instant};
+
+use serenity::all::{Context, CreateAllowedMentions, CreateAttachment, CreateMessage, EditMessage, Message};
+
+use crate::Error;
+
+use super::{message_splitter::split_message, reply_attachments::{build_reply_attachments, build_reply_summary, needs_attachment}};
+
+// Discord allows 2000 characters in a message, a little is kept back for DEBUG: and closing code blocks
+const MESSAGE_CHARACTER_LIMIT: usize = 1980;
+// Discord rate limits message edits to around 5 every 5 seconds per channel
+const EDIT_INTERVAL: Duration = Duration::from_millis(1500);
+// Discord allows 10 attachments on a message
+const MAX_ATTACHMENTS: usize = 10;
+
+/*
+    Posts a streamed reply to Discord
+    Text is added as it arrives and the message being written is edited at most once per EDIT_INTERVAL
+    When the message fills up, it is finished and a new message is started as a reply to it
+    If the reply needs more messages than the attachment threshold, the messages are replaced with a
+    single message with the reply attached as a file once it has finished
+*/
+pub struct StreamingReply<'a> {
+    ctx: &'a Context,
+    original_message: Message,
+    message_prefix: String,
+    // The number of messages a reply can use before it is sent as an attachment, 0 never uses attachments
+    attachment_threshold: usize,
+    attachment_mode: bool,
+    full_text: String,
+    // Every message posted for this reply, these are removed if the reply is changed to an attachment
+    sent_messages: Vec<Message>,
+    // Messages that have been completely written along with the text in them
+    finished_replies: Vec<(Message, String)>,
+    // The last message that was completely written, new messages reply to this
+    last_finished_message: Option<Message>,
+    // The message currently being edited, None until the first text for it has been sent
+    current_message: Option<Message>,
+    current_text: String,
+    last_written_text: String,
+    last_edit: Instant,
+    // Files to post with the reply once it has finished, such as images made by tools
+    files: Vec<CreateAttachment>,
+}
+
+impl<'a> StreamingReply<'a> {
+    pub fn new(ctx: &'a Context, original_message: Message, message_prefix: String, attachment_threshold: usize) -> Self {
+        StreamingReply {
+            ctx,
+            original_message,
+            message_prefix,
+            attachment_threshold,
+            attachment_mode: false,
+            full_text: String::new(),
+            sent_messages: Vec::new(),
+            finished_replies: Vec::new(),
+            last_finished_message: None,
+            current_message: None,
+            current_text: String::new(),
+            last_written_text: String::new(),
+            last_edit: Instant::now(),
+            files: Vec::new(),
+        }
+    }
+
+    /*
+        Adds files to the end of the reply, these are posted when the reply finishes
+    */
+    pub fn attach(&mut self, files: Vec<CreateAttachment>) {
+        self.files.extend(files);
+    }
+
+    pub async fn push(&mut self, text: &str) -> Result<(), Error> {
+        self.full_text.push_str(text);
+        if self.attachment_mode {
+            return Ok(());
+        }
+        self.current_text.push_str(text);
+
+        if self.current_text.chars().count() > MESSAGE_CHARACTER_LIMIT {
+            let mut chunks = split_message(&self.current_text, MESSAGE_CHARACTER_LIMIT);
+
+            if needs_attachment(self.sent_messages.len() + chunks.len(), self.attachment_threshold) {
+                return self.start_attachment_mode().await;
+            }
+
+            // The last chunk is still being written so it stays as the current text
+            let remaining_text = chunks.pop().unwrap_or_default();
+            for chunk in chunks {
+                self.write(chunk.clone()).await?;
+                self.finish_current_message(chunk);
+            }
+            self.current_text = remaining_text;
+        } else if self.last_edit.elapsed() >= EDIT_INTERVAL {
+            self.write(self.current_text.clone()).await?;
+        }
+
+        Ok(())
+    }
+
+    /*
+        Writes whatever is left once the stream has ended
+        Returns every message that makes up the reply along with the text in it
+    */
+    pub async fn finish(&mut self) -> Result<Vec<(Message, String)>, Error> {
+        if self.full_text.trim().is_empty() && self.files.is_empty() {
+            return Err("The chat backend returned an empty response".into());
+        }
+        if self.attachment_mode {
+            self.send_as_attachment().await?;
+        } else {
+            self.write(self.current_text.clone()).await?;
+            if !self.files.is_empty() {
+                self.send_files().await?;
+            }
+            self.finish_current_message(self.current_text.clone());
+        }
+        Ok(std::mem::take(&mut self.finished_replies))
+    }
+
+    fn finish_current_message(&mut self, text: String) {
+        if let Some(current_message) = self.current_message.take() {
+            self.finished_replies.push((current_message.clone(), text));
+            self.last_finished_message = Some(current_message);
+        }
+        self.last_written_text = String::new();
+    }
+
+    /*
+        The first message is kept as a placeholder until the reply has finished, any others are removed
+    */
+    async fn start_attachment_mode(&mut self) -> Result<(), Error> {
+        self.attachment_mode = true;
+        self.finished_replies.clear();
+        let placeholder = format!("{}This reply is long so it will be attached as a file once it has finished...", self.message_prefix);
+
+        let mut sent_messages = std::mem::take(&mut self.sent_messages).into_iter();
+        match sent_messages.next() {
+            Some(mut first_message) => {
+                first_message.edit(self.ctx, EditMessage::new().content(placeholder)).await?;
+                self.sent_messages.push(first_message);
+            },
+            None => {
+                let first_message = self.send(placeholder, &self.original_message).await?;
+                self.sent_messages.push(first_message);
+            },
+        }
+        for sent_message in sent_messages {
+            sent_message.delete(self.ctx).await?;
+        }
+
+        Ok(())
+    }
+
+    /*
+        The files are added to the last message of the reply, or sent on their own if the reply has no text
+    */
+    async fn send_files(&mut self) -> Result<(), Error> {
+        let files: Vec<CreateAttachment> = self.files.drain(..).take(MAX_ATTACHMENTS).collect();
+
+        match self.current_message.as_mut() {
+            Some(current_message) => {
+                let edit_builder = files.into_iter().fold(EditMessage::new(), |edit_builder, file| edit_builder.new_attachment(file));
+                current_message.edit(self.ctx, edit_builder).await?;
+            },
+            None => {
+                let reply_to = self.last_finished_message.clone().unwrap_or_else(|| self.original_message.clone());
+                let message_builder = CreateMessage::new()
+                    .reference_message(&reply_to)
+                    .allowed_mentions(CreateAllowedMentions::new().users(vec![self.original_message.author.id]))
+                    .content(self.message_prefix.clone())
+                    .files(files);
+                let sent_message = self.original_message.channel_id.send_message(&self.ctx.http, message_builder).await?;
+                self.sent_messages.push(sent_message.clone());
+                self.current_message = Some(sent_message);
+            },
+        }
+        Ok(())
+    }
+
+    async fn send_as_attachment(&mut self) -> Result<(), Error> {
+        // Files from tools go straight after reply.md so they are not pushed out by the code files
+        let mut files = build_reply_attachments(&self.full_text);
+        files.splice(1..1, self.files.drain(..));
+        files.truncate(MAX_ATTACHMENTS);
+
+        let message_builder = CreateMessage::new()
+            .reference_message(&self.original_message)
+            .allowed_mentions(CreateAllowedMentions::new().users(vec![self.original_message.author.id]))
+            .content(format!("{}{}", self.message_prefix, build_reply_summary(&self.full_text)))
+            .files(files);
+        let attachment_message = self.original_message.channel_id.send_message(&self.ctx.http, message_builder).await?;
+        self.finished_replies.push((attachment_message, self.full_text.clone()));
+
+        for sent_message in self.sent_messages.drain(..) {
+            sent_message.delete(self.ctx).await?;
+        }
+        Ok(())
+    }
+
+    async fn write(&mut self, text: String) -> Result<(), Error> {
+        // Discord does not allow empty messages and there is no point editing a message to the same text
+        if text.trim().is_empty() || text == self.last_written_text {
+            return Ok(());
+        }
+        let content = format!("{}{}", self.message_prefix, text);
+
+        match self.current_message.as_mut() {
+            Some(current_message) => {
+                current_message.edit(self.ctx, EditMessage::new().content(content)).await?;
+            },
+            None => {
+                let reply_to = self.last_finished_message.clone().unwrap_or_else(|| self.original_message.clone());
+                let sent_message = self.send(content, &reply_to).await?;
+                self.sent_messages.push(sent_message.clone());
+                self.current_message = Some(sent_message);
+            },
+        }
+
+        self.last_written_text = text;
+        self.last_edit = Instant::now();
+        Ok(())
+    }
+
+    async fn send(&self, content: String, reply_to: &Message) -> Result<Message, Error> {
+        let message_builder = CreateMessage::new()
+            .reference_message(reply_to)
+            .allowed_mentions(CreateAllowedMentions::new().users(vec![self.original_message.author.id]))
+            .content(content);
+        Ok(self.original_message.channel_id.send_message(&self.ctx.http, message_builder).await?)
+    }
+}
@@ -1,10 +1,10 @@
-use async_openai::{types::{AudioInput, CreateTranscriptionRequestArgs}, Client};
+use async_openai::{config::OpenAIConfig, types::{AudioInput, AudioResponseFormat, CreateTranscriptionRequestArgs}, Client};
 use poise::CreateReply;
 use serenity::all::Attachment;
 use tokio::time::timeout;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
-use crate::{tasks::{ffmpeg_handler::run_ffmpeg, handle_errors::{return_error, return_error_command}}, Error};
+use crate::{tasks::{ffmpeg_handler::run_ffmpeg, handle_errors::BotError, metrics::metrics, quotas::{start_request, QuotaUser}, usage::record_transcription_usage}, Error};
 
 #[poise::command(slash_command)]
 pub async fn transcribe_from_attachment(
@@ -13,11 +13,8 @@ pub async fn transcribe_from_attachment(
     attachment_to_stt: Attachment
 ) -> Result<(), Error> {
     // NOTE: This command has a timeout of 3 minutes, this is due to OpenAI sometimes taking an extremely long time to process longer text requests and at some point it does have to stop
-    let _result = match timeout(Duration::from_secs(180), stt_run(ctx, attachment_to_stt.proxy_url)).await
-        {
-            Ok(t) => t,
-            Err(_) => return_error_command(ctx, "This TTS command has timed out, this may be due to the length of the text".to_owned()).await.unwrap(),
-        };
+    timeout(Duration::from_secs(180), stt_run(ctx, attachment_to_stt.proxy_url)).await
+        .map_err(|_| BotError::UserInput("This transcription has timed out, this may be due to the length of the file".to_owned()))??;
 
     Ok(())
 }
@@ -29,14 +26,11 @@ pub async fn transcribe_from_message(
     message_to_stt: serenity::all::Message
 ) -> Result<(), Error> {
     if message_to_stt.attachments.is_empty() {
-        return_error_command(ctx, "The linked message does not have any attachments".to_owned()).await.unwrap()
+        return Err(BotError::UserInput("The linked message does not have any attachments".to_owned()));
     }
     // NOTE: This command has a timeout of 3 minutes, this is due to OpenAI sometimes taking an extremely long time to process longer text requests and at some point it does have to stop
-    let _result = match timeout(Duration::from_secs(180), stt_run(ctx, message_to_stt.attachments[0].proxy_url.clone())).await
-        {
-            Ok(t) => t,
-            Err(_) => return_error_command(ctx, "This TTS command has timed out, this may be due to the length of the text".to_owned()).await.unwrap(),
-        };
+    timeout(Duration::from_secs(180), stt_run(ctx, message_to_stt.attachments[0].proxy_url.clone())).await
+        .map_err(|_| BotError::UserInput("This transcription has timed out, this may be due to the length of the file".to_owned()))??;
 
     Ok(())
 }
@@ -48,11 +42,8 @@ pub async fn transcribe_from_url(
     url_to_stt: String
 ) -> Result<(), Error> {
     // NOTE: This command has a timeout of 3 minutes, this is due to OpenAI sometimes taking an extremely long time to process longer text requests and at some point it does have to stop
-    let _result = match timeout(Duration::from_secs(180), stt_run(ctx, url_to_stt)).await
-        {
-            Ok(t) => t,
-            Err(_) => return_error_command(ctx, "This TTS command has timed out, this may be due to the length of the text".to_owned()).await.unwrap(),
-        };
+    timeout(Duration::from_secs(180), stt_run(ctx, url_to_stt)).await
+        .map_err(|_| BotError::UserInput("This transcription has timed out, this may be due to the length of the file".to_owned()))??;
 
     Ok(())
 }
@@ -60,50 +51,56 @@ pub async fn transcribe_from_url(
 pub async fn stt_run (
     ctx: crate::Context<'_>,
     stt_attachment_url: String
-)
+) -> Result<(), Error>
 {
-    let client = Client::new();
-    let requester_id = ctx.author().id;
-    let channel_id = ctx.channel_id();
-
-    match ctx.defer().await
-    {
-        Ok(t) => t,
-        Err(e) => return_error(requester_id, channel_id, e.to_string()).await.unwrap(),
-    };
+    let quota_user = QuotaUser::from_context(ctx).await;
+    start_request(ctx.data(), &quota_user)?;
 
-    let attachment_processed: Vec<u8>= run_ffmpeg(None, Some(stt_attachment_url.clone()), "-f mp3".to_string(), requester_id, channel_id).await;
+    let client = Client::with_config(ctx.data().config.openai_client_config());
+    let requester_id = ctx.author().id;
 
-    if attachment_processed.is_empty() {
-        let _: Error = return_error(requester_id, channel_id, "File conversion output has returned empty".to_owned()).await.unwrap();
-    }
+    ctx.defer().await?;
 
-    let request = match CreateTranscriptionRequestArgs::default()
-        .file(AudioInput::from_vec_u8("discord_video.mp3".to_owned(), attachment_processed))
-        .model("whisper-1")
-        .build()
-            {
-                Ok(t) => t,
-                Err(e) => return_error(requester_id, channel_id, e.to_string()).await.unwrap(),
-            };
-    
-    let response = match client.audio().transcribe(request).await
-        {
-            Ok(t) => t,
-            Err(e) => return_error(requester_id, channel_id, e.to_string()).await.unwrap(),
-        };
-
-    let response_text = response.text;
+    let transcription = transcribe_url(&client, stt_attachment_url.clone()).await?;
+    record_transcription_usage(ctx.data(), &quota_user, TRANSCRIPTION_MODEL, transcription.duration_seconds);
 
     let message_builder = CreateReply 
     { 
-        content: format!("<@{}>\nRequested transcription source: [Here](<{}>)\nTransribed text: {}", requester_id, stt_attachment_url, response_text).into(),
+        content: format!("<@{}>\nRequested transcription source: [Here](<{}>)\nTransribed text: {}", requester_id, stt_attachment_url, transcription.text).into(),
         ..Default::default()
     };
 
-    match ctx.send(message_builder).await
-        {
-            Ok(t) => t,
-            Err(e) => return_error(requester_id, channel_id, e.to_string()).await.unwrap(),
-        };
-}
\ No newline at end of file
+    ctx.send(message_builder).await?;
+    Ok(())
+}
+
+pub const TRANSCRIPTION_MODEL: &str = "whisper-1";
+
+// The duration is used to estimate the cost in the usage ledger
+pub struct Transcription {
+    pub text: String,
+    pub duration_seconds: f64,
+}
+
+/*
+    Converts the audio or video at the URL to MP3 with FFmpeg and transcribes it with Whisper
+    This is also used by the transcribe_attachment tool in text generation
+*/
+pub async fn transcribe_url(
+    client: &Client<OpenAIConfig>,
+    media_url: String
+) -> Result<Transcription, Error> {
+    let attachment_processed: Vec<u8> = run_ffmpeg(None, Some(media_url), "-f mp3".to_string()).await?;
+
+    let request = CreateTranscriptionRequestArgs::default()
+        .file(AudioInput::from_vec_u8("discord_video.mp3".to_owned(), attachment_processed))
+        .model(TRANSCRIPTION_MODEL)
+        .response_format(AudioResponseFormat::VerboseJson)
+        .build()?;
+
+    let transcription_started = Instant::now();
+    let response = client.audio().transcribe_verbose_json(request).await;
+    metrics().transcriptions.observe(&[], transcription_started, response.is_ok());
+    let response = response?;
+    Ok(Transcription { text: response.text, duration_seconds: response.duration as f64 })
+}
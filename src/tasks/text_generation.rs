@@ -1,299 +1,351 @@
-use std::env;
-
-use async_openai::{types::{ChatCompletionRequestAssistantMessageArgs, ChatCompletionRequestFunctionMessageArgs, ChatCompletionRequestMessage, ChatCompletionRequestMessageContentPart, ChatCompletionRequestMessageContentPartImageArgs, ChatCompletionRequestMessageContentPartTextArgs, ChatCompletionRequestSystemMessageArgs, ChatCompletionRequestToolMessageArgs, ChatCompletionRequestUserMessageArgs, CreateChatCompletionRequestArgs, Role}, Client};
+use async_openai::{types::{ChatCompletionMessageToolCall, ChatCompletionRequestAssistantMessageArgs, ChatCompletionRequestFunctionMessageArgs, ChatCompletionRequestMessage, ChatCompletionRequestMessageContentPart, ChatCompletionRequestMessageContentPartImageArgs, ChatCompletionRequestMessageContentPartTextArgs, ChatCompletionRequestSystemMessageArgs, ChatCompletionRequestToolMessageArgs, ChatCompletionRequestUserMessageArgs, Role}};
+use futures::StreamExt;
 use reqwest::Url;
-use serenity::all::{CacheHttp, Http, Message};
-
-use super::handle_errors::return_error_reply;
-
-pub async fn generate_chat_messages (current_role: Role, content: Vec<ChatCompletionRequestMessageContentPart>, content_string_only: String, msg: Message) -> ChatCompletionRequestMessage {
-    if current_role == Role::Assistant {
-        return match ChatCompletionRequestAssistantMessageArgs::default()
-        .content(content_string_only)
-        .build()
-        {
-            Ok(t) => async_openai::types::ChatCompletionRequestMessage::Assistant(t),
-            Err(e) => return_error_reply(msg.clone(), e.to_string()).await.unwrap(),
-        }
-    } else if current_role == Role::Function {
-        return match ChatCompletionRequestFunctionMessageArgs::default()
-        .content(content_string_only)
-        .build()
-        {
-            Ok(t) => async_openai::types::ChatCompletionRequestMessage::Function(t),
-            Err(e) => return_error_reply(msg.clone(), e.to_string()).await.unwrap(),
+use serenity::all::{Context, Message, MessageId};
+use std::time::Instant;
+use tracing::{debug, field::Empty, instrument, warn, Span};
+
+use crate::{Data, Error};
+
+use super::{handle_errors::BotError, metrics::metrics, chat_backend::{ChatEvent, ChatRequest}, context_budget::assemble_context, conversation_store::{drop_expired_images, get_turn, recent_turns, save_turn, ConversationTurn, TurnRole}, permissions::{check_permission, CHAT_PERMISSION}, persona_store::active_persona, quota_store::QuotaKind, quotas::{check_daily_quota, record_daily_usage, start_request, QuotaUser}, streaming_reply::StreamingReply, tools::ToolContext, usage::record_chat_usage};
+
+pub fn generate_chat_messages (current_role: Role, content: Vec<ChatCompletionRequestMessageContentPart>, content_string_only: String) -> Result<ChatCompletionRequestMessage, Error> {
+    let chat_message = match current_role {
+        Role::Assistant => ChatCompletionRequestAssistantMessageArgs::default()
+            .content(content_string_only)
+            .build()?
+            .into(),
+        Role::Function => ChatCompletionRequestFunctionMessageArgs::default()
+            .content(content_string_only)
+            .build()?
+            .into(),
+        Role::System => ChatCompletionRequestSystemMessageArgs::default()
+            .content(content_string_only)
+            .build()?
+            .into(),
+        Role::Tool => ChatCompletionRequestToolMessageArgs::default()
+            .content(content_string_only)
+            .build()?
+            .into(),
+        Role::User => ChatCompletionRequestUserMessageArgs::default()
+            .content(content)
+            .build()?
+            .into(),
+    };
+    Ok(chat_message)
+}
+
+/*
+    Builds a conversation turn from a Discord message
+    The content of any text attachments is downloaded and labeled so it can be sent to the model as plain text
+*/
+async fn build_turn(message: &Message, role: TurnRole) -> Result<ConversationTurn, Error> {
+    let content = match role {
+        TurnRole::User => message.author.id.to_string() + "|" + &message.author.name + ": " + &message.content,
+        TurnRole::Assistant => message.content.clone(),
+    };
+    let mut attachments: Vec<String> = Vec::new();
+    let mut image_urls: Vec<String> = Vec::new();
+
+    // OpenAI only supports JPGs, PNGs and static GIFs
+    // Until I impliment this better, I'm going to hedge my bets that any gifs will be animated
+    let image_extentions = [
+        ".jpg",
+        ".jpeg",
+        ".png"
+    ];
+    // Bunch of text formats, just don't want to try and process a 5MB binary or video
+    // Seriously, I've used the Wiki page for file formats, this should cover almost everything
+    // Plain text docs, scripting, programming source
+    let text_extentions = get_text_type();
+    for message_attachment in &message.attachments {
+        let attachment_link = message_attachment.url.clone();
+        let mut parsed_attachment_link = Url::parse(&attachment_link).map_err(|e| BotError::Internal(format!("Unable to read the attachment link: {}", e)))?;
+        parsed_attachment_link.set_query(None);
+        let parsed_attachement_link_string = parsed_attachment_link.to_string();
+
+        /*
+            If an image is in the context then attach it to the request
+        */
+        if image_extentions.iter().any(|suffix| parsed_attachement_link_string.ends_with(suffix)) {
+            image_urls.push(attachment_link);
         }
-    } else if current_role == Role::System {
-        return match ChatCompletionRequestSystemMessageArgs::default()
-        .content(content_string_only)
-        .build()
-        {
-            Ok(t) => async_openai::types::ChatCompletionRequestMessage::System(t),
-            Err(e) => return_error_reply(msg.clone(), e.to_string()).await.unwrap(),
+        /*
+            This adds the content of any attached text files to the message
+        */
+        else if text_extentions.iter().any(|suffix| parsed_attachement_link_string.ends_with(suffix)) {
+            let text_content = reqwest::get(attachment_link).await?.text().await?;
+            let attachment_name = parsed_attachement_link_string.rsplit('/').next().ok_or("Unable to get the name of an attachment")?;
+            attachments.push(format!("Content of the file: {attachment_name} ```{text_content}```"));
         }
-    } else if current_role == Role::Tool {
-        return match ChatCompletionRequestToolMessageArgs::default()
-        .content(content_string_only)
-        .build()
-        {
-            Ok(t) => async_openai::types::ChatCompletionRequestMessage::Tool(t),
-            Err(e) => return_error_reply(msg.clone(), e.to_string()).await.unwrap(),
+        /*
+            Anything else is listed so the model knows it is there, tools can still use it (transcribing audio for example)
+        */
+        else {
+            attachments.push(format!("Attached file: {}", message_attachment.filename));
         }
-    } else {
-        return match ChatCompletionRequestUserMessageArgs::default()
-        .content(content)
-        .build()
-        {
-            Ok(t) => async_openai::types::ChatCompletionRequestMessage::User(t),
-            Err(e) => return_error_reply(msg.clone(), e.to_string()).await.unwrap(),
+    }
+
+    Ok(ConversationTurn {
+        message_id: message.id.get(),
+        channel_id: message.channel_id.get(),
+        author_id: message.author.id.get(),
+        role,
+        content,
+        attachments,
+        image_urls,
+        reply_to: message.message_reference.as_ref().and_then(|reference| reference.message_id).map(|t| t.get()),
+        created_at: message.timestamp.unix_timestamp(),
+    })
+}
+
+/*
+    Gets the earlier turns in the conversation, newest first
+    Replies are followed back through the conversation store and only fetched from Discord if the bot has not seen them
+    Images in stored turns are dropped once their Discord links may have expired
+    A mention that is not a reply can optionally continue from the recent conversation in the channel
+*/
+async fn load_history(msg: &Message, current_turn: &ConversationTurn, ctx: &Context, data: &Data, user_id: u64) -> Result<Vec<ConversationTurn>, Error> {
+    let mut history: Vec<ConversationTurn> = Vec::new();
+
+    if current_turn.reply_to.is_none() {
+        let conversation_config = &data.config.conversations;
+        if conversation_config.continue_from_mentions {
+            let since = current_turn.created_at - conversation_config.mention_history_minutes * 60;
+            history = recent_turns(&data.database, msg.channel_id.get(), msg.id.get(), since, conversation_config.mention_history_messages)?
+                .into_iter()
+                .map(|t| drop_expired_images(t, current_turn.created_at))
+                .collect();
         }
+        return Ok(history);
+    }
+
+    let mut next_message_id = current_turn.reply_to;
+    while let Some(message_id) = next_message_id {
+        let turn = match get_turn(&data.database, message_id)? {
+            Some(t) => drop_expired_images(t, current_turn.created_at),
+            None => {
+                let previous_message = msg.channel_id.message(&ctx.http, MessageId::new(message_id)).await?;
+                let role = if previous_message.author.id == user_id { TurnRole::Assistant } else { TurnRole::User };
+                let turn = build_turn(&previous_message, role).await?;
+                save_turn(&data.database, &turn)?;
+                turn
+            }
+        };
+        next_message_id = turn.reply_to;
+        history.push(turn);
     }
+
+    Ok(history)
 }
 
-pub async fn text_reply(msg: Message, cache: impl CacheHttp, user_id: u64) -> Vec<String> {
-    let client = Client::new();
-    let current_http = Http::new(&env::var("DISCORD_TOKEN")
-    .expect("Expected a token in the environment"));
-    let current_channel = msg.clone().channel(cache)
-        .await
-        .expect("Failed to get current channel to get the ChatGPT context");
-    let mut current_message = msg.clone();
-    let context_messages: &mut Vec<ChatCompletionRequestMessage> = &mut Vec::new();
-    // Default to user role as the bot needs to be called to reply
-    let mut current_role = Role::User;
-    let chatgpt_system_details = env::var("SYSTEM_DETAILS").unwrap_or("".to_owned());
-    let max_tokens: u16 = 4096;
-    let mut message_content: String;
+fn turn_to_chat_message(turn: ConversationTurn) -> Result<ChatCompletionRequestMessage, Error> {
+    let current_role = match turn.role {
+        TurnRole::User => Role::User,
+        TurnRole::Assistant => Role::Assistant,
+    };
     let mut message_vec_content: Vec<ChatCompletionRequestMessageContentPart> = Vec::new();
-    let mut message_model = "gpt-4o";
-
-    if current_message.message_reference.is_none() {
-        message_content = current_message.author.id.to_string() + "|" + &current_message.author.name + ": " + &current_message.content;
-        message_vec_content.push(ChatCompletionRequestMessageContentPartTextArgs::default().text(message_content.clone()).build().unwrap().into());
-        if !current_message.attachments.is_empty() {
-            // OpenAI only supports JPGs, PNGs and static GIFs
-            // Until I impliment this better, I'm going to hedge my bets that any gifs will be animated
-            let image_extentions = vec!(
-                ".jpg",
-                ".jpeg",
-                ".png"
-            );
-            // Bunch of text formats, just don't want to try and process a 5MB binary or video
-            // Seriously, I've used the Wiki page for file formats, this should cover almost everything
-            // Plain text docs, scripting, programming source
-            let text_extentions = get_text_type();
-            for message_attachment in current_message.attachments {
-                let attachment_link = message_attachment.url;
-                let mut parsed_attachment_link = match Url::parse(&attachment_link)
-                    {
-                        Ok(t) => t,
-                        Err(e) => return_error_reply(msg.clone(), e.to_string()).await.unwrap(),
-                    };
-                parsed_attachment_link.set_query(None);
-                let parsed_attachement_link_string = parsed_attachment_link.to_string();
-                
-                /*
-                    If an image is in the context then attach it to the request
-                */
-                if image_extentions.iter().any(|suffix| parsed_attachement_link_string.ends_with(suffix)) {
-                    message_vec_content.push(ChatCompletionRequestMessageContentPartImageArgs::default().image_url(attachment_link).build().unwrap().into());
-                }
-                /*
-                    This adds the content of any attached text files to the message
-                */
-                else if text_extentions.iter().any(|suffix| parsed_attachement_link_string.ends_with(suffix)) {
-                    let text_response = match reqwest::get(attachment_link).await
-                    {
-                        Ok(t) => t,
-                        Err(e) => return_error_reply(msg.clone(), e.to_string()).await.unwrap(),
-                    };
-                    let text_content = match text_response.text().await
-                    {
-                        Ok(t) => t,
-                        Err(e) => return_error_reply(msg.clone(), e.to_string()).await.unwrap(),
-                    };
-                    let mut attachment_path_split = parsed_attachement_link_string.rsplit("/");
-                    let attachment_name = match attachment_path_split.next()
-                    {
-                        Some(t) => t,
-                        None => return_error_reply(msg.clone(), "Unable to process stop typing".to_owned()).await.unwrap(),
-                    };
-
-                    message_vec_content.push(ChatCompletionRequestMessageContentPartTextArgs::default().text(format!("Content of the file: {attachment_name} ```{text_content}```")).build().unwrap().into());
-                }
-            }
-        }
-        context_messages.push(
-            generate_chat_messages(
-                current_role, 
-                message_vec_content,
-                message_content,
-                msg.clone()
-            )
-            .await
-        );
-    } else {
-        loop{
-            message_vec_content = Vec::new();
-            message_content = match current_role {
-                Role::User => current_message.author.id.to_string() + "|" + &current_message.author.name + ": " + &current_message.content,
-                _ => current_message.content,
-            };
-            message_vec_content.push(ChatCompletionRequestMessageContentPartTextArgs::default().text(message_content.clone()).build().unwrap().into());
-            if !current_message.attachments.is_empty() {
-                // OpenAI only supports JPGs, PNGs and static GIFs
-                // Until I impliment this better, I'm going to hedge my bets that any gifs will be animated
-                let image_extentions = vec!(
-                    ".jpg",
-                    ".jpeg",
-                    ".png"
-                );
-                // Bunch of text formats, just don't want to try and process a 5MB binary or video
-                // Seriously, I've used the Wiki page for file formats, this should cover almost everything
-                // Plain text docs, scripting, programming source
-                let text_extentions: Vec<&str> = get_text_type();
-                for message_attachment in current_message.attachments {
-                    let attachment_link = message_attachment.url;
-                    let mut parsed_attachment_link = match Url::parse(&attachment_link)
-                        {
-                            Ok(t) => t,
-                            Err(e) => return_error_reply(msg.clone(), e.to_string()).await.unwrap(),
-                        };
-                    parsed_attachment_link.set_query(None);
-                    let parsed_attachement_link_string = parsed_attachment_link.to_string();
-    
-                    /*
-                        If an image is in the context then use the vision model instead of the text model
-                        The vison model has a lower call rate per day
-                    */
-                    if image_extentions.iter().any(|suffix| parsed_attachement_link_string.ends_with(suffix)) {
-                        message_vec_content.push(ChatCompletionRequestMessageContentPartImageArgs::default().image_url(attachment_link).build().unwrap().into());
-                    }
-                    /*
-                        This adds the content of any attached text files to the message
-                    */
-                    else if text_extentions.iter().any(|suffix| parsed_attachement_link_string.ends_with(suffix)) {
-                        let text_response = match reqwest::get(attachment_link).await
-                        {
-                            Ok(t) => t,
-                            Err(e) => return_error_reply(msg.clone(), e.to_string()).await.unwrap(),
-                        };
-                        let text_content = match text_response.text().await
-                        {
-                            Ok(t) => t,
-                            Err(e) => return_error_reply(msg.clone(), e.to_string()).await.unwrap(),
-                        };
-                        let mut attachment_path_split = parsed_attachement_link_string.rsplit("/");
-                        let attachment_name = match attachment_path_split.next()
-                        {
-                            Some(t) => t,
-                            None => return_error_reply(msg.clone(), "Unable to process stop typing".to_owned()).await.unwrap(),
-                        };
-    
-                        message_vec_content.push(ChatCompletionRequestMessageContentPartTextArgs::default().text(format!("Content of the file: {attachment_name} ```{text_content}```")).build().unwrap().into());
+    message_vec_content.push(ChatCompletionRequestMessageContentPartTextArgs::default().text(turn.content.clone()).build()?.into());
+    for attachment in turn.attachments {
+        message_vec_content.push(ChatCompletionRequestMessageContentPartTextArgs::default().text(attachment).build()?.into());
+    }
+    for image_url in turn.image_urls {
+        message_vec_content.push(ChatCompletionRequestMessageContentPartImageArgs::default().image_url(image_url).build()?.into());
+    }
+
+    generate_chat_messages(current_role, message_vec_content, turn.content)
+}
+
+/*
+    Replies to a message that mentions the bot
+    Any error is passed back to the event handler which shows it to the user
+    The token counts on the span are estimates from the tokenizer as streamed replies do not include usage
+*/
+#[instrument(skip_all, fields(model = Empty, persona = Empty, prompt_tokens = Empty, completion_tokens = Empty, tool_calls = Empty))]
+pub async fn text_reply(msg: Message, ctx: &Context, data: &Data, user_id: u64, message_prefix: String) -> Result<(), Error> {
+    let config = &data.config;
+
+    // Permissions and quotas are checked before anything is stored or sent to the backend
+    let quota_user = QuotaUser::from_message(&msg);
+    check_permission(data, &quota_user, msg.channel_id.get(), CHAT_PERMISSION)?;
+    let quota_limits = start_request(data, &quota_user)?;
+    // The size of the prompt isn't known yet, this stops users who have no tokens left before their message is stored or summarised
+    check_daily_quota(data, &quota_user, &quota_limits, QuotaKind::Tokens, 0)?;
+
+    // A persona assigned to the channel (or the server) replaces the system details and any model settings it sets
+    let persona = match msg.guild_id {
+        Some(guild_id) => active_persona(&data.database, guild_id.get(), msg.channel_id.get())?,
+        None => None,
+    };
+    let chatgpt_system_details = persona.as_ref().map_or_else(|| config.text_generation.system_details.clone(), |t| t.system_prompt.clone());
+    let max_tokens: u16 = persona.as_ref().and_then(|t| t.max_tokens).unwrap_or(config.text_generation.max_tokens);
+    let temperature: f32 = persona.as_ref().and_then(|t| t.temperature).unwrap_or(config.text_generation.temperature);
+    let message_model = persona.as_ref().and_then(|t| t.model.clone()).unwrap_or_else(|| config.text_generation.model.clone());
+    let span = Span::current();
+    span.record("model", message_model.as_str());
+    if let Some(persona) = &persona {
+        span.record("persona", persona.name.as_str());
+    }
+
+    // Default to user role as the bot needs to be called to reply
+    let current_turn = build_turn(&msg, TurnRole::User).await?;
+    save_turn(&data.database, &current_turn)?;
+    let history = load_history(&msg, &current_turn, ctx, data, user_id).await?;
+
+    let mut context_messages: Vec<ChatCompletionRequestMessage> = Vec::new();
+    context_messages.push(generate_chat_messages(Role::System, Vec::new(), chatgpt_system_details.clone())?);
+
+    // The conversation is cut down (or summarised) to fit the context budget, oldest turns go first
+    // Tokens are counted with the tokenizer of the model the reply is made with
+    let token_counter = data.token_counters.for_model(&message_model)?;
+    let all_turns: Vec<ConversationTurn> = history.into_iter().rev().chain(std::iter::once(current_turn)).collect();
+    let assembled_context = assemble_context(all_turns, &chatgpt_system_details, &token_counter, &config.text_generation, data.chat_backend.as_ref()).await?;
+    let prompt_tokens = token_counter.count(&chatgpt_system_details)
+        + assembled_context.summary.as_deref().map_or(0, |t| token_counter.count(t))
+        + assembled_context.turns.iter().map(|turn| token_counter.turn_tokens(turn)).sum::<usize>();
+    span.record("prompt_tokens", prompt_tokens);
+    // The prompt is sent with every tool round so this is the least the reply will use
+    check_daily_quota(data, &quota_user, &quota_limits, QuotaKind::Tokens, prompt_tokens as u64)?;
+    // The summary is made with the configured model rather than the persona's model
+    let summary_tokens = assembled_context.summary_prompt_tokens + assembled_context.summary_completion_tokens;
+    if summary_tokens > 0 {
+        record_chat_usage(data, &quota_user, &config.text_generation.model, assembled_context.summary_prompt_tokens as u64, assembled_context.summary_completion_tokens as u64);
+    }
+    if let Some(summary) = assembled_context.summary {
+        context_messages.push(generate_chat_messages(Role::System, Vec::new(), summary)?);
+    }
+    for turn in assembled_context.turns {
+        context_messages.push(turn_to_chat_message(turn)?);
+    }
+
+    let tool_definitions = if config.text_generation.enable_tools { data.tools.definitions() } else { Vec::new() };
+    let tool_context = ToolContext { data, message: &msg };
+
+    /*
+        The reply is posted as soon as the first text arrives and then edited as more text is streamed in
+        StreamingReply handles the rate limiting of edits and moving onto a new message when one fills up
+        If the model calls tools, they are run and the results are sent back so the model can carry on with the reply
+        The last round is sent without tools so the model has to give an answer
+    */
+    let mut streaming_reply = StreamingReply::new(ctx, msg.clone(), message_prefix, config.text_generation.attachment_threshold);
+    let mut completion_tokens = 0;
+    // Each round sends the whole conversation again (with the tool calls and results added) so every round's prompt is counted
+    let mut round_prompt_tokens = prompt_tokens;
+    let mut total_prompt_tokens = 0;
+    let mut tool_call_count = 0;
+    let mut earlier_rounds_wrote_text = false;
+    for tool_round in 0..=config.text_generation.max_tool_rounds {
+        total_prompt_tokens += round_prompt_tokens;
+        let chat_request = ChatRequest {
+            model: message_model.clone(),
+            messages: context_messages.clone(),
+            max_tokens,
+            temperature,
+            tools: if tool_round < config.text_generation.max_tool_rounds { tool_definitions.clone() } else { Vec::new() },
+        };
+
+        let completion_metric = &metrics().chat_completions;
+        let completion_started = Instant::now();
+        let mut response_stream = data.chat_backend.complete_stream(chat_request).await
+            .inspect_err(|_| completion_metric.observe(&[&message_model], completion_started, false))?;
+
+        let mut round_text = String::new();
+        let mut tool_calls: Vec<ChatCompletionMessageToolCall> = Vec::new();
+        while let Some(response_event) = response_stream.next().await {
+            let response_event = response_event.inspect_err(|_| completion_metric.observe(&[&message_model], completion_started, false))?;
+            match response_event {
+                ChatEvent::Text(response_text) => {
+                    // Text from an earlier tool round is kept apart from this round's text
+                    if round_text.is_empty() && earlier_rounds_wrote_text && !response_text.is_empty() {
+                        streaming_reply.push("\n\n").await?;
                     }
-                }
-            }
-            context_messages.push(
-                generate_chat_messages(
-                    current_role, 
-                    message_vec_content.clone(),
-                    message_content,
-                    msg.clone()
-                )
-                .await
-            );
-
-            let current_message_reference = current_message.message_reference.expect("Unable to get previous message in chain (1)");
-            let current_message_id = current_message_reference.message_id.expect("Unable to get ID of previous message in chain (2)");
-
-            current_message = current_channel
-            .id()
-            .message(&current_http, current_message_id)
-            .await
-            .expect("Unable to reieve previous message");
-
-            if current_message.author.id == user_id {
-                current_role = Role::Assistant;
-            } else {
-                current_role = Role::User
+                    round_text.push_str(&response_text);
+                    streaming_reply.push(&response_text).await?;
+                },
+                ChatEvent::ToolCalls(t) => tool_calls.extend(t),
             }
+        }
 
-            if current_message.message_reference.is_none()  {
-                break;
-            }
+        completion_metric.observe(&[&message_model], completion_started, true);
+        earlier_rounds_wrote_text |= !round_text.is_empty();
+
+        let round_completion_tokens = token_counter.count(&round_text)
+            + tool_calls.iter().map(|tool_call| token_counter.count(&tool_call.function.arguments)).sum::<usize>();
+        completion_tokens += round_completion_tokens;
+        if tool_calls.is_empty() {
+            break;
         }
+        tool_call_count += tool_calls.len();
+        debug!(tool_round, "The model called {} tool(s)", tool_calls.len());
+
+        // Any files from the tools (such as images) are posted with the reply, only the text goes back to the model
+        let mut tool_results: Vec<String> = Vec::new();
+        for tool_call in &tool_calls {
+            let tool_output = data.tools.run(tool_call, &tool_context).await;
+            streaming_reply.attach(tool_output.attachments);
+            tool_results.push(tool_output.content);
+        }
+        round_prompt_tokens += round_completion_tokens + tool_results.iter().map(|t| token_counter.count(t)).sum::<usize>();
+        let tool_messages = build_tool_messages(round_text, tool_calls, tool_results)?;
+        context_messages.extend(tool_messages);
     }
-    context_messages.push(
-        generate_chat_messages(
-            Role::System, 
-            Vec::new(),
-            chatgpt_system_details,
-            msg.clone()
-        )
-        .await
-    );
-
-    context_messages.reverse();
-
-    let chatgpt_request = match CreateChatCompletionRequestArgs::default()
-        .model(message_model)
-        .temperature(1.0)
-        .messages(&**context_messages)
-        .max_tokens(max_tokens)
-        .build()
-        {
-            Ok(t) => t,
-            Err(e) => return_error_reply(msg.clone(), e.to_string()).await.unwrap(),
-        };
+    let sent_replies = streaming_reply.finish().await?;
+    span.record("completion_tokens", completion_tokens);
+    metrics().tokens.with_label_values(&[&message_model, "prompt"]).inc_by(total_prompt_tokens as u64);
+    metrics().tokens.with_label_values(&[&message_model, "completion"]).inc_by(completion_tokens as u64);
+    record_daily_usage(data, &quota_user, QuotaKind::Tokens, (summary_tokens + total_prompt_tokens + completion_tokens) as u64);
+    record_chat_usage(data, &quota_user, &message_model, total_prompt_tokens as u64, completion_tokens as u64);
+    span.record("tool_calls", tool_call_count);
 
-    let response_choices = match client.chat().create(chatgpt_request).await
-        {
-            Ok(t) => t,
-            Err(e) => return_error_reply(msg.clone(), e.to_string()).await.unwrap(),
+    // The replies are stored so the conversation can be continued without fetching them from Discord again
+    for (sent_message, sent_text) in sent_replies {
+        let reply_turn = ConversationTurn {
+            message_id: sent_message.id.get(),
+            channel_id: sent_message.channel_id.get(),
+            author_id: user_id,
+            role: TurnRole::Assistant,
+            content: sent_text,
+            attachments: Vec::new(),
+            image_urls: Vec::new(),
+            reply_to: sent_message.message_reference.as_ref().and_then(|reference| reference.message_id).map(|t| t.get()),
+            created_at: sent_message.timestamp.unix_timestamp(),
         };
-    let response = &response_choices.choices[0].message.content;
-    let response_text = match response.as_ref()
-        {
-            Some(t) => t,
-            None => return_error_reply(msg.clone(), "Unable to process stop typing".to_owned()).await.unwrap(),
-        };
-    let mut return_vec: Vec<String> = Vec::new();
-    
-    /*
-        If the response is longer than a discord message will allow, split the message
-        This splits the message into slightly shorter chunks than 2000 to allow for adding what part of the message it is and DEBUG: for debug mode
-    */
-    if response_text.len() > 2000 {
-        let chars: Vec<char> = response_text.chars().collect();
-        let total_chunks = (response_text.len()/1980) + 1;
-        let chunk_size = 1980;
-        let mut split = chars.chunks(chunk_size)
-            .map(|chunk| chunk.iter().collect::<String>())
-            .collect::<Vec<_>>();
-        let split_clone = split.clone();
-
-        for (index, _element) in split_clone.iter().enumerate() {
-            let code_vec: Vec<_> = split[index]
-                .match_indices("```")
-                .collect();
-            if (code_vec.len() % 2) == 1 {
-                split[index] = split[index].to_owned() + "```";
-                if index != split.len() - 1 {
-                    split[index + 1] = "```".to_owned() + &split[index + 1];
-                }
-            }
-            split[index] = format!("{}/{total_chunks}: {}", index + 1, split[index])
+        if let Err(e) = save_turn(&data.database, &reply_turn) {
+            warn!("Unable to store a reply in the conversation store: {}", e);
         }
-        return_vec = split;
-    } else {
-        return_vec.push(response_text.to_owned())
     }
-    return return_vec;
+
+    Ok(())
+}
+
+/*
+    The assistant message with the tool calls followed by a tool message with the result of each call
+    The model needs both to carry on from where it left off
+*/
+fn build_tool_messages(round_text: String, tool_calls: Vec<ChatCompletionMessageToolCall>, tool_results: Vec<String>) -> Result<Vec<ChatCompletionRequestMessage>, Error> {
+    let mut tool_messages: Vec<ChatCompletionRequestMessage> = Vec::new();
+
+    let mut assistant_message_args = ChatCompletionRequestAssistantMessageArgs::default();
+    if !round_text.is_empty() {
+        assistant_message_args.content(round_text);
+    }
+    tool_messages.push(assistant_message_args.tool_calls(tool_calls.clone()).build()?.into());
+
+    for (tool_call, tool_result) in tool_calls.into_iter().zip(tool_results) {
+        tool_messages.push(
+            ChatCompletionRequestToolMessageArgs::default()
+                .tool_call_id(tool_call.id)
+                .content(tool_result)
+                .build()?
+                .into()
+        );
+    }
+
+    Ok(tool_messages)
 }
 
 fn get_text_type() -> Vec<&'static str> {
-    return vec!(
+    vec!(
         ".txt",
         ".json",
         ".md",
@@ -517,5 +569,5 @@ fn get_text_type() -> Vec<&'static str> {
         ".XQ",
         ".XSL",
         ".Y"
-    );
+    )
 }
\ No newline at end of file
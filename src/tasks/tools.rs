@@ -0,0 +1,347 @@
+use std::{future::pending, time::Instant};
+
+use async_openai::{types::{ChatCompletionMessageToolCall, ChatCompletionTool, ChatCompletionToolType, FunctionObject}, Client};
+use async_trait::async_trait;
+use rand::Rng;
+
+use serde_json::json;
+use serenity::all::{CreateAttachment, Message, Timestamp};
+use tracing::{info, warn};
+
+use crate::{Data, Error, FunctionData};
+
+use super::{image_generation::{check_aspect_ratio, generate_dalle, generate_runpod_image, ImageGenRunInput}, metrics::metrics, permissions::check_permission, quota_store::QuotaKind, quotas::{check_daily_quota, effective_limits, record_daily_usage, start_request, QuotaUser}, stt::{transcribe_url, TRANSCRIPTION_MODEL}, usage::{record_image_usage, record_transcription_usage}};
+
+/*
+    What a tool gives back
+    The content is sent to the model, any attachments are posted with the final reply
+*/
+pub struct ToolOutput {
+    pub content: String,
+    pub attachments: Vec<CreateAttachment>,
+}
+
+impl ToolOutput {
+    fn text(content: String) -> Self {
+        ToolOutput { content, attachments: Vec::new() }
+    }
+}
+
+/*
+    Everything a tool might need to know about the message being replied to
+*/
+pub struct ToolContext<'a> {
+    pub data: &'a Data,
+    pub message: &'a Message,
+}
+
+/*
+    A tool is a Rust function the model can ask to run while writing a reply
+    The parameters are a JSON schema object describing the arguments the model should give
+*/
+#[async_trait]
+pub trait Tool: Send + Sync {
+    fn name(&self) -> &'static str;
+    fn description(&self) -> &'static str;
+    fn parameters(&self) -> serde_json::Value;
+    async fn run(&self, arguments: serde_json::Value, tool_context: &ToolContext<'_>) -> Result<ToolOutput, Error>;
+}
+
+/*
+    Holds every tool the model can use
+    Tools that depend on something that is not available (FFmpeg for example) are left out when the registry is built
+*/
+pub struct ToolRegistry {
+    tools: Vec<Box<dyn Tool>>,
+}
+
+impl ToolRegistry {
+    pub fn new(ffmpeg_available: bool, image_functions: Vec<FunctionData>) -> Self {
+        let mut tools: Vec<Box<dyn Tool>> = vec![
+            Box::new(CurrentTimeTool),
+            Box::new(RollDiceTool),
+        ];
+        if ffmpeg_available {
+            tools.push(Box::new(TranscribeAttachmentTool));
+        }
+        if !image_functions.is_empty() {
+            tools.push(Box::new(GenerateImageTool { image_functions }));
+        }
+        ToolRegistry { tools }
+    }
+
+    /*
+        The tools in the format sent to the model
+    */
+    pub fn definitions(&self) -> Vec<ChatCompletionTool> {
+        self.tools.iter()
+            .map(|tool| ChatCompletionTool {
+                r#type: ChatCompletionToolType::Function,
+                function: FunctionObject {
+                    name: tool.name().to_owned(),
+                    description: Some(tool.description().to_owned()),
+                    parameters: Some(tool.parameters()),
+                },
+            })
+            .collect()
+    }
+
+    /*
+        Runs a tool call from the model
+        Errors are given back to the model as the result so it can tell the user what went wrong instead of the reply failing
+    */
+    pub async fn run(&self, tool_call: &ChatCompletionMessageToolCall, tool_context: &ToolContext<'_>) -> ToolOutput {
+        let Some(tool) = self.tools.iter().find(|tool| tool.name() == tool_call.function.name) else {
+            return ToolOutput::text(format!("Error: there is no tool called {}", tool_call.function.name));
+        };
+
+        // Models sometimes send nothing at all for tools without arguments
+        let arguments = if tool_call.function.arguments.trim().is_empty() {
+            Ok(json!({}))
+        } else {
+            serde_json::from_str(&tool_call.function.arguments)
+        };
+        let result = match arguments {
+            Ok(t) => tool.run(t, tool_context).await,
+            Err(e) => Err(format!("The arguments are not valid JSON: {}", e).into()),
+        };
+
+        match result {
+            Ok(t) => {
+                info!(tool = tool.name(), "Ran a tool");
+                t
+            },
+            Err(e) => {
+                warn!(tool = tool.name(), "A tool returned an error: {}", e);
+                ToolOutput::text(format!("Error: {}", e))
+            },
+        }
+    }
+}
+
+struct CurrentTimeTool;
+
+#[async_trait]
+impl Tool for CurrentTimeTool {
+    fn name(&self) -> &'static str {
+        "get_current_time"
+    }
+
+    fn description(&self) -> &'static str {
+        "Gets the current date and time in UTC"
+    }
+
+    fn parameters(&self) -> serde_json::Value {
+        json!({ "type": "object", "properties": {} })
+    }
+
+    async fn run(&self, _arguments: serde_json::Value, _tool_context: &ToolContext<'_>) -> Result<ToolOutput, Error> {
+        Ok(ToolOutput::text(Timestamp::now().to_string()))
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct RollDiceArguments {
+    sides: u32,
+    count: Option<u32>,
+    modifier: Option<i64>,
+}
+
+struct RollDiceTool;
+
+#[async_trait]
+impl Tool for RollDiceTool {
+    fn name(&self) -> &'static str {
+        "roll_dice"
+    }
+
+    fn description(&self) -> &'static str {
+        "Rolls dice, for example 3d6+2 is 3 dice with 6 sides and a modifier of 2"
+    }
+
+    fn parameters(&self) -> serde_json::Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "sides": { "type": "integer", "minimum": 2, "maximum": 1000, "description": "The number of sides on each die" },
+                "count": { "type": "integer", "minimum": 1, "maximum": 100, "description": "The number of dice to roll, defaults to 1" },
+                "modifier": { "type": "integer", "description": "Added to the total, defaults to 0" }
+            },
+            "required": ["sides"]
+        })
+    }
+
+    async fn run(&self, arguments: serde_json::Value, _tool_context: &ToolContext<'_>) -> Result<ToolOutput, Error> {
+        let arguments: RollDiceArguments = serde_json::from_value(arguments)?;
+        let count = arguments.count.unwrap_or(1);
+        if !(2..=1000).contains(&arguments.sides) || !(1..=100).contains(&count) {
+            return Err("Dice must have 2 to 1000 sides and 1 to 100 dice can be rolled at a time".into());
+        }
+
+        let mut rng = rand::thread_rng();
+        let rolls: Vec<u32> = (0..count).map(|_| rng.gen_range(1..=arguments.sides)).collect();
+        let total = rolls.iter().map(|roll| *roll as i64).sum::<i64>() + arguments.modifier.unwrap_or(0);
+
+        Ok(ToolOutput::text(json!({ "rolls": rolls, "total": total }).to_string()))
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct TranscribeAttachmentArguments {
+    filename: Option<String>,
+}
+
+struct TranscribeAttachmentTool;
+
+#[async_trait]
+impl Tool for TranscribeAttachmentTool {
+    fn name(&self) -> &'static str {
+        "transcribe_attachment"
+    }
+
+    fn description(&self) -> &'static str {
+        "Transcribes the speech in an audio or video file attached to the user's message, or to the message they replied to"
+    }
+
+    fn parameters(&self) -> serde_json::Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "filename": { "type": "string", "description": "The name of the file to transcribe, defaults to the first audio or video file" }
+            }
+        })
+    }
+
+    async fn run(&self, arguments: serde_json::Value, tool_context: &ToolContext<'_>) -> Result<ToolOutput, Error> {
+        let arguments: TranscribeAttachmentArguments = serde_json::from_value(arguments)?;
+        let message = tool_context.message;
+        // The tool follows the permission rules of the command that does the same thing
+        let quota_user = QuotaUser::from_message(message);
+        check_permission(tool_context.data, &quota_user, message.channel_id.get(), "transcribe_from_attachment")?;
+        // Each transcription counts as a request, the same as the transcribe commands, so a reply can't run them without limit
+        start_request(tool_context.data, &quota_user)?;
+        let referenced_attachments = message.referenced_message.iter().flat_map(|referenced_message| referenced_message.attachments.iter());
+        let mut attachments = message.attachments.iter().chain(referenced_attachments);
+
+        let attachment = match arguments.filename {
+            Some(filename) => attachments.find(|attachment| attachment.filename == filename),
+            None => attachments.find(|attachment| {
+                attachment.content_type.as_deref().is_some_and(|content_type| content_type.starts_with("audio/") || content_type.starts_with("video/"))
+            }),
+        };
+        let attachment = attachment.ok_or("No matching audio or video attachment was found")?;
+
+        let client = Client::with_config(tool_context.data.config.openai_client_config());
+        let transcription = transcribe_url(&client, attachment.proxy_url.clone()).await?;
+        record_transcription_usage(tool_context.data, &quota_user, TRANSCRIPTION_MODEL, transcription.duration_seconds);
+
+        Ok(ToolOutput::text(transcription.text))
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct GenerateImageArguments {
+    prompt: String,
+    model: Option<String>,
+    negative_prompt: Option<String>,
+    width_ratio: Option<f32>,
+    height_ratio: Option<f32>,
+}
+
+/*
+    Generates images with the models in assets/functions.json, the same models the imagegen command uses
+    The model is picked by its function_command, the first model is used if the model does not pick one
+*/
+struct GenerateImageTool {
+    image_functions: Vec<FunctionData>,
+}
+
+#[async_trait]
+impl Tool for GenerateImageTool {
+    fn name(&self) -> &'static str {
+        "generate_image"
+    }
+
+    fn description(&self) -> &'static str {
+        "Generates images from a text prompt, the images are attached to the reply. Use this when the user asks for a picture or drawing"
+    }
+
+    fn parameters(&self) -> serde_json::Value {
+        let model_names: Vec<&str> = self.image_functions.iter().map(|function| function.function_command.as_str()).collect();
+        let model_descriptions: Vec<String> = self.image_functions.iter()
+            .map(|function| format!("{} ({})", function.function_command, function.function_friendly_name))
+            .collect();
+        json!({
+            "type": "object",
+            "properties": {
+                "prompt": { "type": "string", "description": "A detailed description of the image" },
+                "model": { "type": "string", "enum": model_names, "description": format!("The model to use, one of: {}", model_descriptions.join(", ")) },
+                "negative_prompt": { "type": "string", "description": "Things to leave out of the image, not supported by DALL-E" },
+                "width_ratio": { "type": "number", "description": "The width part of the aspect ratio, defaults to 1, not supported by DALL-E" },
+                "height_ratio": { "type": "number", "description": "The height part of the aspect ratio, defaults to 1, not supported by DALL-E" }
+            },
+            "required": ["prompt"]
+        })
+    }
+
+    async fn run(&self, arguments: serde_json::Value, tool_context: &ToolContext<'_>) -> Result<ToolOutput, Error> {
+        let arguments: GenerateImageArguments = serde_json::from_value(arguments)?;
+        let image_function = match &arguments.model {
+            Some(model) => self.image_functions.iter().find(|function| &function.function_command == model).ok_or(format!("There is no image model called {}", model))?,
+            None => self.image_functions.first().ok_or("There are no image models set up")?,
+        };
+        let config = &tool_context.data.config;
+
+        // Images made in a reply count towards the daily image quota of the user who asked, the reply itself was already counted as a request
+        let quota_user = QuotaUser::from_message(tool_context.message);
+        check_permission(tool_context.data, &quota_user, tool_context.message.channel_id.get(), "imagegen")?;
+        let quota_limits = effective_limits(tool_context.data, &quota_user)?;
+        // The tool always makes one image
+        check_daily_quota(tool_context.data, &quota_user, &quota_limits, QuotaKind::Images, 1)?;
+
+        // The model's prefix and suffix are used with every backend, the same as the imagegen command
+        let full_prompt = format!("{}{}{}", image_function.prompt_prefix, arguments.prompt, image_function.prompt_suffix);
+        let generation_started = Instant::now();
+        let generation_result = match image_function.function_type.as_str() {
+            "runpod_image" => {
+                let width_ratio = arguments.width_ratio.unwrap_or(1.0);
+                let height_ratio = arguments.height_ratio.unwrap_or(1.0);
+                check_aspect_ratio(width_ratio, height_ratio)?;
+                let (width, height) = image_function.parameters.image_size(width_ratio, height_ratio);
+                // There is no cancel button in a reply, the job can only be stopped by runpod.max_wait_seconds
+                // The tool always makes one image, the rest of the parameters are the model's defaults
+                let (steps, scheduler, _) = image_function.parameters.resolve(None, None, None)?;
+                let run_input = ImageGenRunInput {
+                    prompt: full_prompt,
+                    negative_prompt: arguments.negative_prompt.unwrap_or_default(),
+                    width,
+                    height,
+                    num_inference_steps: steps,
+                    scheduler,
+                    ..Default::default()
+                };
+                generate_runpod_image(&tool_context.data.runpod, &image_function.function_api_key, run_input, image_function.use_runsync, pending()).await
+                    .map(|(image_attachments, _)| image_attachments)
+            },
+            "openai_dalle" => generate_dalle(full_prompt, config.openai_client_config()).await,
+            function_type => return Err(format!("The image model type {} is not supported", function_type).into()),
+        };
+        metrics().image_generations.observe(&[&image_function.function_command], generation_started, generation_result.is_ok());
+        let image_attachments = generation_result?;
+        record_daily_usage(tool_context.data, &quota_user, QuotaKind::Images, image_attachments.len() as u64);
+        record_image_usage(tool_context.data, &quota_user, &image_function.function_command, image_attachments.len() as u64);
+        if image_attachments.is_empty() {
+            return Err("No images were generated".into());
+        }
+
+        Ok(ToolOutput {
+            content: format!(
+                "{} image(s) were generated with {} using the prompt \"{}\" and will be attached to your reply, do not include links or markdown images for them",
+                image_attachments.len(),
+                image_function.function_friendly_name,
+                arguments.prompt
+            ),
+            attachments: image_attachments,
+        })
+    }
+}
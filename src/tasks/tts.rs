@@ -2,9 +2,12 @@ use async_openai::{types::{CreateSpeechRequestArgs, SpeechModel, Voice}, Client}
 use poise::CreateReply;
 use serenity::all::CreateAttachment;
 use tokio::time::timeout;
-use std::{env, fs::{create_dir_all, remove_file}, time::Duration};
+use std::{env, fs::{create_dir_all, remove_file}, time::{Duration, Instant}};
 
-use crate::{tasks::{ffmpeg_handler::run_ffmpeg, handle_errors::{return_error, return_error_command}}, Error};
+use crate::{tasks::{ffmpeg_handler::run_ffmpeg, handle_errors::BotError, metrics::metrics, quota_store::QuotaKind, quotas::{check_daily_quota, record_daily_usage, start_request, QuotaUser}, usage::record_tts_usage}, Error};
+
+// The name of SpeechModel::Tts1Hd in the usage ledger
+const TTS_MODEL_NAME: &str = "tts-1-hd";
 
 #[poise::command(slash_command)]
 pub async fn tts_from_text(
@@ -13,11 +16,8 @@ pub async fn tts_from_text(
     text_to_tts: String
 ) -> Result<(), Error> {
     // NOTE: This command has a timeout of 3 minutes, this is due to OpenAI sometimes taking an extremely long time to process longer text requests and at some point it does have to stop
-    let _result = match timeout(Duration::from_secs(180), tts_run(ctx, text_to_tts)).await
-        {
-            Ok(t) => t,
-            Err(_) => return_error_command(ctx, "This TTS command has timed out, this may be due to the length of the text".to_owned()).await.unwrap(),
-        };
+    timeout(Duration::from_secs(180), tts_run(ctx, text_to_tts)).await
+        .map_err(|_| BotError::UserInput("This TTS command has timed out, this may be due to the length of the text".to_owned()))??;
 
     Ok(())
 }
@@ -29,11 +29,8 @@ pub async fn tts_from_message(
     message_to_tts: serenity::all::Message
 ) -> Result<(), Error> {
     // NOTE: This command has a timeout of 3 minutes, this is due to OpenAI sometimes taking an extremely long time to process longer text requests and at some point it does have to stop
-    let _result = match timeout(Duration::from_secs(180), tts_run(ctx, message_to_tts.content_safe(ctx))).await
-        {
-            Ok(t) => t,
-            Err(_) => return_error_command(ctx, "This TTS command has timed out, this may be due to the length of the text".to_owned()).await.unwrap(),
-        };
+    timeout(Duration::from_secs(180), tts_run(ctx, message_to_tts.content_safe(ctx))).await
+        .map_err(|_| BotError::UserInput("This TTS command has timed out, this may be due to the length of the text".to_owned()))??;
 
     Ok(())
 }
@@ -41,59 +38,47 @@ pub async fn tts_from_message(
 pub async fn tts_run (
     ctx: crate::Context<'_>,
     tts_string: String
-)
+) -> Result<(), Error>
 {
-    let client = Client::new();
+    let quota_user = QuotaUser::from_context(ctx).await;
+    let tts_characters = tts_string.chars().count() as u64;
+    let quota_limits = start_request(ctx.data(), &quota_user)?;
+    check_daily_quota(ctx.data(), &quota_user, &quota_limits, QuotaKind::TtsCharacters, tts_characters)?;
+
+    let client = Client::with_config(ctx.data().config.openai_client_config());
     let requester_id = ctx.author().id;
     let channel_id = ctx.channel_id();
 
-    let current_exe = match env::current_exe()
-        {
-            Ok(t) => t,
-            Err(e) => return_error(requester_id.clone(), channel_id.clone(), e.to_string()).await.unwrap(),
-        };
-    let current_path = match current_exe.parent() 
-        {
-            Some(t) => t,
-            None => return_error(requester_id.clone(), channel_id.clone(), "Unable to process current function string".to_owned()).await.unwrap(),
-        };
+    let current_exe = env::current_exe()?;
+    let current_path = current_exe.parent().ok_or("Unable to process current function string")?;
     let tmp_location = current_path.join("tmp");
 
     let _ = create_dir_all(tmp_location.clone());
 
     let tmp_file = tmp_location.join(format!("{}_{}.mp3", requester_id, channel_id));
 
-    match ctx.defer().await
-    {
-        Ok(t) => t,
-        Err(e) => return_error(requester_id, channel_id, e.to_string()).await.unwrap(),
-    };
+    ctx.defer().await?;
 
-    let request = match CreateSpeechRequestArgs::default()
+    let request = CreateSpeechRequestArgs::default()
     .input(tts_string.clone())
     .voice(Voice::Nova)
     .model(SpeechModel::Tts1Hd)
-    .build()
-        {
-            Ok(t) => t,
-            Err(e) => return_error(requester_id, channel_id, e.to_string()).await.unwrap(),
-        };
-
-    let response = match client.audio().speech(request).await
-        {
-            Ok(t) => t,
-            Err(e) => return_error(requester_id, channel_id, e.to_string()).await.unwrap(),
-        };
+    .build()?;
 
-    // let attachment: Vec<u8> = response.bytes.to_vec();
-    let _ = response.save(tmp_file.clone()).await;
+    let speech_started = Instant::now();
+    let response = client.audio().speech(request).await;
+    metrics().tts.observe(&[], speech_started, response.is_ok());
+    let response = response?;
+    record_daily_usage(ctx.data(), &quota_user, QuotaKind::TtsCharacters, tts_characters);
+    record_tts_usage(ctx.data(), &quota_user, TTS_MODEL_NAME, tts_characters);
 
-    let attachment_processed: Vec<u8>= run_ffmpeg(None, Some(tmp_file.clone().into_os_string().into_string().unwrap()), "-f matroska -filter_complex \"[0:a]showwaves=s=320x240:colors=White:mode=line'\" -c:a mp3".to_string(), requester_id, channel_id).await;
+    // let attachment: Vec<u8> = response.bytes.to_vec();
+    response.save(tmp_file.clone()).await?;
 
+    // The temporary file is removed before checking the FFmpeg result so it is not left behind if FFmpeg fails
+    let ffmpeg_result = run_ffmpeg(None, Some(tmp_file.to_string_lossy().into_owned()), "-f matroska -filter_complex \"[0:a]showwaves=s=320x240:colors=White:mode=line'\" -c:a mp3".to_string()).await;
     let _ = remove_file(tmp_file);
-    if attachment_processed.is_empty() {
-        let _: Error = return_error(requester_id, channel_id, "TTS output has returned empty".to_owned()).await.unwrap();
-    }
+    let attachment_processed: Vec<u8> = ffmpeg_result?;
 
     let message_builder = CreateReply 
     { 
@@ -102,9 +87,6 @@ pub async fn tts_run (
         ..Default::default()
     };
 
-    match ctx.send(message_builder).await
-        {
-            Ok(t) => t,
-            Err(e) => return_error(requester_id, channel_id, e.to_string()).await.unwrap(),
-        };
+    ctx.send(message_builder).await?;
+    Ok(())
 }
\ No newline at end of file
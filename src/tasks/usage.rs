@@ -0,0 +1,67 @@
+use serenity::all::Timestamp;
+use tracing::warn;
+
+use crate::Data;
+
+use super::{quotas::QuotaUser, usage_store::{add_usage_entry, UsageEntry, UsageFeature}};
+
+/*
+    Records requests to the usage ledger with an estimated cost from the pricing config
+    These are called once a request has succeeded, a failure to record is logged rather than failing the request
+*/
+pub fn record_chat_usage(data: &Data, quota_user: &QuotaUser, model: &str, prompt_tokens: u64, completion_tokens: u64) {
+    let token_pricing = data.config.pricing.chat_models.get(model).copied().unwrap_or_default();
+    let estimated_cost = (prompt_tokens as f64 * token_pricing.prompt + completion_tokens as f64 * token_pricing.completion) / 1_000_000.0;
+    record_usage(data, UsageEntry {
+        prompt_tokens,
+        completion_tokens,
+        estimated_cost,
+        ..empty_entry(quota_user, UsageFeature::Chat, model)
+    });
+}
+
+pub fn record_image_usage(data: &Data, quota_user: &QuotaUser, function_command: &str, images: u64) {
+    let image_price = data.config.pricing.images.get(function_command).copied().unwrap_or(0.0);
+    record_usage(data, UsageEntry {
+        images,
+        estimated_cost: images as f64 * image_price,
+        ..empty_entry(quota_user, UsageFeature::Image, function_command)
+    });
+}
+
+pub fn record_tts_usage(data: &Data, quota_user: &QuotaUser, model: &str, characters: u64) {
+    record_usage(data, UsageEntry {
+        characters,
+        estimated_cost: characters as f64 * data.config.pricing.tts_per_million_characters / 1_000_000.0,
+        ..empty_entry(quota_user, UsageFeature::Tts, model)
+    });
+}
+
+pub fn record_transcription_usage(data: &Data, quota_user: &QuotaUser, model: &str, audio_seconds: f64) {
+    record_usage(data, UsageEntry {
+        audio_seconds,
+        estimated_cost: audio_seconds / 60.0 * data.config.pricing.transcription_per_minute,
+        ..empty_entry(quota_user, UsageFeature::Transcription, model)
+    });
+}
+
+fn empty_entry(quota_user: &QuotaUser, feature: UsageFeature, model: &str) -> UsageEntry {
+    UsageEntry {
+        guild_id: quota_user.guild_id,
+        user_id: quota_user.user_id,
+        feature,
+        model: model.to_owned(),
+        prompt_tokens: 0,
+        completion_tokens: 0,
+        characters: 0,
+        images: 0,
+        audio_seconds: 0.0,
+        estimated_cost: 0.0,
+    }
+}
+
+fn record_usage(data: &Data, usage_entry: UsageEntry) {
+    if let Err(e) = add_usage_entry(&data.database, &usage_entry, Timestamp::now().unix_timestamp()) {
+        warn!("Unable to record usage to the ledger: {}", e);
+    }
+}
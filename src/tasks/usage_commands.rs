@@ -0,0 +1,151 @@
+use poise::CreateReply;
+use serenity::all::{GuildId, User};
+
+use crate::{tasks::handle_errors::BotError, Error};
+
+use super::{message_splitter::split_message, quotas::format_wait, usage_store::{top_consumers, usage_totals, UsageFeature, UsagePeriod, UsageTotal}};
+
+// The most users shown in the top consumers of a server
+const TOP_CONSUMER_COUNT: usize = 10;
+
+/*
+    Usage and estimated costs from the usage ledger, anyone can see their own usage
+    The server and user subcommands need the Manage Server permission
+*/
+#[poise::command(
+    slash_command,
+    subcommands("usage_me", "usage_server", "usage_user"),
+    subcommand_required
+)]
+pub async fn usage(_ctx: crate::Context<'_>) -> Result<(), Error> {
+    Ok(())
+}
+
+/// Show what you have used today and this month, in this server or in DMs
+#[poise::command(slash_command, rename = "me")]
+async fn usage_me(ctx: crate::Context<'_>) -> Result<(), Error> {
+    let guild_id = ctx.guild_id().map_or(0, |t| t.get());
+    let user_id = ctx.author().id.get();
+    let usage_report = format!(
+        "Your usage{}\n\n{}",
+        if guild_id == 0 { " in DMs" } else { " in this server" },
+        user_report(ctx, guild_id, user_id)?
+    );
+    send_report(ctx, usage_report).await
+}
+
+/// Show the usage of the whole server and the users who have used the most
+#[poise::command(slash_command, guild_only, rename = "server", required_permissions = "MANAGE_GUILD")]
+async fn usage_server(ctx: crate::Context<'_>) -> Result<(), Error> {
+    let guild_id = command_guild_id(ctx)?.get();
+    let database = &ctx.data().database;
+
+    let mut usage_report = String::from("Usage in this server\n\n");
+    for (period_name, period) in [("Today", UsagePeriod::Today), ("This month", UsagePeriod::ThisMonth)] {
+        usage_report.push_str(&format_totals(period_name, &usage_totals(database, guild_id, None, period)?));
+    }
+
+    usage_report.push_str("**Top users this month**\n");
+    let consumers = top_consumers(database, guild_id, UsagePeriod::ThisMonth, TOP_CONSUMER_COUNT)?;
+    if consumers.is_empty() {
+        usage_report.push_str("> Nobody has used anything yet\n");
+    }
+    for (index, consumer) in consumers.iter().enumerate() {
+        usage_report.push_str(&format!(
+            "> {}. <@{}>: {} {}, {}\n",
+            index + 1,
+            consumer.user_id,
+            consumer.requests,
+            plural(consumer.requests, "request", "requests"),
+            format_cost(consumer.estimated_cost)
+        ));
+    }
+
+    send_report(ctx, usage_report).await
+}
+
+/// Show what a user has used in this server today and this month
+#[poise::command(slash_command, guild_only, rename = "user", required_permissions = "MANAGE_GUILD")]
+async fn usage_user(
+    ctx: crate::Context<'_>,
+    #[description = "User to show"]
+    user: User
+) -> Result<(), Error> {
+    let guild_id = command_guild_id(ctx)?.get();
+    let usage_report = format!("Usage for <@{}> in this server\n\n{}", user.id, user_report(ctx, guild_id, user.id.get())?);
+    send_report(ctx, usage_report).await
+}
+
+fn user_report(ctx: crate::Context<'_>, guild_id: u64, user_id: u64) -> Result<String, Error> {
+    let mut usage_report = String::new();
+    for (period_name, period) in [("Today", UsagePeriod::Today), ("This month", UsagePeriod::ThisMonth)] {
+        usage_report.push_str(&format_totals(period_name, &usage_totals(&ctx.data().database, guild_id, Some(user_id), period)?));
+    }
+    Ok(usage_report)
+}
+
+/*
+    One line for each feature and model, followed by the total cost for the period
+*/
+fn format_totals(period_name: &str, usage_totals: &[UsageTotal]) -> String {
+    let mut totals_text = format!("**{}**\n", period_name);
+    if usage_totals.is_empty() {
+        totals_text.push_str("> Nothing has been used\n\n");
+        return totals_text;
+    }
+
+    for usage_total in usage_totals {
+        let amount = match usage_total.feature {
+            UsageFeature::Chat => format!("{} prompt and {} reply tokens", usage_total.prompt_tokens, usage_total.completion_tokens),
+            UsageFeature::Image => format!("{} {}", usage_total.images, plural(usage_total.images, "image", "images")),
+            UsageFeature::Tts => format!("{} characters", usage_total.characters),
+            UsageFeature::Transcription => format!("{} of audio", format_wait(usage_total.audio_seconds.round() as u64)),
+        };
+        totals_text.push_str(&format!(
+            "> {} ({}): {} {}, {}, {}\n",
+            feature_name(usage_total.feature),
+            usage_total.model,
+            usage_total.requests,
+            plural(usage_total.requests, "request", "requests"),
+            amount,
+            format_cost(usage_total.estimated_cost)
+        ));
+    }
+    let total_cost: f64 = usage_totals.iter().map(|t| t.estimated_cost).sum();
+    totals_text.push_str(&format!("> Total: {}\n\n", format_cost(total_cost)));
+    totals_text
+}
+
+fn feature_name(feature: UsageFeature) -> &'static str {
+    match feature {
+        UsageFeature::Chat => "Chat",
+        UsageFeature::Image => "Images",
+        UsageFeature::Tts => "TTS",
+        UsageFeature::Transcription => "Transcription",
+    }
+}
+
+// Costs are estimates from the pricing config, small amounts are shown with more decimal places so they do not show as $0.00
+fn format_cost(cost: f64) -> String {
+    if cost > 0.0 && cost < 0.01 {
+        format!("~${:.4}", cost)
+    } else {
+        format!("~${:.2}", cost)
+    }
+}
+
+fn plural<'a>(count: u64, singular: &'a str, plural: &'a str) -> &'a str {
+    if count == 1 { singular } else { plural }
+}
+
+// The commands are guild_only so this only fails if Discord sends something unexpected
+fn command_guild_id(ctx: crate::Context<'_>) -> Result<GuildId, Error> {
+    ctx.guild_id().ok_or(BotError::UserInput("Server usage can only be shown in a server".to_owned()))
+}
+
+async fn send_report(ctx: crate::Context<'_>, usage_report: String) -> Result<(), Error> {
+    for usage_report_chunk in split_message(&usage_report, 1900) {
+        ctx.send(CreateReply::default().content(usage_report_chunk).ephemeral(true)).await?;
+    }
+    Ok(())
+}
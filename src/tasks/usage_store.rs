@@ -0,0 +1,178 @@
+use rusqlite::{params, Row};
+
+use crate::Error;
+
+use super::database::Database;
+
+/*
+    The features that make requests to a backend, each ledger entry is for one of these
+*/
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum UsageFeature {
+    Chat,
+    Image,
+    Tts,
+    Transcription,
+}
+
+impl UsageFeature {
+    fn as_str(&self) -> &'static str {
+        match self {
+            UsageFeature::Chat => "chat",
+            UsageFeature::Image => "image",
+            UsageFeature::Tts => "tts",
+            UsageFeature::Transcription => "transcription",
+        }
+    }
+
+    fn parse(feature: &str) -> Option<UsageFeature> {
+        match feature {
+            "chat" => Some(UsageFeature::Chat),
+            "image" => Some(UsageFeature::Image),
+            "tts" => Some(UsageFeature::Tts),
+            "transcription" => Some(UsageFeature::Transcription),
+            _ => None,
+        }
+    }
+}
+
+/*
+    One request made to a backend, only the amounts that apply to the feature are set
+    The model is the model name for chat, TTS and transcription and the function_command for images
+*/
+#[derive(Clone, Debug)]
+pub struct UsageEntry {
+    pub guild_id: u64,
+    pub user_id: u64,
+    pub feature: UsageFeature,
+    pub model: String,
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+    pub characters: u64,
+    pub images: u64,
+    pub audio_seconds: f64,
+    pub estimated_cost: f64,
+}
+
+/*
+    The entries for one feature and model added together
+*/
+#[derive(Clone, Debug)]
+pub struct UsageTotal {
+    pub feature: UsageFeature,
+    pub model: String,
+    pub requests: u64,
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+    pub characters: u64,
+    pub images: u64,
+    pub audio_seconds: f64,
+    pub estimated_cost: f64,
+}
+
+// A user's requests and estimated cost in a server
+#[derive(Clone, Copy, Debug)]
+pub struct UsageConsumer {
+    pub user_id: u64,
+    pub requests: u64,
+    pub estimated_cost: f64,
+}
+
+/*
+    The periods usage is shown for, both start at midnight UTC the same as the daily quotas
+*/
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum UsagePeriod {
+    Today,
+    ThisMonth,
+}
+
+impl UsagePeriod {
+    // The SQLite date modifier for the start of the period
+    fn start_modifier(&self) -> &'static str {
+        match self {
+            UsagePeriod::Today => "start of day",
+            UsagePeriod::ThisMonth => "start of month",
+        }
+    }
+}
+
+pub fn add_usage_entry(database: &Database, usage_entry: &UsageEntry, created_at: i64) -> Result<(), Error> {
+    database.run(|connection| connection.execute(
+        "INSERT INTO usage_ledger (guild_id, user_id, feature, model, prompt_tokens, completion_tokens, characters, images, audio_seconds, estimated_cost, created_at)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)",
+        params![
+            usage_entry.guild_id as i64,
+            usage_entry.user_id as i64,
+            usage_entry.feature.as_str(),
+            usage_entry.model,
+            usage_entry.prompt_tokens as i64,
+            usage_entry.completion_tokens as i64,
+            usage_entry.characters as i64,
+            usage_entry.images as i64,
+            usage_entry.audio_seconds,
+            usage_entry.estimated_cost,
+            created_at
+        ],
+    ))?;
+    Ok(())
+}
+
+/*
+    The totals for each feature and model in a server since the start of the period, optionally for a single user
+*/
+pub fn usage_totals(database: &Database, guild_id: u64, user_id: Option<u64>, period: UsagePeriod) -> Result<Vec<UsageTotal>, Error> {
+    database.run(|connection| {
+        let mut statement = connection.prepare(
+            "SELECT feature, model, COUNT(*), SUM(prompt_tokens), SUM(completion_tokens), SUM(characters), SUM(images), SUM(audio_seconds), SUM(estimated_cost)
+                FROM usage_ledger
+                WHERE guild_id = ?1 AND (?2 IS NULL OR user_id = ?2) AND created_at >= CAST(strftime('%s', 'now', ?3) AS INTEGER)
+                GROUP BY feature, model ORDER BY feature, model",
+        )?;
+        let rows = statement.query_map(params![guild_id as i64, user_id.map(|t| t as i64), period.start_modifier()], read_usage_total)?;
+        // Rows with a feature this version does not know about are skipped
+        Ok(rows.collect::<rusqlite::Result<Vec<Option<UsageTotal>>>>()?.into_iter().flatten().collect())
+    })
+}
+
+/*
+    The users with the highest estimated cost in a server since the start of the period, most first
+*/
+pub fn top_consumers(database: &Database, guild_id: u64, period: UsagePeriod, limit: usize) -> Result<Vec<UsageConsumer>, Error> {
+    database.run(|connection| {
+        let mut statement = connection.prepare(
+            "SELECT user_id, COUNT(*), SUM(estimated_cost)
+                FROM usage_ledger
+                WHERE guild_id = ?1 AND created_at >= CAST(strftime('%s', 'now', ?2) AS INTEGER)
+                GROUP BY user_id ORDER BY SUM(estimated_cost) DESC, COUNT(*) DESC LIMIT ?3",
+        )?;
+        let rows = statement.query_map(params![guild_id as i64, period.start_modifier(), limit as i64], |row| {
+            let user_id: i64 = row.get(0)?;
+            let requests: i64 = row.get(1)?;
+            Ok(UsageConsumer { user_id: user_id as u64, requests: requests as u64, estimated_cost: row.get(2)? })
+        })?;
+        rows.collect()
+    })
+}
+
+fn read_usage_total(row: &Row) -> rusqlite::Result<Option<UsageTotal>> {
+    let feature: String = row.get(0)?;
+    let Some(feature) = UsageFeature::parse(&feature) else {
+        return Ok(None);
+    };
+    let read_count = |index: usize| -> rusqlite::Result<u64> {
+        let count: i64 = row.get(index)?;
+        Ok(count as u64)
+    };
+    Ok(Some(UsageTotal {
+        feature,
+        model: row.get(1)?,
+        requests: read_count(2)?,
+        prompt_tokens: read_count(3)?,
+        completion_tokens: read_count(4)?,
+        characters: read_count(5)?,
+        images: read_count(6)?,
+        audio_seconds: row.get(7)?,
+        estimated_cost: row.get(8)?,
+    }))
+}